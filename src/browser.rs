@@ -6,23 +6,65 @@ use wasm_bindgen::{closure::WasmClosure, closure::WasmClosureFnOnce};
 use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
 use wasm_bindgen_futures::JsFuture;
 use web_sys::{
-    CanvasRenderingContext2d, Document, Element, HtmlCanvasElement, HtmlElement, HtmlImageElement,
-    Response, Window,
+    CanvasRenderingContext2d, Document, Element, Headers, HtmlCanvasElement, HtmlElement,
+    HtmlImageElement, HtmlInputElement, KeyboardEvent, Request, RequestInit, Response, Storage,
+    UrlSearchParams, Window,
 };
 
+// log!は細かい情報なのでリリースビルドでは出さない。警告とエラーはいつでも出す
 macro_rules! log {
     ( $( $t:tt )* ) => {
-        web_sys::console::log_1(&format!( $( $t )* ).into())
+        if $crate::browser::log_enabled($crate::browser::LogLevel::Info) {
+            web_sys::console::log_1(&format!( $( $t )* ).into())
+        }
     };
 }
 
-macro_rules! error {
+macro_rules! log_warn {
     ( $( $t:tt )* ) => {
-        web_sys::console::error_1(&format!( $( $t )* ).into());
+        if $crate::browser::log_enabled($crate::browser::LogLevel::Warn) {
+            web_sys::console::warn_1(&format!( $( $t )* ).into())
+        }
+    };
+}
 
+macro_rules! log_error {
+    ( $( $t:tt )* ) => {
+        if $crate::browser::log_enabled($crate::browser::LogLevel::Error) {
+            web_sys::console::error_1(&format!( $( $t )* ).into())
+        }
     };
 }
 
+macro_rules! error {
+    ( $( $t:tt )* ) => {
+        log_error!( $( $t )* )
+    };
+}
+
+// 頼まれて出すものなので、ログのレベルに関係なくいつでもコンソールに出す
+pub fn print(text: &str) {
+    web_sys::console::log_1(&text.into())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Info,
+    Warn,
+    Error,
+}
+
+// これより低いレベルのログは捨てる
+const MIN_LOG_LEVEL: LogLevel = if cfg!(debug_assertions) {
+    LogLevel::Info
+} else {
+    LogLevel::Warn
+};
+
+pub fn log_enabled(level: LogLevel) -> bool {
+    level >= MIN_LOG_LEVEL
+}
+
 pub fn window() -> Result<Window> {
     web_sys::window().ok_or_else(|| anyhow!("No Window Found"))
 }
@@ -33,16 +75,43 @@ pub fn document() -> Result<Document> {
         .ok_or_else(|| anyhow!("No Document Found"))
 }
 
+const CANVAS_ID: &str = "canvas";
+// index.htmlに置いてあるcanvasと同じ大きさ
+const DEFAULT_CANVAS_SIZE: u32 = 600;
+
 pub fn canvas() -> Result<HtmlCanvasElement> {
     document()?
-        .get_element_by_id("canvas")
+        .get_element_by_id(CANVAS_ID)
         .ok_or_else(|| anyhow!("No Canvas Element found with ID 'canvas"))?
         .dyn_into::<web_sys::HtmlCanvasElement>()
         .map_err(|element| anyhow!("Error converting {:#?} to HtmlCanvasElement", element))
 }
 
+// id="canvas"の要素があればそれを返し、なければ作ってbodyの末尾に足す
+// index.htmlを使わずに埋め込んだときでも動くようにするため
+pub fn ensure_canvas() -> Result<HtmlCanvasElement> {
+    if document()?.get_element_by_id(CANVAS_ID).is_some() {
+        return canvas();
+    }
+
+    let canvas = create_canvas(DEFAULT_CANVAS_SIZE, DEFAULT_CANVAS_SIZE)?;
+    canvas.set_id(CANVAS_ID);
+    // キー入力を受け取れるように、フォーカスできるようにしておく
+    canvas.set_tab_index(0);
+    document()?
+        .body()
+        .ok_or_else(|| anyhow!("No Body Found"))?
+        .append_child(&canvas)
+        .map_err(|err| anyhow!("Could not append canvas {:#?}", err))?;
+    Ok(canvas)
+}
+
 pub fn context() -> Result<CanvasRenderingContext2d> {
-    canvas()?
+    context_of(&canvas()?)
+}
+
+pub fn context_of(canvas: &HtmlCanvasElement) -> Result<CanvasRenderingContext2d> {
+    canvas
         .get_context("2d")
         .map_err(|js_value| anyhow!("Error getting 2d context {:#?}", js_value))?
         .ok_or_else(|| anyhow!("Nod 2d context found"))?
@@ -55,6 +124,71 @@ pub fn context() -> Result<CanvasRenderingContext2d> {
         })
 }
 
+// DOMには追加しない、オフスクリーン描画用のcanvas
+pub fn create_canvas(width: u32, height: u32) -> Result<HtmlCanvasElement> {
+    let canvas = document()?
+        .create_element("canvas")
+        .map_err(|err| anyhow!("Could not create canvas {:#?}", err))?
+        .dyn_into::<HtmlCanvasElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlCanvasElement", element))?;
+    canvas.set_width(width);
+    canvas.set_height(height);
+    Ok(canvas)
+}
+
+// bodyの末尾にテキスト入力欄を追加する
+pub fn create_text_input(id: &str) -> Result<HtmlInputElement> {
+    let input = document()?
+        .create_element("input")
+        .map_err(|err| anyhow!("Could not create input {:#?}", err))?
+        .dyn_into::<HtmlInputElement>()
+        .map_err(|element| anyhow!("Error converting {:#?} to HtmlInputElement", element))?;
+    input.set_id(id);
+    input.set_type("text");
+
+    document()?
+        .body()
+        .ok_or_else(|| anyhow!("No Body Found"))?
+        .append_child(&input)
+        .map_err(|err| anyhow!("Could not append input {:#?}", err))?;
+    Ok(input)
+}
+
+// 高DPIの画面では1より大きくなる、CSSピクセルあたりの実際のピクセル数
+pub fn device_pixel_ratio() -> Result<f64> {
+    Ok(window()?.device_pixel_ratio())
+}
+
+pub fn query_param(name: &str) -> Result<Option<String>> {
+    let search = window()?
+        .location()
+        .search()
+        .map_err(|err| anyhow!("Could not read location search {:#?}", err))?;
+
+    UrlSearchParams::new_with_str(&search)
+        .map(|params| params.get(name))
+        .map_err(|err| anyhow!("Could not parse query string {:#?}", err))
+}
+
+fn local_storage() -> Result<Storage> {
+    window()?
+        .local_storage()
+        .map_err(|err| anyhow!("Could not access localStorage {:#?}", err))?
+        .ok_or_else(|| anyhow!("No localStorage found"))
+}
+
+pub fn load_item(key: &str) -> Result<Option<String>> {
+    local_storage()?
+        .get_item(key)
+        .map_err(|err| anyhow!("Could not read {} from localStorage {:#?}", key, err))
+}
+
+pub fn save_item(key: &str, value: &str) -> Result<()> {
+    local_storage()?
+        .set_item(key, value)
+        .map_err(|err| anyhow!("Could not write {} to localStorage {:#?}", key, err))
+}
+
 pub fn spawn_local<F>(future: F)
 where
     F: Future<Output = ()> + 'static,
@@ -86,6 +220,47 @@ pub async fn fetch_json(json_path: &str) -> Result<JsValue> {
     .map_err(|err| anyhow!("error fetching JSON {:#?}", err))
 }
 
+// bodyはJSONの文字列。2xx以外の応答もエラーにする
+pub async fn post_json(url: &str, body: &str) -> Result<Response> {
+    post_json_with(url, body, |request| async move {
+        JsFuture::from(window()?.fetch_with_request(&request))
+            .await
+            .map_err(|err| anyhow!("error fetching {:#?}", err))
+    })
+    .await
+}
+
+// fetchを差し替えられるようにしたもの。テストでは送ろうとしたリクエストを受け取って調べる
+async fn post_json_with<F, Fut>(url: &str, body: &str, fetch: F) -> Result<Response>
+where
+    F: FnOnce(Request) -> Fut,
+    Fut: Future<Output = Result<JsValue>>,
+{
+    let headers = Headers::new().map_err(|err| anyhow!("Could not create headers {:#?}", err))?;
+    headers
+        .set("Content-Type", "application/json")
+        .map_err(|err| anyhow!("Could not set header {:#?}", err))?;
+    let mut init = RequestInit::new();
+    init.method("POST")
+        .headers(&headers)
+        .body(Some(&JsValue::from_str(body)));
+    let request = Request::new_with_str_and_init(url, &init)
+        .map_err(|err| anyhow!("Could not create request for {} {:#?}", url, err))?;
+
+    let response: Response = fetch(request)
+        .await?
+        .dyn_into()
+        .map_err(|err| anyhow!("error converting fetch to Response {:#?}", err))?;
+    if !response.ok() {
+        return Err(anyhow!(
+            "POST {} failed with status {}",
+            url,
+            response.status()
+        ));
+    }
+    Ok(response)
+}
+
 pub async fn fetch_array_buffer(resource: &str) -> Result<ArrayBuffer> {
     let array_buffer = fetch_response(resource)
         .await?
@@ -117,6 +292,70 @@ pub fn request_animation_frame(callback: &LoopClosure) -> Result<i32> {
         .map_err(|err| anyhow!("Cannot request animation frame {:#?}", err))
 }
 
+pub fn set_timeout(callback: &Closure<dyn FnMut()>, milliseconds: i32) -> Result<i32> {
+    window()?
+        .set_timeout_with_callback_and_timeout_and_arguments_0(
+            callback.as_ref().unchecked_ref(),
+            milliseconds,
+        )
+        .map_err(|err| anyhow!("Cannot set timeout {:#?}", err))
+}
+
+pub fn clear_timeout(handle: i32) -> Result<()> {
+    window()?.clear_timeout_with_handle(handle);
+    Ok(())
+}
+
+pub type KeyboardClosure = Closure<dyn FnMut(KeyboardEvent)>;
+
+// canvasのキーイベントにcallbackを登録する
+// 返したClosureが破棄されるとリスナーも動かなくなるので、呼び出し側で持ち続ける（かforgetする）こと
+pub fn on_keydown(callback: impl FnMut(KeyboardEvent) + 'static) -> Result<KeyboardClosure> {
+    let closure = closure_wrap(Box::new(callback) as Box<dyn FnMut(KeyboardEvent)>);
+    canvas()?.set_onkeydown(Some(closure.as_ref().unchecked_ref()));
+    Ok(closure)
+}
+
+pub fn on_keyup(callback: impl FnMut(KeyboardEvent) + 'static) -> Result<KeyboardClosure> {
+    let closure = closure_wrap(Box::new(callback) as Box<dyn FnMut(KeyboardEvent)>);
+    canvas()?.set_onkeyup(Some(closure.as_ref().unchecked_ref()));
+    Ok(closure)
+}
+
+// タブの表示・非表示が切り替わるたびに、隠れたかどうかを渡してcallbackを呼ぶ
+// on_keydownと同じく、返したClosureは呼び出し側で持ち続けること
+pub fn on_visibility_change(
+    mut callback: impl FnMut(bool) + 'static,
+) -> Result<Closure<dyn FnMut()>> {
+    let document = document()?;
+    let target = document.clone();
+    let closure = closure_wrap(Box::new(move || callback(target.hidden())) as Box<dyn FnMut()>);
+    document.set_onvisibilitychange(Some(closure.as_ref().unchecked_ref()));
+    Ok(closure)
+}
+
+// ページ上で最初にキー・タッチ・クリックのどれかが起きたときに、一度だけcallbackを呼ぶ
+// ゲームの入力はcanvasで受けているので、windowのハンドラを一時的に借りても邪魔しない
+pub fn on_first_gesture(callback: impl FnOnce() + 'static) -> Result<()> {
+    let window = window()?;
+    let target = window.clone();
+    let mut callback = Some(callback);
+    let closure = closure_wrap(Box::new(move || {
+        if let Some(callback) = callback.take() {
+            callback();
+        }
+        target.set_onkeydown(None);
+        target.set_ontouchstart(None);
+        target.set_onmousedown(None);
+    }) as Box<dyn FnMut()>);
+    window.set_onkeydown(Some(closure.as_ref().unchecked_ref()));
+    window.set_ontouchstart(Some(closure.as_ref().unchecked_ref()));
+    window.set_onmousedown(Some(closure.as_ref().unchecked_ref()));
+    // 外したあとも呼ばれなくなるだけなので、リークさせておく
+    closure.forget();
+    Ok(())
+}
+
 pub fn create_raf_closure(f: impl FnMut(f64) + 'static) -> LoopClosure {
     closure_wrap(Box::new(f))
 }
@@ -182,6 +421,55 @@ mod tests {
 
     wasm_bindgen_test_configure!(run_in_browser);
 
+    #[test]
+    fn log_levels_are_ordered_and_errors_are_never_filtered() {
+        assert!(LogLevel::Info < LogLevel::Warn);
+        assert!(LogLevel::Warn < LogLevel::Error);
+        assert!(log_enabled(LogLevel::Error));
+        assert!(log_enabled(LogLevel::Warn));
+        assert_eq!(log_enabled(LogLevel::Info), cfg!(debug_assertions));
+    }
+
+    #[wasm_bindgen_test]
+    fn ensure_canvas_creates_the_canvas_only_once() {
+        assert!(canvas().is_err());
+
+        let created = ensure_canvas().unwrap();
+        let found = ensure_canvas().unwrap();
+
+        assert!(created.is_same_node(Some(&found)));
+        assert_eq!(created.width(), DEFAULT_CANVAS_SIZE);
+        assert!(context().is_ok());
+        created.remove();
+    }
+
+    #[wasm_bindgen_test]
+    async fn post_json_sends_the_body_as_a_json_post() {
+        let sent = std::rc::Rc::new(std::cell::RefCell::new(None));
+        let captured = sent.clone();
+
+        let response = post_json_with(
+            "https://example.com/scores",
+            r#"{"score":120}"#,
+            |request| async move {
+                captured.borrow_mut().replace(request);
+                Ok(Response::new().unwrap().into())
+            },
+        )
+        .await;
+
+        assert!(response.is_ok());
+        let request = sent.borrow_mut().take().unwrap();
+        assert_eq!(request.method(), "POST");
+        assert_eq!(request.url(), "https://example.com/scores");
+        assert_eq!(
+            request.headers().get("Content-Type").unwrap(),
+            Some("application/json".to_string())
+        );
+        let body = JsFuture::from(request.text().unwrap()).await.unwrap();
+        assert_eq!(body.as_string().unwrap(), r#"{"score":120}"#);
+    }
+
     #[wasm_bindgen_test]
     async fn test_error_loading_json() {
         let json = fetch_json("not_there.json").await;
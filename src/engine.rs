@@ -1,18 +1,22 @@
-use std::{cell::RefCell, collections::HashMap, rc::Rc, sync::Mutex};
+use std::{cell::RefCell, collections::HashMap, future::Future, rc::Rc, sync::Mutex};
 
 use crate::{
     browser::{self, LoopClosure},
-    sound,
+    sound::{self, Playback},
 };
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
 use futures::channel::{
     mpsc::{unbounded, UnboundedReceiver},
     oneshot::channel,
 };
-use serde::Deserialize;
+use gloo_utils::format::JsValueSerdeExt;
+use serde::{de::DeserializeOwned, Deserialize};
 use wasm_bindgen::{prelude::Closure, JsCast, JsValue};
-use web_sys::{AudioBuffer, AudioContext, CanvasRenderingContext2d, HtmlElement, HtmlImageElement};
+use web_sys::{
+    AudioBuffer, AudioBufferSourceNode, AudioContext, CanvasRenderingContext2d, HtmlElement,
+    HtmlImageElement, HtmlInputElement,
+};
 
 #[derive(Deserialize, Clone)]
 // シリアライズ時の挙動（デシリアライズは逆になる）
@@ -30,17 +34,36 @@ pub struct SheetRect {
     pub h: i16,
 }
 
-#[derive(Clone, Copy, Default)]
+#[derive(Deserialize, Clone, Copy, Default)]
 pub struct Point {
     pub x: i16,
     pub y: i16,
 }
 
+impl Point {
+    // alphaが0ならself、1ならtoになる
+    pub fn lerp(&self, to: &Point, alpha: f32) -> Point {
+        let lerp = |from: i16, to: i16| (from as f32 + (to - from) as f32 * alpha).round() as i16;
+        Point {
+            x: lerp(self.x, to.x),
+            y: lerp(self.y, to.y),
+        }
+    }
+}
+
 #[derive(Deserialize, Clone)]
 pub struct Sheet {
     pub frames: HashMap<String, Cell>,
 }
 
+impl Sheet {
+    // 順番はHashMapのままなので決まっていない
+    #[allow(dead_code)]
+    pub fn frame_names(&self) -> impl Iterator<Item = &str> {
+        self.frames.keys().map(String::as_str)
+    }
+}
+
 pub struct SpriteSheet {
     sheet: Sheet,
     image: HtmlImageElement,
@@ -55,65 +78,322 @@ impl SpriteSheet {
         self.sheet.frames.get(name)
     }
 
-    pub fn draw(&self, renderer: &Renderer, source: &Rect, dest: &Rect) {
-        renderer.draw_image(&self.image, source, dest)
+    // 名前とセルの組をすべて返す。順番は決まっていない
+    #[allow(dead_code)]
+    pub fn cells(&self) -> impl Iterator<Item = (&str, &Cell)> {
+        self.sheet
+            .frames
+            .iter()
+            .map(|(name, cell)| (name.as_str(), cell))
+    }
+
+    #[allow(dead_code)]
+    pub fn cell_count(&self) -> usize {
+        self.sheet.frames.len()
+    }
+
+    pub fn draw_batch(&self, renderer: &Renderer, draws: &[(Rect, Rect)]) {
+        renderer.draw_image_batch(&self.image, draws)
     }
 }
 
+// JSONを取ってきて、そのままTにする。失敗したときはパスと型の名前をエラーに含める
+pub async fn load_json<T: DeserializeOwned>(path: &str) -> Result<T> {
+    load_json_with(path, |path| async move { browser::fetch_json(&path).await }).await
+}
+
+// fetchを差し替えられるようにしたもの。テストではネットワークの代わりに決まった値を返す
+async fn load_json_with<T, F, Fut>(path: &str, fetch: F) -> Result<T>
+where
+    T: DeserializeOwned,
+    F: FnOnce(String) -> Fut,
+    Fut: Future<Output = Result<JsValue>>,
+{
+    let json = fetch(path.to_string())
+        .await
+        .with_context(|| format!("Could not fetch {}", path))?;
+    json.into_serde().map_err(|err| {
+        anyhow!(
+            "Could not read {} as {}: {}",
+            path,
+            std::any::type_name::<T>(),
+            err
+        )
+    })
+}
+
+// 読み込みがこれ以上かかったら、固まったとみなしてエラーにする
+const IMAGE_LOAD_TIMEOUT_MS: i32 = 10_000;
+
 pub async fn load_image(source: &str) -> Result<HtmlImageElement> {
+    load_image_with_timeout(source, Some(IMAGE_LOAD_TIMEOUT_MS)).await
+}
+
+// timeout_msがNoneなら、loadかerrorが来るまでいつまでも待つ
+pub async fn load_image_with_timeout(
+    source: &str,
+    timeout_ms: Option<i32>,
+) -> Result<HtmlImageElement> {
     let image = browser::new_image()?;
 
     let (complete_tx, complete_rx) = channel::<Result<()>>();
     let success_tx = Rc::new(Mutex::new(Some(complete_tx)));
     let error_tx = Rc::clone(&success_tx);
+    let timeout_tx = Rc::clone(&success_tx);
     let success_callback = browser::closure_once(move || {
         if let Some(success_tx) = success_tx.lock().ok().and_then(|mut opt| opt.take()) {
             let _ = success_tx.send(Ok(()));
         }
     });
 
+    let path = source.to_string();
     let error_callback: Closure<dyn FnMut(JsValue)> = browser::closure_once(move |err| {
         if let Some(error_tx) = error_tx.lock().ok().and_then(|mut opt| opt.take()) {
-            let _ = error_tx.send(Err(anyhow!("Error Loading Image: {:#?}", err)));
+            let _ = error_tx.send(Err(anyhow!("Error Loading Image {}: {:#?}", path, err)));
+        }
+    });
+
+    let path = source.to_string();
+    let timeout_callback: Closure<dyn FnMut()> = browser::closure_once(move || {
+        if let Some(timeout_tx) = timeout_tx.lock().ok().and_then(|mut opt| opt.take()) {
+            let _ = timeout_tx.send(Err(anyhow!("Timed out loading image {}", path)));
         }
     });
 
     image.set_onload(Some(success_callback.as_ref().unchecked_ref()));
     image.set_onerror(Some(error_callback.as_ref().unchecked_ref()));
+    let timeout = match timeout_ms {
+        Some(timeout_ms) => Some(browser::set_timeout(&timeout_callback, timeout_ms)?),
+        None => None,
+    };
     image.set_src(source);
 
-    complete_rx.await??;
+    let result = complete_rx.await;
+    // コールバックはこの関数を抜けると破棄されるので、後から呼ばれないようにしておく
+    if let Some(timeout) = timeout {
+        browser::clear_timeout(timeout)?;
+    }
+    result??;
 
     Ok(image)
 }
 
+// WebPなど、読めないブラウザがある形式を先に試し、error イベントが来たらPNGなどで読み直す
+#[allow(dead_code)]
+pub async fn load_image_with_fallback(primary: &str, fallback: &str) -> Result<HtmlImageElement> {
+    match load_image(primary).await {
+        Ok(image) => Ok(image),
+        Err(err) => {
+            log!(
+                "Could not load {}, falling back to {} {:#?}",
+                primary,
+                fallback,
+                err
+            );
+            load_image(fallback).await
+        }
+    }
+}
+
 #[async_trait(?Send)]
 pub trait Game {
     async fn initialize(&self) -> Result<Box<dyn Game>>;
     fn update(&mut self, keystate: &KeyState);
-    fn draw(&self, renderer: &Renderer);
+    // alphaは前回と今回の固定更新の間のどこを描くか。0なら前回、1なら今回の状態になる
+    fn draw(&self, renderer: &Renderer, alpha: f32);
+    // タブが隠れてゲームループが止まるとき・戻ってきたときに呼ばれる
+    fn on_pause(&mut self) {}
+    fn on_resume(&mut self) {}
 }
 
 const FRAME_SIZE: f32 = 1.0 / 60.0 * 1000.0;
+// 1フレームで進める時間の上限。止まっていた後に一気に進めると、障害物をすり抜けてしまう
+const MAX_FRAME_TIME: f64 = FRAME_SIZE as f64 * 3.0;
+
+fn clamp_frame_time(frame_time: f64) -> f64 {
+    frame_time.min(MAX_FRAME_TIME)
+}
+
+// ポーズ中の時間を含まない、ゲーム内の経過時間（ミリ秒）
+// アニメーションはbrowser::now()ではなくこちらを基準にすれば、再開時に時間が飛ばない
+#[derive(Default)]
+pub struct Clock {
+    elapsed: f64,
+    paused: bool,
+}
+
+impl Clock {
+    pub fn new() -> Self {
+        Clock::default()
+    }
+
+    pub fn advance(&mut self, delta: f64) {
+        if !self.paused {
+            self.elapsed += delta;
+        }
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn elapsed(&self) -> f64 {
+        self.elapsed
+    }
+}
+
+// 1回のupdateにかけてよい時間と、それを何フレーム続けて超えたら警告するか
+const UPDATE_BUDGET_MS: f64 = 8.0;
+const OVER_BUDGET_FRAMES: u8 = 30;
+
+// updateにかかった時間を見張り、予算超えが続いたら一度だけ知らせる
+pub struct UpdateBudget {
+    budget: f64,
+    limit: u8,
+    over_budget: u8,
+    last_update: f64,
+}
+
+impl UpdateBudget {
+    pub fn new(budget: f64, limit: u8) -> Self {
+        UpdateBudget {
+            budget,
+            limit,
+            over_budget: 0,
+            last_update: 0.0,
+        }
+    }
+
+    // 予算超えがちょうどlimitフレーム続いたときだけtrueを返す
+    pub fn record(&mut self, duration: f64) -> bool {
+        self.last_update = duration;
+        if duration > self.budget {
+            self.over_budget = self.over_budget.saturating_add(1);
+            self.over_budget == self.limit
+        } else {
+            self.over_budget = 0;
+            false
+        }
+    }
+
+    pub fn last_update(&self) -> f64 {
+        self.last_update
+    }
+}
+
+// フレームレート上限の判定に持たせる余裕（RAFのタイムスタンプの揺れを吸収する）
+const FRAME_CAP_TOLERANCE_MS: f64 = 1.0;
+
+// RAFのコールバックを間引いてフレームレートに上限を設ける
+// 間引いた分の時間は次に動くフレームのframe_timeにまとめて乗るので、物理の進み方は変わらない
+#[derive(Default)]
+pub struct FrameLimiter {
+    min_interval: Option<f64>,
+    last_run: Option<f64>,
+}
+
+impl FrameLimiter {
+    pub fn new() -> Self {
+        FrameLimiter::default()
+    }
+
+    // Noneで上限なし
+    pub fn set_fps_cap(&mut self, fps: Option<u32>) {
+        self.min_interval = fps.filter(|fps| *fps > 0).map(|fps| 1000.0 / fps as f64);
+    }
+
+    pub fn should_run(&mut self, now: f64) -> bool {
+        let ready = match (self.min_interval, self.last_run) {
+            (Some(min_interval), Some(last_run)) => {
+                now - last_run >= min_interval - FRAME_CAP_TOLERANCE_MS
+            }
+            _ => true,
+        };
+        if ready {
+            self.last_run = Some(now);
+        }
+        ready
+    }
+}
+
+const FAST_FORWARD_SCALE: f64 = 4.0;
+
+// デバッグ用のコマ送りと早送り
+// Pで一時停止を切り替え、一時停止中は.で1フレームだけ進める。]で早送りを切り替える
+#[derive(Default)]
+pub struct FrameStepper {
+    fast_forward: bool,
+}
+
+impl FrameStepper {
+    // 今回のキー入力を反映し、コマ送りのupdateを1回だけ行うならtrueを返す
+    pub fn apply(&mut self, keystate: &KeyState, clock: &mut Clock) -> bool {
+        let pressed = |code: &str| keystate.presses().iter().any(|press| press == code);
+        if pressed("KeyP") {
+            if clock.is_paused() {
+                clock.resume();
+            } else {
+                clock.pause();
+            }
+        }
+        if pressed("BracketRight") {
+            self.fast_forward = !self.fast_forward;
+        }
+        clock.is_paused() && pressed("Period")
+    }
+
+    pub fn time_scale(&self) -> f64 {
+        if self.fast_forward {
+            FAST_FORWARD_SCALE
+        } else {
+            1.0
+        }
+    }
+}
 
 pub struct GameLoop {
     last_frame: f64,
     accumulated_delta: f32,
+    clock: Clock,
+    update_budget: UpdateBudget,
+    frame_limiter: FrameLimiter,
+    frame_stepper: FrameStepper,
+    // タブが隠れたことで止めているか。自分でポーズしていた場合は戻っても再開しない
+    paused_by_visibility: bool,
 }
 type SharedLoopClosure = Rc<RefCell<Option<LoopClosure>>>;
 
 impl GameLoop {
     pub async fn start(game: impl Game + 'static) -> Result<()> {
+        browser::ensure_canvas()?;
         let mut keyevent_receiver = prepare_input()?;
+        let mut visibility_receiver = prepare_visibility()?;
         let mut game = game.initialize().await?;
         let mut game_loop = GameLoop {
             last_frame: browser::now()?,
             accumulated_delta: 0.0,
+            clock: Clock::new(),
+            update_budget: UpdateBudget::new(UPDATE_BUDGET_MS, OVER_BUDGET_FRAMES),
+            frame_limiter: FrameLimiter::new(),
+            frame_stepper: FrameStepper::default(),
+            paused_by_visibility: false,
         };
+        // ?fps=30 のようにクエリで上限を指定できる
+        let fps_cap = browser::query_param("fps")?.and_then(|fps| fps.parse().ok());
+        game_loop.set_fps_cap(fps_cap);
 
-        let renderer = Renderer {
-            context: browser::context()?,
-        };
+        let renderer = Renderer::with_backbuffer(browser::context()?);
+        renderer.scale_for_pixel_ratio(browser::device_pixel_ratio()?)?;
+        // ドット絵がぼやけないように、拡大縮小時の補間を切る
+        renderer.set_image_smoothing(false);
 
         let f: SharedLoopClosure = Rc::new(RefCell::new(None));
         let g = f.clone();
@@ -122,22 +402,29 @@ impl GameLoop {
         *g.borrow_mut() = Some(browser::create_raf_closure(move |perf: f64| {
             // レシーバに溜まったキーイベントをkeystateに反映させる
             process_input(&mut keystate, &mut keyevent_receiver);
+            while let Ok(Some(hidden)) = visibility_receiver.try_next() {
+                game_loop.set_hidden(game.as_mut(), hidden, perf);
+            }
 
-            let frame_time = perf - game_loop.last_frame;
-            // perf: request_animation_frameがコールバック関数を呼び出した時刻の高精度なタイムスタンプ
-            game_loop.accumulated_delta += frame_time as f32;
-            while game_loop.accumulated_delta > FRAME_SIZE {
-                game.update(&keystate);
-                game_loop.accumulated_delta -= FRAME_SIZE;
+            // 上限を超えるフレームでは何もしない。last_frameを進めないので時間は次のフレームに持ち越される
+            if !game_loop.frame_limiter.should_run(perf) {
+                let _ = browser::request_animation_frame(f.borrow().as_ref().unwrap());
+                return;
             }
+
+            // perf: request_animation_frameがコールバック関数を呼び出した時刻の高精度なタイムスタンプ
+            let frame_time = perf - game_loop.last_frame;
+            game_loop.tick(game.as_mut(), &mut keystate, frame_time);
             game_loop.last_frame = perf;
-            game.draw(&renderer);
+            // 更新しきれずに残った時間の分だけ、次の更新に向けて進めた位置で描く
+            game.draw(&renderer, game_loop.interpolation_alpha());
 
             if cfg!(debug_assertions) {
                 unsafe {
-                    draw_frame_rate(&renderer, frame_time);
+                    draw_frame_rate(&renderer, frame_time, game_loop.update_budget.last_update());
                 }
             }
+            renderer.present();
 
             let _ = browser::request_animation_frame(f.borrow().as_ref().unwrap());
         }));
@@ -150,13 +437,164 @@ impl GameLoop {
 
         Ok(())
     }
+
+    pub fn set_fps_cap(&mut self, fps: Option<u32>) {
+        self.frame_limiter.set_fps_cap(fps);
+    }
+
+    // タブが隠れている間はrequest_animation_frameが止まるので、戻ったときの経過時間を捨てる
+    fn set_hidden(&mut self, game: &mut dyn Game, hidden: bool, now: f64) {
+        if hidden {
+            if !self.clock.is_paused() {
+                self.clock.pause();
+                self.paused_by_visibility = true;
+                game.on_pause();
+            }
+        } else {
+            if self.paused_by_visibility {
+                self.clock.resume();
+                self.paused_by_visibility = false;
+                game.on_resume();
+            }
+            self.last_frame = now;
+        }
+    }
+
+    // frame_time分だけゲーム時間を進め、固定の刻みでupdateする
+    // ポーズ中は時計が進まないので、コマ送りを除いて更新も行われない
+    fn tick(&mut self, game: &mut dyn Game, keystate: &mut KeyState, frame_time: f64) {
+        let step = cfg!(debug_assertions) && self.frame_stepper.apply(keystate, &mut self.clock);
+
+        let game_time = self.clock.elapsed();
+        self.clock
+            .advance(clamp_frame_time(frame_time) * self.frame_stepper.time_scale());
+        self.accumulated_delta += (self.clock.elapsed() - game_time) as f32;
+        if step {
+            self.update(game, keystate);
+        }
+        while self.accumulated_delta > FRAME_SIZE {
+            self.update(game, keystate);
+            self.accumulated_delta -= FRAME_SIZE;
+        }
+        // ポーズ中に押されたキーは、次のコマ送りまで持ち越さない
+        if self.clock.is_paused() {
+            keystate.clear_presses();
+        }
+    }
+
+    fn interpolation_alpha(&self) -> f32 {
+        (self.accumulated_delta / FRAME_SIZE).clamp(0.0, 1.0)
+    }
+
+    fn update(&mut self, game: &mut dyn Game, keystate: &mut KeyState) {
+        let update_start = browser::now().unwrap_or_default();
+        game.update(keystate);
+        let update_time = browser::now().unwrap_or_default() - update_start;
+        if self.update_budget.record(update_time) {
+            log_warn!(
+                "update has taken over {}ms for {} frames (last {}ms)",
+                UPDATE_BUDGET_MS,
+                OVER_BUDGET_FRAMES,
+                update_time
+            );
+        }
+        // 押されたキーは一度のupdateでだけ扱う
+        keystate.clear_presses();
+    }
+}
+
+// 見た目の大きさはCSSで元のまま固定し、ピクセル数だけratio倍にする
+fn scale_canvas(context: &CanvasRenderingContext2d, ratio: f64) -> Result<()> {
+    let canvas = context
+        .canvas()
+        .ok_or_else(|| anyhow!("Context has no canvas"))?;
+    let (width, height) = (canvas.width(), canvas.height());
+    canvas
+        .set_attribute(
+            "style",
+            &format!("width: {}px; height: {}px;", width, height),
+        )
+        .map_err(|err| anyhow!("Could not set canvas style {:#?}", err))?;
+    canvas.set_width((f64::from(width) * ratio).round() as u32);
+    canvas.set_height((f64::from(height) * ratio).round() as u32);
+    Ok(())
 }
 
 pub struct Renderer {
+    // 描画先。バックバッファを使う場合はオフスクリーンのcanvasのもの
     context: CanvasRenderingContext2d,
+    // バックバッファを使う場合の、画面に見えているcanvas
+    visible: Option<CanvasRenderingContext2d>,
 }
 
 impl Renderer {
+    pub fn new(context: CanvasRenderingContext2d) -> Self {
+        Renderer {
+            context,
+            visible: None,
+        }
+    }
+
+    // 同じ大きさのオフスクリーンcanvasに描いて、present()でまとめて転送する
+    // オフスクリーンcanvasが作れなければ、直接描画にフォールバックする
+    pub fn with_backbuffer(visible: CanvasRenderingContext2d) -> Self {
+        let backbuffer = visible
+            .canvas()
+            .ok_or_else(|| anyhow!("Context has no canvas"))
+            .and_then(|canvas| browser::create_canvas(canvas.width(), canvas.height()))
+            .and_then(|canvas| browser::context_of(&canvas));
+
+        match backbuffer {
+            Ok(context) => Renderer {
+                context,
+                visible: Some(visible),
+            },
+            Err(err) => {
+                log_warn!("Could not create backbuffer, drawing directly {:#?}", err);
+                Renderer {
+                    context: visible,
+                    visible: None,
+                }
+            }
+        }
+    }
+
+    // 高DPIの画面でぼやけないように、canvasの実際のピクセル数をratio倍にする
+    // 描画側のcontextは同じ倍率で拡大するので、ゲームからは元の大きさの座標系のまま描ける
+    // canvasの大きさを変えるとcontextの設定が初期化されるので、他の設定より先に一度だけ呼ぶこと
+    pub fn scale_for_pixel_ratio(&self, ratio: f64) -> Result<()> {
+        if let Some(visible) = &self.visible {
+            scale_canvas(visible, ratio)?;
+        }
+        scale_canvas(&self.context, ratio)?;
+        self.context
+            .scale(ratio, ratio)
+            .map_err(|err| anyhow!("Could not scale context {:#?}", err))
+    }
+
+    // バックバッファを使う場合は、転送先の画面側のcanvasにも同じ設定をする
+    pub fn set_image_smoothing(&self, enabled: bool) {
+        self.context.set_image_smoothing_enabled(enabled);
+        if let Some(visible) = &self.visible {
+            visible.set_image_smoothing_enabled(enabled);
+        }
+    }
+
+    // バックバッファの内容を画面に転送する。直接描画している場合は何もしない
+    pub fn present(&self) {
+        if let (Some(visible), Some(backbuffer)) = (&self.visible, self.context.canvas()) {
+            visible.clear_rect(
+                0.0,
+                0.0,
+                backbuffer.width().into(),
+                backbuffer.height().into(),
+            );
+            visible
+                .draw_image_with_html_canvas_element(&backbuffer, 0.0, 0.0)
+                .expect("Drawing is throwing exceptions! Unrecoverable error.");
+        }
+    }
+
     pub fn clear(&self, rect: &Rect) {
         self.context.clear_rect(
             rect.x().into(),
@@ -166,6 +604,31 @@ impl Renderer {
         );
     }
 
+    // clearと違い、透明ではなく指定した色で塗りつぶす
+    pub fn clear_with_color(&self, rect: &Rect, color: &str) {
+        self.clear(rect);
+        self.fill_rect(rect, color);
+    }
+
+    // fractionの割合だけ前景色で塗ったゲージ。fractionは0..=1に丸める
+    pub fn draw_bar(&self, rect: &Rect, fraction: f64, fg: &str, bg: &str) {
+        self.fill_rect(rect, bg);
+        self.fill_rect(&bar_fill(rect, fraction), fg);
+    }
+
+    // 半透明の色を使えば、描画済みの画面に重ねるオーバーレイにもなる
+    pub fn fill_rect(&self, rect: &Rect, color: &str) {
+        self.context.save();
+        self.context.set_fill_style(&JsValue::from_str(color));
+        self.context.fill_rect(
+            rect.x().into(),
+            rect.y().into(),
+            rect.width.into(),
+            rect.height.into(),
+        );
+        self.context.restore();
+    }
+
     pub fn draw_image(&self, image: &HtmlImageElement, frame: &Rect, destination: &Rect) {
         self.context
             .draw_image_with_html_image_element_and_sw_and_sh_and_dx_and_dy_and_dw_and_dh(
@@ -182,6 +645,53 @@ impl Renderer {
             .expect("Drawing is throwing exceptions! Unrecoverable error.");
     }
 
+    // flip_xならdestinationの中で左右を反転して描く
+    pub fn draw_image_flipped(
+        &self,
+        image: &HtmlImageElement,
+        frame: &Rect,
+        destination: &Rect,
+        flip_x: bool,
+    ) {
+        if !flip_x {
+            return self.draw_image(image, frame, destination);
+        }
+
+        // 反転させた座標系を後の描画に持ち越さないよう、save/restoreで囲む
+        self.context.save();
+        // destinationの右端を原点にしてx軸を反転すると、[0, width]がdestinationの範囲に映る
+        let _ = self.context.translate(destination.right().into(), 0.0);
+        let _ = self.context.scale(-1.0, 1.0);
+        self.draw_image(
+            image,
+            frame,
+            &Rect::new_from_x_y(0, destination.y(), destination.width, destination.height),
+        );
+        self.context.restore();
+    }
+
+    // 同じ画像から切り出す複数の (frame, destination) をまとめて描く
+    pub fn draw_image_batch(&self, image: &HtmlImageElement, draws: &[(Rect, Rect)]) {
+        draws
+            .iter()
+            .for_each(|(frame, destination)| self.draw_image(image, frame, destination));
+    }
+
+    // drawImageを、呼び出しを数えるだけのものに差し替える
+    #[cfg(test)]
+    pub fn count_image_draws(&self) {
+        let count = js_sys::Function::new_no_args("this.imageDraws = (this.imageDraws || 0) + 1;");
+        js_sys::Reflect::set(&self.context, &JsValue::from_str("drawImage"), &count).unwrap();
+    }
+
+    #[cfg(test)]
+    pub fn image_draws(&self) -> u32 {
+        js_sys::Reflect::get(&self.context, &JsValue::from_str("imageDraws"))
+            .unwrap()
+            .as_f64()
+            .unwrap_or(0.0) as u32
+    }
+
     pub fn draw_entire_image(&self, image: &HtmlImageElement, position: &Point) {
         self.context
             .draw_image_with_html_image_element(image, position.x.into(), position.y.into())
@@ -200,6 +710,91 @@ impl Renderer {
         self.context.stroke();
     }
 
+    // draw_rectと違い、色と太さを選べて、描画の設定も元に戻す
+    pub fn stroke_rect(&self, rect: &Rect, color: &str, line_width: f64) {
+        self.context.save();
+        self.context.set_stroke_style(&JsValue::from_str(color));
+        self.context.set_line_width(line_width);
+        self.context.stroke_rect(
+            rect.x().into(),
+            rect.y().into(),
+            rect.width.into(),
+            rect.height.into(),
+        );
+        self.context.restore();
+    }
+
+    // offset分ずらした座標系でdrawを実行する
+    pub fn with_offset(&self, offset: &Point, draw: impl FnOnce(&Renderer)) {
+        self.context.save();
+        let _ = self.context.translate(offset.x.into(), offset.y.into());
+        draw(self);
+        self.context.restore();
+    }
+
+    // drawの間だけ、rectの外には何も描かれないようにする
+    #[allow(dead_code)]
+    pub fn with_clip(&self, rect: &Rect, draw: impl FnOnce(&Renderer)) {
+        self.context.save();
+        self.context.begin_path();
+        self.context.rect(
+            rect.x().into(),
+            rect.y().into(),
+            rect.width.into(),
+            rect.height.into(),
+        );
+        self.context.clip();
+        draw(self);
+        self.context.restore();
+    }
+
+    // global_alphaを後の描画に持ち越さないよう、drawの間だけ変える
+    pub fn with_alpha(&self, alpha: f64, draw: impl FnOnce(&Renderer)) {
+        self.context.save();
+        self.context.set_global_alpha(alpha);
+        draw(self);
+        self.context.restore();
+    }
+
+    pub fn draw_image_with_alpha(
+        &self,
+        image: &HtmlImageElement,
+        frame: &Rect,
+        destination: &Rect,
+        alpha: f64,
+    ) {
+        self.with_alpha(alpha, |renderer| {
+            renderer.draw_image(image, frame, destination)
+        });
+    }
+
+    pub fn draw_circle(&self, center: &Point, radius: f64, color: &str) {
+        // fill_styleをスプライトの描画に持ち越さないよう、save/restoreで囲む
+        self.context.save();
+        self.context.set_fill_style(&JsValue::from_str(color));
+        self.context.begin_path();
+        let _ = self.context.arc(
+            center.x.into(),
+            center.y.into(),
+            radius,
+            0.0,
+            std::f64::consts::PI * 2.0,
+        );
+        self.context.fill();
+        self.context.restore();
+    }
+
+    // デバッグ用に速度などのベクトルを描く
+    pub fn draw_line(&self, from: &Point, to: &Point, color: &str) {
+        self.context.save();
+        self.context.set_stroke_style(&JsValue::from_str(color));
+        self.context.begin_path();
+        self.context.move_to(from.x.into(), from.y.into());
+        self.context.line_to(to.x.into(), to.y.into());
+        self.context.stroke();
+        self.context.restore();
+    }
+
     #[allow(dead_code)]
     pub fn draw_text(&self, text: &str, location: &Point) -> Result<()> {
         self.context.set_font("16pt serif");
@@ -211,7 +806,12 @@ impl Renderer {
     }
 }
 
-#[derive(Default, Clone, Copy)]
+fn bar_fill(rect: &Rect, fraction: f64) -> Rect {
+    let width = (rect.width as f64 * fraction.clamp(0.0, 1.0)) as i16;
+    Rect::new(rect.position, width, rect.height)
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
 pub struct Rect {
     pub position: Point,
     pub width: i16,
@@ -246,6 +846,49 @@ impl Rect {
             && self.y() + self.height > rect.y()
     }
 
+    // selfがotherのどの面にぶつかったか。めり込みが浅い方の軸で判断する
+    // 縦と横で同じだけめり込んでいる角では、着地しやすいよう上下を優先する
+    pub fn collision_side(&self, other: &Rect) -> Option<Side> {
+        if !self.intersects(other) {
+            return None;
+        }
+
+        let from_top = self.bottom() - other.y();
+        let from_bottom = other.bottom() - self.y();
+        let from_left = self.right() - other.x();
+        let from_right = other.right() - self.x();
+
+        let (vertical_side, vertical_depth) = if from_top <= from_bottom {
+            (Side::Top, from_top)
+        } else {
+            (Side::Bottom, from_bottom)
+        };
+        let (horizontal_side, horizontal_depth) = if from_left <= from_right {
+            (Side::Left, from_left)
+        } else {
+            (Side::Right, from_right)
+        };
+
+        if vertical_depth <= horizontal_depth {
+            Some(vertical_side)
+        } else {
+            Some(horizontal_side)
+        }
+    }
+
+    // 両方をちょうど含む一番小さい矩形。離れていれば間の空白も含む
+    #[allow(dead_code)]
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x().min(other.x());
+        let y = self.y().min(other.y());
+        Rect::new_from_x_y(
+            x,
+            y,
+            self.right().max(other.right()) - x,
+            self.bottom().max(other.bottom()) - y,
+        )
+    }
+
     pub fn right(&self) -> i16 {
         self.x() + self.width
     }
@@ -257,6 +900,18 @@ impl Rect {
     pub fn set_x(&mut self, x: i16) {
         self.position.x = x
     }
+
+    pub fn set_y(&mut self, y: i16) {
+        self.position.y = y
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Side {
+    Top,
+    Bottom,
+    Left,
+    Right,
 }
 
 enum KeyPress {
@@ -269,24 +924,19 @@ fn prepare_input() -> Result<UnboundedReceiver<KeyPress>> {
     let keydown_sender = Rc::new(RefCell::new(keydown_sender));
     let keyup_sender = Rc::clone(&keydown_sender);
 
-    let onkeydown = browser::closure_wrap(Box::new(move |keycode: web_sys::KeyboardEvent| {
+    let onkeydown = browser::on_keydown(move |keycode| {
         let _ = keydown_sender
             .borrow_mut()
             .start_send(KeyPress::KeyDown(keycode));
-    }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
+    })?;
 
-    let onkeyup = browser::closure_wrap(Box::new(move |keycode: web_sys::KeyboardEvent| {
+    let onkeyup = browser::on_keyup(move |keycode| {
         let _ = keyup_sender
             .borrow_mut()
             .start_send(KeyPress::KeyUp(keycode));
-    }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
+    })?;
 
-    browser::canvas()
-        .unwrap()
-        .set_onkeydown(Some(onkeydown.as_ref().unchecked_ref()));
-    browser::canvas()
-        .unwrap()
-        .set_onkeyup(Some(onkeyup.as_ref().unchecked_ref()));
+    // ゲームが動いている間ずっと必要なので、破棄されないようにリークさせる
     onkeydown.forget();
     onkeyup.forget();
 
@@ -295,20 +945,40 @@ fn prepare_input() -> Result<UnboundedReceiver<KeyPress>> {
 
 pub struct KeyState {
     pressed_keys: HashMap<String, web_sys::KeyboardEvent>,
+    // 前回のupdate以降にkeydownが来たキー。フレームの間に押して離されても取りこぼさない
+    presses: Vec<String>,
 }
 
 impl KeyState {
-    fn new() -> Self {
+    pub fn new() -> Self {
         KeyState {
             pressed_keys: HashMap::new(),
+            presses: vec![],
         }
     }
 
+    pub fn presses(&self) -> &[String] {
+        &self.presses
+    }
+
+    pub fn record_press(&mut self, code: &str) {
+        self.presses.push(code.into());
+    }
+
+    fn clear_presses(&mut self) {
+        self.presses.clear();
+    }
+
     pub fn is_pressed(&self, code: &str) -> bool {
         self.pressed_keys.contains_key(code)
     }
 
-    fn set_pressed(&mut self, code: &str, event: web_sys::KeyboardEvent) {
+    // 何かのキーが押されているか、前回のupdate以降に押されたか
+    pub fn has_input(&self) -> bool {
+        !self.pressed_keys.is_empty() || !self.presses.is_empty()
+    }
+
+    pub fn set_pressed(&mut self, code: &str, event: web_sys::KeyboardEvent) {
         self.pressed_keys.insert(code.into(), event);
     }
 
@@ -317,6 +987,17 @@ impl KeyState {
     }
 }
 
+fn prepare_visibility() -> Result<UnboundedReceiver<bool>> {
+    let (sender, receiver) = unbounded();
+    let sender = RefCell::new(sender);
+    let onvisibilitychange = browser::on_visibility_change(move |hidden| {
+        let _ = sender.borrow_mut().start_send(hidden);
+    })?;
+    onvisibilitychange.forget();
+
+    Ok(receiver)
+}
+
 fn process_input(state: &mut KeyState, keyevent_listener: &mut UnboundedReceiver<KeyPress>) {
     loop {
         match keyevent_listener.try_next() {
@@ -324,7 +1005,13 @@ fn process_input(state: &mut KeyState, keyevent_listener: &mut UnboundedReceiver
             Err(_err) => break,
             Ok(Some(evt)) => match evt {
                 KeyPress::KeyUp(evt) => state.set_released(&evt.code()),
-                KeyPress::KeyDown(evt) => state.set_pressed(&evt.code(), evt),
+                KeyPress::KeyDown(evt) => {
+                    // 押しっぱなしによるキーリピートは、新しく押されたとは数えない
+                    if !evt.repeat() {
+                        state.record_press(&evt.code());
+                    }
+                    state.set_pressed(&evt.code(), evt)
+                }
             },
         }
     }
@@ -333,6 +1020,7 @@ fn process_input(state: &mut KeyState, keyevent_listener: &mut UnboundedReceiver
 pub struct Image {
     element: HtmlImageElement,
     bounding_box: Rect,
+    alpha: f64,
 }
 
 impl Image {
@@ -341,11 +1029,21 @@ impl Image {
         Self {
             element,
             bounding_box,
+            alpha: 1.0,
         }
     }
 
     pub fn draw(&self, renderer: &Renderer) {
-        renderer.draw_entire_image(&self.element, &self.bounding_box.position);
+        if self.alpha < 1.0 {
+            renderer.draw_image_with_alpha(
+                &self.element,
+                &Rect::new_from_x_y(0, 0, self.bounding_box.width, self.bounding_box.height),
+                &self.bounding_box,
+                self.alpha,
+            );
+        } else {
+            renderer.draw_entire_image(&self.element, &self.bounding_box.position);
+        }
 
         // debugオプション
         renderer.draw_rect(&self.bounding_box())
@@ -363,14 +1061,37 @@ impl Image {
         self.bounding_box.set_x(x);
     }
 
+    pub fn move_vertically(&mut self, distance: i16) {
+        self.bounding_box.set_y(self.bounding_box.y() + distance)
+    }
+
+    #[allow(dead_code)]
+    pub fn set_alpha(&mut self, alpha: f64) {
+        self.alpha = alpha;
+    }
+
+    // 位置はそのままで、描く画像だけ差し替える
+    pub fn set_element(&mut self, element: HtmlImageElement) {
+        self.bounding_box.width = element.width() as i16;
+        self.bounding_box.height = element.height() as i16;
+        self.element = element;
+    }
+
     pub fn right(&self) -> i16 {
         self.bounding_box.right()
     }
 }
 
+const TONE_VOLUME: f32 = 0.3;
+
 #[derive(Clone)]
 pub struct Audio {
     context: AudioContext,
+    // ユーザー操作のあとでAudioContextを再開したか。クローンした全員で共有する
+    resumed: Rc<std::cell::Cell<bool>>,
+    // 再開前に頼まれた効果音。再開したときにまとめて鳴らす
+    // 同じ音は一つだけ残すので、デモのように何度も鳴らされても溜まり続けない
+    queued: Rc<RefCell<Vec<Sound>>>,
 }
 
 #[derive(Clone)]
@@ -382,9 +1103,35 @@ impl Audio {
     pub fn new() -> Result<Self> {
         Ok(Audio {
             context: sound::create_audio_context()?,
+            resumed: Rc::new(std::cell::Cell::new(false)),
+            queued: Rc::new(RefCell::new(vec![])),
         })
     }
 
+    // ブラウザはユーザー操作があるまで音を出させないので、最初の操作でAudioContextを再開する
+    pub fn resume_on_gesture(&self) -> Result<()> {
+        let audio = self.clone();
+        browser::on_first_gesture(move || {
+            if let Err(err) = audio.unlock() {
+                error!("Could not resume audio {:#?}", err);
+            }
+        })
+    }
+
+    fn unlock(&self) -> Result<()> {
+        // resumeはPromiseを返すが、待たなくても再開は始まる
+        let _ = self
+            .context
+            .resume()
+            .map_err(|err| anyhow!("Could not resume audio context {:#?}", err))?;
+        self.resumed.set(true);
+        let queued: Vec<Sound> = self.queued.borrow_mut().drain(..).collect();
+        for sound in queued {
+            self.play(&sound, sound::LOOPING::NO, 0.0)?;
+        }
+        Ok(())
+    }
+
     pub async fn load_sound(&self, filename: &str) -> Result<Sound> {
         let array_buffer = browser::fetch_array_buffer(filename).await?;
         let audio_buffer = sound::decode_audio_data(&self.context, &array_buffer).await?;
@@ -393,12 +1140,105 @@ impl Audio {
         })
     }
 
-    pub fn play_sound(&self, sound: &Sound) -> Result<()> {
-        sound::play_sound(&self.context, &sound.buffer, sound::LOOPING::NO)
+    // 周波数をfrom_hzからto_hzへ滑らかに変える、短い正弦波の音を作る
+    // 最後に向けて小さくして、ぷつっと切れないようにする
+    pub fn create_tone(&self, from_hz: f32, to_hz: f32, seconds: f32) -> Result<Sound> {
+        let sample_rate = self.context.sample_rate();
+        let length = (sample_rate * seconds) as u32;
+        let buffer = self
+            .context
+            .create_buffer(1, length, sample_rate)
+            .map_err(|err| anyhow!("Could not create tone buffer {:#?}", err))?;
+        let mut phase = 0.0f32;
+        let samples: Vec<f32> = (0..length)
+            .map(|index| {
+                let progress = index as f32 / length as f32;
+                phase +=
+                    std::f32::consts::TAU * (from_hz + (to_hz - from_hz) * progress) / sample_rate;
+                phase.sin() * (1.0 - progress) * TONE_VOLUME
+            })
+            .collect();
+        buffer
+            .copy_to_channel(&samples, 0)
+            .map_err(|err| anyhow!("Could not write tone samples {:#?}", err))?;
+        Ok(Sound { buffer })
+    }
+
+    #[cfg(test)]
+    pub fn queued_count(&self) -> usize {
+        self.queued.borrow().len()
+    }
+
+    // 再開前は鳴らさずに溜めておき、Noneを返す
+    pub fn play_sound(&self, sound: &Sound) -> Result<Option<SoundHandle>> {
+        if !self.resumed.get() {
+            let mut queued = self.queued.borrow_mut();
+            if queued.is_empty() {
+                log!("Audio is not resumed yet, queueing sounds");
+            }
+            if !queued.iter().any(|pending| pending.buffer == sound.buffer) {
+                queued.push(sound.clone());
+            }
+            return Ok(None);
+        }
+        self.play(sound, sound::LOOPING::NO, 0.0).map(Some)
+    }
+
+    pub fn play_looping_sound(&self, sound: &Sound) -> Result<SoundHandle> {
+        self.play(sound, sound::LOOPING::YES, 0.0)
+    }
+
+    fn play(&self, sound: &Sound, looping: sound::LOOPING, offset: f64) -> Result<SoundHandle> {
+        let node = sound::play_sound(&self.context, &sound.buffer, looping, offset)?;
+        Ok(SoundHandle {
+            node,
+            started_at: self.context.current_time() - offset,
+            paused_at: None,
+        })
     }
 
-    pub fn play_looping_sound(&self, sound: &Sound) -> Result<()> {
-        sound::play_sound(&self.context, &sound.buffer, sound::LOOPING::YES)
+    pub fn stop<N: Playback>(&self, handle: &SoundHandle<N>) -> Result<()> {
+        handle.node.stop()
+    }
+
+    // 音を止めて、どこまで鳴ったかを覚えておく
+    pub fn pause<N: Playback>(&self, handle: &mut SoundHandle<N>) -> Result<()> {
+        if handle.paused_at.is_none() {
+            self.stop(handle)?;
+            handle.paused_at = Some(self.context.current_time() - handle.started_at);
+        }
+        Ok(())
+    }
+
+    // 止めた位置からループ再生をやり直す
+    // AudioBufferSourceNodeは一度しかstartできないので、新しく作って差し替える
+    pub fn resume(&self, sound: &Sound, handle: &mut SoundHandle) -> Result<()> {
+        if let Some(position) = handle.paused_at {
+            let duration = sound.buffer.duration();
+            let offset = if duration > 0.0 {
+                position % duration
+            } else {
+                0.0
+            };
+            *handle = self.play(sound, sound::LOOPING::YES, offset)?;
+        }
+        Ok(())
+    }
+}
+
+// 鳴らした音のハンドル。Audio::stopやpause/resumeに渡して使う
+pub struct SoundHandle<N: Playback = AudioBufferSourceNode> {
+    node: N,
+    // 音の先頭を鳴らした（ことになる）AudioContext上の時刻
+    started_at: f64,
+    // 一時停止中なら、止めたときの再生位置（秒）
+    paused_at: Option<f64>,
+}
+
+impl<N: Playback> SoundHandle<N> {
+    #[allow(dead_code)]
+    pub fn is_paused(&self) -> bool {
+        self.paused_at.is_some()
     }
 }
 
@@ -414,7 +1254,24 @@ pub fn add_click_handler(elem: HtmlElement) -> UnboundedReceiver<()> {
     click_receiver
 }
 
-unsafe fn draw_frame_rate(renderer: &Renderer, frame_time: f64) {
+// 入力欄でEnterが押されたら、その文字列を送って欄を空にする
+pub fn add_submit_handler(input: HtmlInputElement) -> UnboundedReceiver<String> {
+    let (mut submit_sender, submit_receiver) = unbounded();
+    let target = input.clone();
+    let on_keydown = browser::closure_wrap(Box::new(move |event: web_sys::KeyboardEvent| {
+        if event.code() == "Enter" {
+            let _ = submit_sender.start_send(target.value());
+            target.set_value("");
+        }
+    }) as Box<dyn FnMut(web_sys::KeyboardEvent)>);
+
+    input.set_onkeydown(Some(on_keydown.as_ref().unchecked_ref()));
+    on_keydown.forget();
+
+    submit_receiver
+}
+
+unsafe fn draw_frame_rate(renderer: &Renderer, frame_time: f64, update_time: f64) {
     static mut FRAMES_COUNTED: i32 = 0;
     static mut TOTAL_FRAME_TIME: f64 = 0.0;
     static mut FRAME_RATE: i32 = 0;
@@ -434,11 +1291,104 @@ unsafe fn draw_frame_rate(renderer: &Renderer, frame_time: f64) {
     ) {
         error!("Could not draw text {:#?}", err);
     }
+    if let Err(err) = renderer.draw_text(
+        &format!("Update {:.1}ms", update_time),
+        &Point { x: 400, y: 160 },
+    ) {
+        error!("Could not draw text {:#?}", err);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    fn test_renderer() -> Renderer {
+        let canvas = browser::document()
+            .unwrap()
+            .create_element("canvas")
+            .unwrap()
+            .dyn_into::<web_sys::HtmlCanvasElement>()
+            .unwrap();
+        Renderer::new(browser::context_of(&canvas).unwrap())
+    }
+
+    fn pixel_at(renderer: &Renderer, x: f64, y: f64) -> Vec<u8> {
+        renderer
+            .context
+            .get_image_data(x, y, 1.0, 1.0)
+            .unwrap()
+            .data()
+            .to_vec()
+    }
+
+    #[wasm_bindgen_test]
+    fn test_image_batch_issues_one_draw_per_entry() {
+        let draws = [
+            (
+                Rect::new_from_x_y(0, 0, 128, 93),
+                Rect::new_from_x_y(10, 20, 128, 93),
+            ),
+            (
+                Rect::new_from_x_y(128, 0, 128, 93),
+                Rect::new_from_x_y(138, 20, 128, 93),
+            ),
+            (
+                Rect::new_from_x_y(256, 0, 128, 93),
+                Rect::new_from_x_y(266, 20, 128, 93),
+            ),
+        ];
+        let renderer = test_renderer();
+        renderer.count_image_draws();
+
+        renderer.draw_image_batch(&HtmlImageElement::new().unwrap(), &draws);
+        assert_eq!(renderer.image_draws(), 3);
+
+        renderer.draw_image_batch(&HtmlImageElement::new().unwrap(), &[]);
+        assert_eq!(renderer.image_draws(), 3);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_scale_for_pixel_ratio() {
+        let renderer = Renderer::with_backbuffer(test_renderer().context);
+        let visible = renderer.visible.as_ref().unwrap().canvas().unwrap();
+        let logical_width = visible.width();
+
+        renderer.scale_for_pixel_ratio(2.0).unwrap();
+
+        assert_eq!(visible.width(), logical_width * 2);
+        assert_eq!(
+            renderer.context.canvas().unwrap().width(),
+            logical_width * 2
+        );
+        // 論理座標の1ピクセルが、実際には2x2ピクセルになる
+        renderer.fill_rect(&Rect::new_from_x_y(10, 10, 1, 1), "#FF0000");
+        assert_eq!(pixel_at(&renderer, 21.0, 21.0)[3], 255);
+        assert_eq!(pixel_at(&renderer, 22.0, 22.0)[3], 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_set_image_smoothing() {
+        let renderer = Renderer::with_backbuffer(test_renderer().context);
+
+        renderer.set_image_smoothing(false);
+
+        assert!(!renderer.context.image_smoothing_enabled());
+        assert!(!renderer.visible.as_ref().unwrap().image_smoothing_enabled());
+    }
+
+    #[test]
+    fn lerp_at_half_alpha_is_the_midpoint() {
+        let from = Point { x: 10, y: 400 };
+        let to = Point { x: 30, y: 380 };
+
+        let halfway = from.lerp(&to, 0.5);
+
+        assert_eq!((halfway.x, halfway.y), (20, 390));
+        assert_eq!(from.lerp(&to, 0.0).y, 400);
+        assert_eq!(from.lerp(&to, 1.0).y, 380);
+    }
 
     #[test]
     fn two_rects_that_intersect_on_the_left() {
@@ -456,4 +1406,507 @@ mod tests {
 
         assert_eq!(rect2.intersects(&rect1), true);
     }
+
+    #[test]
+    fn collision_side_is_the_axis_with_the_least_penetration() {
+        let platform = Rect::new_from_x_y(100, 100, 100, 50);
+
+        let landing = Rect::new_from_x_y(120, 60, 40, 45);
+        let bumping_head = Rect::new_from_x_y(120, 145, 40, 45);
+        let running_into = Rect::new_from_x_y(65, 90, 40, 45);
+        let from_behind = Rect::new_from_x_y(195, 90, 40, 45);
+
+        assert_eq!(landing.collision_side(&platform), Some(Side::Top));
+        assert_eq!(bumping_head.collision_side(&platform), Some(Side::Bottom));
+        assert_eq!(running_into.collision_side(&platform), Some(Side::Left));
+        assert_eq!(from_behind.collision_side(&platform), Some(Side::Right));
+    }
+
+    #[test]
+    fn collision_side_prefers_top_and_bottom_on_corners() {
+        let platform = Rect::new_from_x_y(100, 100, 100, 50);
+
+        let top_left_corner = Rect::new_from_x_y(65, 65, 40, 40);
+        let bottom_right_corner = Rect::new_from_x_y(195, 145, 40, 40);
+        let apart = Rect::new_from_x_y(0, 0, 40, 40);
+
+        assert_eq!(top_left_corner.collision_side(&platform), Some(Side::Top));
+        assert_eq!(
+            bottom_right_corner.collision_side(&platform),
+            Some(Side::Bottom)
+        );
+        assert_eq!(apart.collision_side(&platform), None);
+    }
+
+    fn extent(rect: &Rect) -> (i16, i16, i16, i16) {
+        (rect.x(), rect.y(), rect.width, rect.height)
+    }
+
+    #[test]
+    fn union_of_disjoint_rects_spans_the_gap() {
+        let left = Rect::new_from_x_y(0, 50, 10, 10);
+        let right = Rect::new_from_x_y(100, 0, 20, 30);
+
+        assert_eq!(extent(&left.union(&right)), (0, 0, 120, 60));
+        assert_eq!(extent(&right.union(&left)), (0, 0, 120, 60));
+    }
+
+    #[test]
+    fn union_with_a_contained_rect_is_the_outer_rect() {
+        let outer = Rect::new_from_x_y(10, 10, 100, 100);
+        let inner = Rect::new_from_x_y(20, 30, 10, 10);
+
+        assert_eq!(extent(&outer.union(&inner)), extent(&outer));
+        assert_eq!(extent(&inner.union(&outer)), extent(&outer));
+    }
+
+    #[wasm_bindgen_test]
+    fn draw_circle_fills_the_arc_and_restores_fill_style() {
+        let renderer = test_renderer();
+        let fill_style = renderer.context.fill_style();
+
+        renderer.draw_circle(&Point { x: 50, y: 50 }, 10.0, "#FF0000");
+
+        assert_eq!(pixel_at(&renderer, 50.0, 50.0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel_at(&renderer, 70.0, 70.0), vec![0, 0, 0, 0]);
+        assert_eq!(renderer.context.fill_style(), fill_style);
+    }
+
+    #[wasm_bindgen_test]
+    fn with_clip_limits_drawing_to_the_rect_until_it_returns() {
+        let renderer = test_renderer();
+        let everything = Rect::new_from_x_y(0, 0, 100, 100);
+
+        renderer.with_clip(&Rect::new_from_x_y(10, 10, 20, 20), |renderer| {
+            renderer.fill_rect(&everything, "#FF0000");
+        });
+
+        assert_eq!(pixel_at(&renderer, 15.0, 15.0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel_at(&renderer, 50.0, 50.0), vec![0, 0, 0, 0]);
+
+        renderer.fill_rect(&everything, "#00FF00");
+        assert_eq!(pixel_at(&renderer, 50.0, 50.0), vec![0, 255, 0, 255]);
+    }
+
+    #[wasm_bindgen_test]
+    fn draw_line_strokes_the_path_and_restores_stroke_style() {
+        let renderer = test_renderer();
+        renderer.context.set_line_width(4.0);
+        let stroke_style = renderer.context.stroke_style();
+
+        renderer.draw_line(&Point { x: 10, y: 20 }, &Point { x: 90, y: 20 }, "#FF0000");
+
+        assert_eq!(pixel_at(&renderer, 50.0, 20.0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel_at(&renderer, 50.0, 40.0), vec![0, 0, 0, 0]);
+        assert_eq!(renderer.context.stroke_style(), stroke_style);
+    }
+
+    #[wasm_bindgen_test]
+    fn clear_with_color_fills_only_the_given_rect() {
+        let renderer = test_renderer();
+        let fill_style = renderer.context.fill_style();
+
+        renderer.clear_with_color(&Rect::new_from_x_y(0, 0, 20, 20), "#FF0000");
+
+        assert_eq!(pixel_at(&renderer, 10.0, 10.0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel_at(&renderer, 30.0, 30.0), vec![0, 0, 0, 0]);
+        assert_eq!(renderer.context.fill_style(), fill_style);
+    }
+
+    #[test]
+    fn bar_fill_is_scaled_and_clamped_to_the_fraction() {
+        let rect = Rect::new_from_x_y(10, 20, 200, 8);
+
+        let widths: Vec<i16> = [0.0, 0.25, 0.5, 1.0, -0.5, 1.5]
+            .iter()
+            .map(|fraction| bar_fill(&rect, *fraction).width)
+            .collect();
+
+        assert_eq!(widths, vec![0, 50, 100, 200, 0, 200]);
+        assert_eq!(bar_fill(&rect, 0.5).x(), 10);
+        assert_eq!(bar_fill(&rect, 0.5).height, 8);
+    }
+
+    #[wasm_bindgen_test]
+    fn draw_bar_fills_the_foreground_up_to_the_fraction() {
+        let renderer = test_renderer();
+
+        renderer.draw_bar(
+            &Rect::new_from_x_y(0, 0, 100, 10),
+            0.5,
+            "#FF0000",
+            "#0000FF",
+        );
+
+        assert_eq!(pixel_at(&renderer, 49.0, 5.0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel_at(&renderer, 50.0, 5.0), vec![0, 0, 255, 255]);
+    }
+
+    #[wasm_bindgen_test]
+    fn with_alpha_applies_only_inside_the_draw() {
+        let renderer = test_renderer();
+
+        renderer.with_alpha(0.5, |renderer| {
+            assert_eq!(renderer.context.global_alpha(), 0.5);
+            renderer.fill_rect(&Rect::new_from_x_y(0, 0, 10, 10), "#FF0000");
+        });
+
+        assert_eq!(renderer.context.global_alpha(), 1.0);
+        assert_eq!(pixel_at(&renderer, 5.0, 5.0)[3], 128);
+    }
+
+    #[wasm_bindgen_test]
+    fn backbuffer_is_shown_only_after_present() {
+        let visible = test_renderer();
+        let renderer = Renderer::with_backbuffer(visible.context.clone());
+        assert!(renderer.visible.is_some());
+
+        renderer.draw_circle(&Point { x: 50, y: 50 }, 10.0, "#FF0000");
+        assert_eq!(pixel_at(&visible, 50.0, 50.0), vec![0, 0, 0, 0]);
+
+        renderer.present();
+        assert_eq!(pixel_at(&visible, 50.0, 50.0), vec![255, 0, 0, 255]);
+    }
+
+    // 1x1の透明なPNG
+    const PNG_DATA_URL: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAQAAAC1HAwCAAAAC0lEQVR42mNkYAAAAAYAAjCB0C8AAAAASUVORK5CYII=";
+
+    #[wasm_bindgen_test]
+    async fn load_image_with_fallback_uses_the_fallback_on_error() {
+        let image = load_image_with_fallback("data:image/webp;base64,broken", PNG_DATA_URL)
+            .await
+            .unwrap();
+
+        assert_eq!(image.src(), PNG_DATA_URL);
+        assert_eq!(image.width(), 1);
+    }
+
+    // 左が赤、右が青の2x1の画像
+    const RED_BLUE_PNG: &str = "data:image/png;base64,iVBORw0KGgoAAAANSUhEUgAAAAIAAAABCAYAAAD0In+KAAAADklEQVR4nGP4z8AAQv8BD/kD/YURmXYAAAAASUVORK5CYII=";
+
+    #[wasm_bindgen_test]
+    async fn draw_image_flipped_mirrors_the_image_and_restores_the_transform() {
+        let renderer = test_renderer();
+        let image = load_image(RED_BLUE_PNG).await.unwrap();
+        let frame = Rect::new_from_x_y(0, 0, 2, 1);
+
+        renderer.draw_image_flipped(&image, &frame, &Rect::new_from_x_y(10, 0, 2, 1), true);
+        assert_eq!(pixel_at(&renderer, 10.0, 0.0), vec![0, 0, 255, 255]);
+        assert_eq!(pixel_at(&renderer, 11.0, 0.0), vec![255, 0, 0, 255]);
+
+        renderer.draw_image(&image, &frame, &Rect::new_from_x_y(20, 0, 2, 1));
+        assert_eq!(pixel_at(&renderer, 20.0, 0.0), vec![255, 0, 0, 255]);
+        assert_eq!(pixel_at(&renderer, 21.0, 0.0), vec![0, 0, 255, 255]);
+    }
+
+    #[wasm_bindgen_test]
+    async fn load_image_errors_with_the_path_for_a_bad_source() {
+        let result = load_image("data:image/png;base64,broken").await;
+
+        let err = result.err().unwrap();
+        assert!(format!("{}", err).contains("data:image/png;base64,broken"));
+    }
+
+    #[test]
+    fn update_budget_trips_once_after_consecutive_slow_updates() {
+        let mut budget = UpdateBudget::new(8.0, 3);
+
+        assert!(!budget.record(20.0));
+        assert!(!budget.record(20.0));
+        assert!(!budget.record(2.0));
+
+        let trips: Vec<bool> = (0..5).map(|_| budget.record(20.0)).collect();
+        assert_eq!(trips, vec![false, false, true, false, false]);
+        assert_eq!(budget.last_update(), 20.0);
+    }
+
+    struct MockNode {
+        stops: std::cell::Cell<u32>,
+    }
+
+    impl Playback for MockNode {
+        fn stop(&self) -> Result<()> {
+            self.stops.set(self.stops.get() + 1);
+            Ok(())
+        }
+    }
+
+    fn mock_handle() -> SoundHandle<MockNode> {
+        SoundHandle {
+            node: MockNode {
+                stops: std::cell::Cell::new(0),
+            },
+            started_at: 0.0,
+            paused_at: None,
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn stop_stops_the_underlying_node() {
+        let audio = Audio::new().unwrap();
+        let handle = mock_handle();
+
+        audio.stop(&handle).unwrap();
+
+        assert_eq!(handle.node.stops.get(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn each_sound_played_before_resume_is_queued_once_until_unlock() {
+        let audio = Audio::new().unwrap();
+        let sound = Sound {
+            buffer: AudioBuffer::new(&web_sys::AudioBufferOptions::new(1, 3000.0)).unwrap(),
+        };
+
+        let other = Sound {
+            buffer: AudioBuffer::new(&web_sys::AudioBufferOptions::new(1, 3000.0)).unwrap(),
+        };
+
+        for _ in 0..5 {
+            assert!(audio.play_sound(&sound).unwrap().is_none());
+        }
+        audio.play_sound(&other).unwrap();
+        assert_eq!(audio.queued.borrow().len(), 2);
+
+        audio.unlock().unwrap();
+
+        assert!(audio.queued.borrow().is_empty());
+        assert!(audio.play_sound(&sound).unwrap().is_some());
+    }
+
+    #[wasm_bindgen_test]
+    fn pausing_twice_stops_the_node_once() {
+        let audio = Audio::new().unwrap();
+        let mut handle = mock_handle();
+
+        audio.pause(&mut handle).unwrap();
+        audio.pause(&mut handle).unwrap();
+
+        assert!(handle.is_paused());
+        assert_eq!(handle.node.stops.get(), 1);
+    }
+
+    #[derive(Default)]
+    struct CountingGame {
+        updates: u32,
+        paused: bool,
+    }
+
+    #[async_trait(?Send)]
+    impl Game for CountingGame {
+        async fn initialize(&self) -> Result<Box<dyn Game>> {
+            Ok(Box::new(CountingGame::default()))
+        }
+
+        fn update(&mut self, _keystate: &KeyState) {
+            self.updates += 1;
+        }
+
+        fn draw(&self, _renderer: &Renderer, _alpha: f32) {}
+
+        fn on_pause(&mut self) {
+            self.paused = true;
+        }
+
+        fn on_resume(&mut self) {
+            self.paused = false;
+        }
+    }
+
+    fn test_game_loop() -> GameLoop {
+        GameLoop {
+            last_frame: 0.0,
+            accumulated_delta: 0.0,
+            clock: Clock::new(),
+            update_budget: UpdateBudget::new(UPDATE_BUDGET_MS, OVER_BUDGET_FRAMES),
+            frame_limiter: FrameLimiter::new(),
+            frame_stepper: FrameStepper::default(),
+            paused_by_visibility: false,
+        }
+    }
+
+    #[test]
+    fn frame_names_yields_every_frame() {
+        let cell = Cell {
+            frame: SheetRect {
+                x: 0,
+                y: 0,
+                w: 1,
+                h: 1,
+            },
+            sprite_source_size: SheetRect {
+                x: 0,
+                y: 0,
+                w: 1,
+                h: 1,
+            },
+        };
+        let sheet = Sheet {
+            frames: ["Idle (1).png", "Run (1).png", "Dead (1).png"]
+                .iter()
+                .map(|name| (name.to_string(), cell.clone()))
+                .collect(),
+        };
+
+        let mut names: Vec<&str> = sheet.frame_names().collect();
+        names.sort_unstable();
+
+        assert_eq!(names, vec!["Dead (1).png", "Idle (1).png", "Run (1).png"]);
+    }
+
+    #[derive(Deserialize, Debug)]
+    struct JsonFixture {
+        name: String,
+        size: i16,
+    }
+
+    fn fixture_loader(
+        json: &'static str,
+    ) -> impl FnOnce(String) -> futures::future::Ready<Result<JsValue>> {
+        move |_path| futures::future::ready(Ok(js_sys::JSON::parse(json).unwrap()))
+    }
+
+    #[wasm_bindgen_test]
+    async fn load_json_deserializes_into_the_requested_type() {
+        let fixture: JsonFixture = load_json_with(
+            "fixture.json",
+            fixture_loader(r#"{ "name": "stone", "size": 90 }"#),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(fixture.name, "stone");
+        assert_eq!(fixture.size, 90);
+    }
+
+    #[wasm_bindgen_test]
+    async fn load_json_names_the_path_and_type_when_the_json_does_not_fit() {
+        let err = load_json_with::<JsonFixture, _, _>(
+            "fixture.json",
+            fixture_loader(r#"{ "name": "stone", "size": "big" }"#),
+        )
+        .await
+        .unwrap_err()
+        .to_string();
+
+        assert!(err.contains("fixture.json"));
+        assert!(err.contains("JsonFixture"));
+    }
+
+    #[wasm_bindgen_test]
+    fn sprite_sheet_visits_every_tile_once() {
+        use std::collections::HashSet;
+
+        let json = js_sys::JSON::parse(include_str!("../static/tiles.json")).unwrap();
+        let sprite_sheet =
+            SpriteSheet::new(json.into_serde().unwrap(), HtmlImageElement::new().unwrap());
+
+        let names: HashSet<&str> = sprite_sheet.cells().map(|(name, _cell)| name).collect();
+
+        assert_eq!(sprite_sheet.cell_count(), 18);
+        assert_eq!(sprite_sheet.cells().count(), 18);
+        assert_eq!(names.len(), 18);
+    }
+
+    #[test]
+    fn huge_frame_time_is_clamped() {
+        assert_eq!(clamp_frame_time(10_000.0), MAX_FRAME_TIME);
+        assert_eq!(clamp_frame_time(10.0), 10.0);
+    }
+
+    #[wasm_bindgen_test]
+    fn huge_frame_time_runs_at_most_three_updates() {
+        let mut game_loop = test_game_loop();
+        let mut game = CountingGame::default();
+        let mut keystate = KeyState::new();
+
+        game_loop.tick(&mut game, &mut keystate, 10_000.0);
+
+        assert!(game.updates <= 3);
+        assert_eq!(game_loop.clock.elapsed(), MAX_FRAME_TIME);
+    }
+
+    #[wasm_bindgen_test]
+    fn hiding_the_tab_pauses_the_game() {
+        let mut game_loop = test_game_loop();
+        let mut game = CountingGame::default();
+        let mut keystate = KeyState::new();
+
+        game_loop.set_hidden(&mut game, true, 0.0);
+        game_loop.tick(&mut game, &mut keystate, 100.0);
+        assert!(game.paused);
+        assert_eq!(game.updates, 0);
+
+        game_loop.set_hidden(&mut game, false, 5000.0);
+        assert!(!game.paused);
+        assert!(!game_loop.clock.is_paused());
+        assert_eq!(game_loop.last_frame, 5000.0);
+    }
+
+    #[wasm_bindgen_test]
+    fn step_while_paused_runs_exactly_one_update() {
+        let mut game_loop = test_game_loop();
+        let mut game = CountingGame::default();
+        let mut keystate = KeyState::new();
+
+        keystate.record_press("KeyP");
+        game_loop.tick(&mut game, &mut keystate, 100.0);
+        assert_eq!(game.updates, 0);
+
+        keystate.record_press("Period");
+        game_loop.tick(&mut game, &mut keystate, 100.0);
+        assert_eq!(game.updates, 1);
+
+        game_loop.tick(&mut game, &mut keystate, 100.0);
+        assert_eq!(game.updates, 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn fast_forward_runs_four_times_as_many_updates() {
+        let mut game_loop = test_game_loop();
+        let mut game = CountingGame::default();
+        let mut keystate = KeyState::new();
+
+        game_loop.tick(&mut game, &mut keystate, 40.0);
+        assert_eq!(game.updates, 2);
+
+        // 前回の端数6.7msに、4倍した30msが足される
+        keystate.record_press("BracketRight");
+        game_loop.tick(&mut game, &mut keystate, 30.0);
+        assert_eq!(game.updates, 2 + 7);
+    }
+
+    #[test]
+    fn fps_cap_of_30_runs_about_half_of_60hz_callbacks() {
+        let mut limiter = FrameLimiter::new();
+        limiter.set_fps_cap(Some(30));
+
+        let frame = 1000.0 / 60.0;
+        let runs = (0..60)
+            .filter(|i| limiter.should_run(*i as f64 * frame))
+            .count();
+
+        assert!((29..=31).contains(&runs), "ran {} of 60 callbacks", runs);
+    }
+
+    #[test]
+    fn uncapped_limiter_runs_every_callback() {
+        let mut limiter = FrameLimiter::new();
+
+        assert!((0..10).all(|i| limiter.should_run(i as f64)));
+    }
+
+    #[test]
+    fn clock_does_not_advance_while_paused() {
+        let mut clock = Clock::new();
+        clock.advance(100.0);
+
+        clock.pause();
+        clock.advance(500.0);
+        assert_eq!(clock.elapsed(), 100.0);
+
+        clock.resume();
+        clock.advance(16.0);
+        assert_eq!(clock.elapsed(), 116.0);
+    }
 }
@@ -1,18 +1,29 @@
-use std::rc::Rc;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    future::Future,
+    rc::Rc,
+};
 
 use self::red_hat_boy_states::*;
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use async_trait::async_trait;
-use futures::channel::mpsc::UnboundedReceiver;
+use futures::{channel::mpsc::UnboundedReceiver, future::try_join_all, try_join};
 use gloo_utils::format::JsValueSerdeExt;
-use rand::{thread_rng, Rng};
+use rand::{rngs::StdRng, thread_rng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::JsValue;
 use web_sys::HtmlImageElement;
 
 use crate::{
     engine::{
-        self, Audio, Cell, Game, Image, KeyState, Point, Rect, Renderer, Sheet, Sound, SpriteSheet,
+        self, Audio, Cell, Game, Image, KeyState, Point, Rect, Renderer, Sheet, Side, Sound,
+        SoundHandle, SpriteSheet,
+    },
+    segment::{
+        load_segment, lone_stone, low_barrier, next_obstacle_id, platform_and_stone,
+        stone_and_platform, tutorial, PropImages, SegmentData,
     },
-    segment::{platform_and_stone, stone_and_platform},
 };
 
 #[cfg(test)]
@@ -23,9 +34,329 @@ use test_browser as browser;
 #[cfg(not(test))]
 use crate::browser;
 
+const WIDTH: i16 = 600;
 const HEIGHT: i16 = 600;
-const TIMELINE_MINIMUM: i16 = 1000;
-const OBSTACLE_BUFFER: i16 = 20;
+
+// 障害物を一つ抜けるごとの基本点と、コンボ倍率の上限
+const OBSTACLE_SCORE: i32 = 10;
+// 避けるのが難しい障害物ほど高い点にする
+const LOW_BARRIER_SCORE: i32 = 15;
+const PLATFORM_SCORE: i32 = 20;
+// これ以上の障害物が画面に残っている間は、次のセグメントを作らない
+const MAX_ACTIVE_OBSTACLES: usize = 24;
+// generate_next_segmentにRustで書いてあるセグメントの数
+const BUILTIN_SEGMENTS: usize = 3;
+const MAX_COMBO_MULTIPLIER: i32 = 5;
+
+// やられた瞬間の画面の揺れ。揺れ幅はフレームごとに減衰する
+const SHAKE_FRAMES: u8 = 12;
+const SHAKE_INTENSITY: i16 = 8;
+// コンボが途切れたときに画面を白く光らせるフレーム数
+const COMBO_BREAK_FLASH_FRAMES: u8 = 2;
+// ポーズメニューの後ろで、止まった画面を暗くする色
+const PAUSE_MENU_DIM_COLOR: &str = "rgba(0, 0, 0, 0.5)";
+// セグメントごとに切り替わる背景。画像を足せばその分だけテーマが増える
+const BACKGROUND_THEMES: [&str; 3] = ["BG.png", "City.png", "Cave.png"];
+// テーマが変わってから、前のテーマが消えきるまでのフレーム数
+const THEME_FADE_FRAMES: u8 = 60;
+// 昼→夜→昼を一周する走行距離
+const DAY_LENGTH: i32 = 12000;
+// 真夜中でも少年と障害物が見えるよう、暗さには上限を設ける
+const MAX_NIGHT_ALPHA: f64 = 0.5;
+
+const NOTIFICATION_FRAMES: u8 = 90;
+// 消える前の何フレームかで薄くしていく
+const NOTIFICATION_FADE_FRAMES: u8 = 30;
+const PIXELS_PER_METER: i32 = 10;
+// この距離（ピクセル）ごとにチェックポイントを置き、少しの間だけ障害物を止めてから難しくする
+const CHECKPOINT_DISTANCE: i32 = 5000;
+const CHECKPOINT_PAUSE_FRAMES: u16 = 180;
+const DISTANCE_MILESTONE: i32 = 100;
+
+const LOADING_BAR_COLOR: &str = "#FFFFFF";
+const LOADING_BAR_BACKGROUND: &str = "#333333";
+const DASH_METER_BACKGROUND: &str = "rgba(0, 0, 0, 0.4)";
+
+// 自己ベストのゴーストの透明度
+const GHOST_ALPHA: f64 = 0.35;
+
+// アニメーションごとの描画位置の補正。状態が変わったときに絵が跳ねて見えないようにする
+// 当たり判定には影響しない。載っていない状態は補正なし
+const ANIMATION_OFFSETS: [(&str, Point); 3] = [
+    ("Sliding", Point { x: -4, y: 3 }),
+    ("Falling", Point { x: -8, y: 0 }),
+    ("KnockedOut", Point { x: -8, y: 0 }),
+];
+
+fn animation_offset(state_name: &str) -> Point {
+    ANIMATION_OFFSETS
+        .iter()
+        .find(|(name, _)| *name == state_name)
+        .map_or(Point::default(), |(_, offset)| *offset)
+}
+
+const SHIELD_RADIUS: f64 = 70.0;
+// 磁石の効く時間と範囲。範囲内のコインは1フレームにMAGNET_PULLずつ寄ってくる
+const MAGNET_FRAMES: u16 = 300;
+const MAGNET_RADIUS: f64 = 200.0;
+const MAGNET_PULL: i16 = 6;
+// デバッグ用に描く速度の線の長さの倍率
+const VELOCITY_LINE_SCALE: i16 = 4;
+// ハイコントラストモードの縁取りの太さと、背景を暗くする色。縁取りの色はパレットから引く
+const HIGH_CONTRAST_LINE_WIDTH: f64 = 3.0;
+const HIGH_CONTRAST_DIM_COLOR: &str = "rgba(0, 0, 0, 0.6)";
+// この速さ以上で走っているか、ダッシュ中は残像を出す
+const TRAIL_LENGTH: usize = 5;
+const TRAIL_MIN_SPEED: i16 = 6;
+const TRAIL_ALPHA: f64 = 0.4;
+const COIN_SCORE: i32 = 5;
+
+#[derive(Clone, Copy, PartialEq, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    // URLパラメータ（?difficulty=hard など）から難易度を決める
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "easy" => Some(Difficulty::Easy),
+            "normal" => Some(Difficulty::Normal),
+            "hard" => Some(Difficulty::Hard),
+            _ => None,
+        }
+    }
+
+    fn settings(&self) -> DifficultySettings {
+        match self {
+            Difficulty::Easy => DifficultySettings {
+                obstacle_buffer: 150,
+                timeline_minimum: 1000,
+                running_speed: 3,
+                crumbling_platforms: false,
+                grace_distance: 600,
+            },
+            Difficulty::Normal => DifficultySettings {
+                obstacle_buffer: 20,
+                timeline_minimum: 1000,
+                running_speed: 4,
+                crumbling_platforms: false,
+                grace_distance: 300,
+            },
+            Difficulty::Hard => DifficultySettings {
+                obstacle_buffer: 0,
+                timeline_minimum: 1200,
+                running_speed: 5,
+                crumbling_platforms: true,
+                grace_distance: 0,
+            },
+        }
+    }
+}
+
+// 地面の高さなど、ステージの縦方向のレイアウトと、落ち方
+#[derive(Clone, Copy)]
+pub struct LevelConfig {
+    // 少年が立つ位置（スプライトの左上のy座標）
+    pub floor: i16,
+    // 地面の表面のy座標
+    pub height: i16,
+    pub ceiling: i16,
+    // 上昇中と下降中に毎フレーム足す重力。上昇を弱くするとふわっと跳んで、すとんと落ちる
+    pub rise_gravity: i16,
+    pub fall_gravity: i16,
+    pub terminal_velocity: i16,
+}
+
+impl LevelConfig {
+    pub fn player_height(&self) -> i16 {
+        self.height - self.floor
+    }
+}
+
+impl Default for LevelConfig {
+    fn default() -> Self {
+        LevelConfig {
+            floor: 479,
+            height: HEIGHT,
+            ceiling: 0,
+            rise_gravity: 1,
+            fall_gravity: 1,
+            terminal_velocity: 20,
+        }
+    }
+}
+
+// やり直すときに戻る場所
+#[derive(Clone, Copy)]
+struct Checkpoint {
+    distance: i32,
+    // 越えたときの少年のx座標。跳んでいても、戻るときは地面に立たせる
+    x: i16,
+}
+
+// 終わりのない走りか、決まった距離（ピクセル）を走りきれば勝ちのステージか
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum GameMode {
+    Endless,
+    Level { length: i32 },
+}
+
+impl GameMode {
+    // ?length=500 のようにメートルで指定されたらステージにする
+    fn from_length(length: Option<String>) -> Self {
+        match length.and_then(|meters| meters.parse::<i32>().ok()) {
+            Some(meters) if meters > 0 => GameMode::Level {
+                length: meters * PIXELS_PER_METER,
+            },
+            _ => GameMode::Endless,
+        }
+    }
+}
+
+// 難易度ごとに変わる調整値
+#[derive(Clone, Copy)]
+pub struct DifficultySettings {
+    // セグメント同士の間隔
+    obstacle_buffer: i16,
+    // timelineがこれを下回ったら次のセグメントを生成する
+    timeline_minimum: i16,
+    running_speed: i16,
+    // 足場が乗ると崩れるか
+    crumbling_platforms: bool,
+    // 走り始めてから障害物が出てこない距離。慣れるまでの猶予
+    grace_distance: i16,
+}
+
+// チェックポイントで難しくなるのは、この回数まで
+const MAX_DIFFICULTY_STEPS: u32 = 10;
+
+impl DifficultySettings {
+    // チェックポイントを越えた数だけ、セグメントの間を詰めて先まで生成する
+    // MAX_DIFFICULTY_STEPSより先は難しくならない
+    fn harder_by(self, checkpoints: u32) -> Self {
+        let steps = checkpoints.min(MAX_DIFFICULTY_STEPS) as i16;
+        DifficultySettings {
+            obstacle_buffer: self
+                .obstacle_buffer
+                .saturating_sub(steps.saturating_mul(20))
+                .max(0),
+            timeline_minimum: self
+                .timeline_minimum
+                .saturating_add(steps.saturating_mul(100)),
+            ..self
+        }
+    }
+
+    // 調整用。?buffer=200&timeline=1500 のように、セグメントの間隔と生成のタイミングを上書きする
+    // 設定としては保存しない
+    fn with_query_params(self) -> Result<Self> {
+        let mut settings = self;
+        if let Some(buffer) = browser::query_param("buffer")?.and_then(|buffer| buffer.parse().ok())
+        {
+            settings.obstacle_buffer = buffer;
+        }
+        if let Some(timeline) =
+            browser::query_param("timeline")?.and_then(|timeline| timeline.parse().ok())
+        {
+            settings.timeline_minimum = timeline;
+        }
+        Ok(settings)
+    }
+}
+
+// 色分けに使う、障害物の大まかな種類
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ObstacleCategory {
+    // 当たるとやられるもの
+    Hazard,
+    // 乗れるもの
+    Platform,
+    // 取るもの
+    Pickup,
+    Goal,
+}
+
+// 色で見分けているものの役割。実際の色はパレットから引く
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ColorRole {
+    Boy,
+    Obstacle(ObstacleCategory),
+    // デバッグ表示で塗る、当たった当たり判定
+    Hit,
+    DamageFlash,
+    DashMeter,
+    ComboBreakFlash,
+    // 少年の周りに描く、ダッシュ中のシールド、パワーアップのシールドとそれが割れたとき、磁石の範囲
+    DashShield,
+    PowerShield,
+    ShieldBreak,
+    Magnet,
+    // デバッグ表示で描く速度の線
+    VelocityLine,
+}
+
+// 色覚の多様性に配慮したパレットでは、Okabe-Itoの配色を使う
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Palette {
+    Standard,
+    Colorblind,
+}
+
+impl Palette {
+    // URLパラメータ（?palette=colorblind など）からパレットを決める
+    fn from_name(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "standard" => Some(Palette::Standard),
+            "colorblind" => Some(Palette::Colorblind),
+            _ => None,
+        }
+    }
+
+    fn toggled(self) -> Self {
+        match self {
+            Palette::Standard => Palette::Colorblind,
+            Palette::Colorblind => Palette::Standard,
+        }
+    }
+
+    fn color(self, role: ColorRole) -> &'static str {
+        match (self, role) {
+            (Palette::Standard, ColorRole::Boy) => "#FFFF00",
+            (Palette::Standard, ColorRole::Obstacle(ObstacleCategory::Hazard)) => "#00FFFF",
+            (Palette::Standard, ColorRole::Obstacle(ObstacleCategory::Platform)) => "#FFFFFF",
+            (Palette::Standard, ColorRole::Obstacle(ObstacleCategory::Pickup)) => "#FF00FF",
+            (Palette::Standard, ColorRole::Obstacle(ObstacleCategory::Goal)) => "#00FF00",
+            (Palette::Standard, ColorRole::Hit) => "rgba(255, 0, 0, 0.5)",
+            (Palette::Standard, ColorRole::DamageFlash) => "#FF0000",
+            (Palette::Standard, ColorRole::DashMeter) => "#FFD700",
+            (Palette::Standard, ColorRole::ComboBreakFlash) => "rgba(255, 255, 255, 0.6)",
+            (Palette::Standard, ColorRole::DashShield) => "rgba(255, 255, 255, 0.4)",
+            (Palette::Standard, ColorRole::PowerShield) => "rgba(80, 160, 255, 0.4)",
+            (Palette::Standard, ColorRole::ShieldBreak) => "rgba(80, 160, 255, 0.8)",
+            (Palette::Standard, ColorRole::Magnet) => "rgba(255, 215, 0, 0.15)",
+            (Palette::Standard, ColorRole::VelocityLine) => "#00FF00",
+            (Palette::Colorblind, ColorRole::Boy) => "#E69F00",
+            (Palette::Colorblind, ColorRole::Obstacle(ObstacleCategory::Hazard)) => "#D55E00",
+            (Palette::Colorblind, ColorRole::Obstacle(ObstacleCategory::Platform)) => "#56B4E9",
+            (Palette::Colorblind, ColorRole::Obstacle(ObstacleCategory::Pickup)) => "#F0E442",
+            (Palette::Colorblind, ColorRole::Obstacle(ObstacleCategory::Goal)) => "#009E73",
+            (Palette::Colorblind, ColorRole::Hit) => "rgba(204, 121, 167, 0.5)",
+            (Palette::Colorblind, ColorRole::DamageFlash) => "#D55E00",
+            (Palette::Colorblind, ColorRole::DashMeter) => "#56B4E9",
+            (Palette::Colorblind, ColorRole::ComboBreakFlash) => "rgba(255, 255, 255, 0.6)",
+            (Palette::Colorblind, ColorRole::DashShield) => "rgba(255, 255, 255, 0.4)",
+            (Palette::Colorblind, ColorRole::PowerShield) => "rgba(0, 114, 178, 0.4)",
+            (Palette::Colorblind, ColorRole::ShieldBreak) => "rgba(0, 114, 178, 0.8)",
+            (Palette::Colorblind, ColorRole::Magnet) => "rgba(240, 228, 66, 0.15)",
+            (Palette::Colorblind, ColorRole::VelocityLine) => "#009E73",
+        }
+    }
+}
 
 // 課題:
 // game.rsとsegment.rsの間に循環依存がある
@@ -34,38 +365,203 @@ const OBSTACLE_BUFFER: i16 = 20;
 
 pub struct WalkTheDog {
     machine: Option<WalkTheDogStateMachine>,
+    level: LevelConfig,
+    // メニューで何も押されていないフレーム数と、放っておかれて始めたデモ
+    idle_frames: u32,
+    demo: Option<Demo>,
+    // このセッションで効いている設定と、localStorageに保存している設定
+    // URLパラメータはsettingsだけを上書きし、保存はしない
+    settings: Settings,
+    saved_settings: Settings,
 }
 
 impl WalkTheDog {
     pub fn new() -> Self {
-        WalkTheDog { machine: None }
+        WalkTheDog {
+            machine: None,
+            level: LevelConfig::default(),
+            idle_frames: 0,
+            demo: None,
+            settings: Settings::default(),
+            saved_settings: Settings::default(),
+        }
+    }
+
+    // デモ中は用意した入力で動かし、本物の入力があればメニューに戻る
+    fn update_demo(
+        &mut self,
+        machine: WalkTheDogStateMachine,
+        keystate: &KeyState,
+    ) -> WalkTheDogStateMachine {
+        let demo = match self.demo.as_mut() {
+            Some(demo) => demo,
+            None => return machine,
+        };
+        if keystate.has_input() || matches!(machine, WalkTheDogStateMachine::GameOver(_)) {
+            self.demo = None;
+            self.idle_frames = 0;
+            return machine.exit_demo();
+        }
+        machine.update(&demo.next_input())
+    }
+
+    fn update_idle(
+        &mut self,
+        mut machine: WalkTheDogStateMachine,
+        keystate: &KeyState,
+    ) -> WalkTheDogStateMachine {
+        self.change_settings(&mut machine, keystate);
+        if keystate.has_input() || !matches!(machine, WalkTheDogStateMachine::MainMenu(_)) {
+            self.idle_frames = 0;
+        } else {
+            self.idle_frames += 1;
+        }
+        if self.idle_frames >= DEMO_IDLE_FRAMES {
+            self.demo = Some(Demo::default());
+            machine.start_demo()
+        } else {
+            machine.update(keystate)
+        }
+    }
+
+    // メニューではキーで設定を切り替えられる。プレイヤーが切り替えたときだけ保存する
+    fn change_settings(&mut self, machine: &mut WalkTheDogStateMachine, keystate: &KeyState) {
+        if !matches!(machine, WalkTheDogStateMachine::MainMenu(_)) {
+            return;
+        }
+        let toggles: Vec<SettingToggle> = keystate
+            .presses()
+            .iter()
+            .filter_map(|code| SettingToggle::from_code(code))
+            .collect();
+        if toggles.is_empty() {
+            return;
+        }
+        for toggle in toggles {
+            toggle.apply(&mut self.settings, &mut self.saved_settings);
+        }
+        if let Err(err) = self.saved_settings.save() {
+            error!("Could not save settings {:#?}", err);
+        }
+        machine.walk_mut().apply_settings(self.settings);
+    }
+
+    // 読み込み中はまだ少年がいないのでNone
+    pub fn boy_state_name(&self) -> Option<&'static str> {
+        self.machine
+            .as_ref()
+            .map(|machine| machine.walk().boy.state_name())
+    }
+}
+
+// メニューでこれだけ放っておかれたら、デモを始める
+const DEMO_IDLE_FRAMES: u32 = 600;
+const DEMO_JUMP_INTERVAL: u32 = 45;
+
+// 一定の間隔でジャンプし続けるだけの、デモ用の入力
+#[derive(Default)]
+struct Demo {
+    frame: u32,
+}
+
+impl Demo {
+    fn next_input(&mut self) -> KeyState {
+        self.frame += 1;
+        let mut keystate = KeyState::new();
+        if self.frame >= DEMO_JUMP_INTERVAL {
+            self.frame = 0;
+            keystate.record_press("Space");
+        }
+        keystate
     }
 }
 
 enum WalkTheDogStateMachine {
+    MainMenu(WalkTheDogState<MainMenu>),
     Ready(WalkTheDogState<Ready>),
     Walking(WalkTheDogState<Walking>),
+    Paused(WalkTheDogState<Paused>),
     GameOver(WalkTheDogState<GameOver>),
 }
 
 impl WalkTheDogStateMachine {
     fn new(walk: Walk) -> Self {
-        WalkTheDogStateMachine::Ready(WalkTheDogState::new(walk))
+        WalkTheDogStateMachine::MainMenu(WalkTheDogState::new(walk))
     }
 
     fn update(self, keystate: &KeyState) -> Self {
         match self {
+            WalkTheDogStateMachine::MainMenu(state) => state.update(keystate).into(),
             WalkTheDogStateMachine::Ready(state) => state.update(keystate).into(),
             WalkTheDogStateMachine::Walking(state) => state.update(keystate).into(),
-            WalkTheDogStateMachine::GameOver(state) => state.update().into(),
+            WalkTheDogStateMachine::Paused(state) => state.update(keystate).into(),
+            WalkTheDogStateMachine::GameOver(state) => state.update(keystate).into(),
         }
     }
 
-    fn draw(&self, renderer: &Renderer) {
+    fn walk(&self) -> &Walk {
+        match self {
+            WalkTheDogStateMachine::MainMenu(state) => &state.walk,
+            WalkTheDogStateMachine::Ready(state) => &state.walk,
+            WalkTheDogStateMachine::Walking(state) => &state.walk,
+            WalkTheDogStateMachine::Paused(state) => &state.walk,
+            WalkTheDogStateMachine::GameOver(state) => &state.walk,
+        }
+    }
+
+    fn into_walk(self) -> Walk {
+        match self {
+            WalkTheDogStateMachine::MainMenu(state) => state.walk,
+            WalkTheDogStateMachine::Ready(state) => state.walk,
+            WalkTheDogStateMachine::Walking(state) => state.walk,
+            WalkTheDogStateMachine::Paused(state) => state.walk,
+            WalkTheDogStateMachine::GameOver(state) => state.walk,
+        }
+    }
+
+    // メニューからそのまま走り出す。デモ中の走りは記録に残さない
+    fn start_demo(self) -> Self {
+        match self {
+            WalkTheDogStateMachine::MainMenu(mut state) => {
+                state.walk.demo = true;
+                state.start_game().start_running().into()
+            }
+            machine => machine,
+        }
+    }
+
+    fn exit_demo(self) -> Self {
+        if matches!(self, WalkTheDogStateMachine::GameOver(_)) {
+            if let Err(err) = browser::hide_ui() {
+                error!("Could not hide ui {:#?}", err);
+            }
+        }
+        let mut walk = self.into_walk();
+        walk.demo = false;
+        // デモの走りを自己ベストのゴーストにしない
+        walk.ghost = GhostTrack::default();
+        walk.reset();
+        walk.resume_music();
+        WalkTheDogState::<MainMenu>::new(walk).into()
+    }
+
+    fn walk_mut(&mut self) -> &mut Walk {
         match self {
-            WalkTheDogStateMachine::Ready(state) => state.draw(renderer),
-            WalkTheDogStateMachine::Walking(state) => state.draw(renderer),
-            WalkTheDogStateMachine::GameOver(state) => state.draw(renderer),
+            WalkTheDogStateMachine::MainMenu(state) => &mut state.walk,
+            WalkTheDogStateMachine::Ready(state) => &mut state.walk,
+            WalkTheDogStateMachine::Walking(state) => &mut state.walk,
+            WalkTheDogStateMachine::Paused(state) => &mut state.walk,
+            WalkTheDogStateMachine::GameOver(state) => &mut state.walk,
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer, alpha: f32) {
+        match self {
+            WalkTheDogStateMachine::MainMenu(state) => state.draw_menu(renderer),
+            WalkTheDogStateMachine::Ready(state) => state.draw(renderer, alpha),
+            WalkTheDogStateMachine::Walking(state) => state.draw(renderer, alpha),
+            WalkTheDogStateMachine::Paused(state) => state.draw_paused(renderer),
+            WalkTheDogStateMachine::GameOver(state) => state.draw_game_over(renderer, alpha),
         }
     }
 }
@@ -76,24 +572,106 @@ struct WalkTheDogState<T> {
 }
 
 impl<T> WalkTheDogState<T> {
-    fn draw(&self, renderer: &Renderer) {
-        self.walk.draw(renderer);
+    fn draw(&self, renderer: &Renderer, alpha: f32) {
+        self.walk.draw(renderer, alpha);
     }
 }
 
-struct Ready;
+// タイトル画面。Enterを押すとReady（プレイ開始前の待機）に進む
+struct MainMenu {
+    // 押しっぱなしのEnterでメニューを素通りしないよう、押された瞬間だけを拾う
+    enter_pressed: bool,
+}
 
-impl WalkTheDogState<Ready> {
-    fn new(walk: Walk) -> WalkTheDogState<Ready> {
+impl WalkTheDogState<MainMenu> {
+    fn new(walk: Walk) -> WalkTheDogState<MainMenu> {
+        walk.leaderboard.refresh();
         WalkTheDogState {
-            _state: Ready,
+            _state: MainMenu {
+                enter_pressed: true,
+            },
             walk,
         }
     }
 
+    fn update(mut self, keystate: &KeyState) -> MainMenuEndState {
+        self.walk.boy.update();
+
+        let enter_pressed = keystate.is_pressed("Enter");
+        if enter_pressed && !self._state.enter_pressed {
+            MainMenuEndState::Complete(self.start_game())
+        } else {
+            self._state.enter_pressed = enter_pressed;
+            MainMenuEndState::Continue(self)
+        }
+    }
+
+    fn start_game(self) -> WalkTheDogState<Ready> {
+        WalkTheDogState {
+            _state: Ready,
+            walk: self.walk,
+        }
+    }
+
+    // メニューでは障害物は出さず、背景と中央に立つ少年だけを描く
+    fn draw_menu(&self, renderer: &Renderer) {
+        self.walk.draw_backgrounds(renderer);
+
+        let boy = &self.walk.boy;
+        boy.draw_at(
+            renderer,
+            Point {
+                x: (WIDTH - boy.destination_box().width) / 2,
+                y: boy.pos_y(),
+            },
+        );
+
+        if let Err(err) = renderer
+            .draw_text("Walk the Dog", &Point { x: 220, y: 150 })
+            .and_then(|_| renderer.draw_text("Press Enter to start", &Point { x: 180, y: 250 }))
+        {
+            error!("Could not draw text {:#?}", err);
+        }
+        self.walk.leaderboard.draw(renderer);
+
+        for (index, line) in self.walk.settings.menu_lines().iter().enumerate() {
+            let location = Point {
+                x: 20,
+                y: 530 + index as i16 * 25,
+            };
+            if let Err(err) = renderer.draw_text(line, &location) {
+                error!("Could not draw text {:#?}", err);
+            }
+        }
+    }
+}
+
+impl From<WalkTheDogState<MainMenu>> for WalkTheDogStateMachine {
+    fn from(state: WalkTheDogState<MainMenu>) -> Self {
+        WalkTheDogStateMachine::MainMenu(state)
+    }
+}
+
+enum MainMenuEndState {
+    Complete(WalkTheDogState<Ready>),
+    Continue(WalkTheDogState<MainMenu>),
+}
+
+impl From<MainMenuEndState> for WalkTheDogStateMachine {
+    fn from(state: MainMenuEndState) -> Self {
+        match state {
+            MainMenuEndState::Complete(ready) => ready.into(),
+            MainMenuEndState::Continue(main_menu) => main_menu.into(),
+        }
+    }
+}
+
+struct Ready;
+
+impl WalkTheDogState<Ready> {
     fn update(mut self, keystate: &KeyState) -> ReadyEndState {
         self.walk.boy.update();
-        if keystate.is_pressed("ArrowRight") {
+        if Action::queued(keystate).contains(&Action::Run) {
             ReadyEndState::Complete(self.start_running())
         } else {
             ReadyEndState::Continue(self)
@@ -109,7 +687,7 @@ impl WalkTheDogState<Ready> {
     }
 
     fn run_right(&mut self) {
-        self.walk.boy.run_right();
+        self.walk.boy.run_right(self.walk.difficulty.running_speed);
     }
 }
 
@@ -134,51 +712,125 @@ impl From<ReadyEndState> for WalkTheDogStateMachine {
     }
 }
 
+// 同時に入力されたときは、宣言順（ジャンプ > スライディング > ダッシュ > 走る）に処理する
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+enum Action {
+    Jump,
+    Slide,
+    Dash,
+    Run,
+}
+
+impl Action {
+    fn from_code(code: &str) -> Option<Action> {
+        match code {
+            "Space" => Some(Action::Jump),
+            "ArrowDown" => Some(Action::Slide),
+            "ShiftLeft" => Some(Action::Dash),
+            "ArrowRight" => Some(Action::Run),
+            _ => None,
+        }
+    }
+
+    // 押しっぱなしのキーと、前回のupdate以降に押されたキーを、優先順に重複なく並べる
+    fn queued(keystate: &KeyState) -> Vec<Action> {
+        let held = ["Space", "ArrowDown", "ShiftLeft", "ArrowRight"]
+            .iter()
+            .filter(|code| keystate.is_pressed(code))
+            .filter_map(|code| Action::from_code(code));
+        let pressed = keystate
+            .presses()
+            .iter()
+            .filter_map(|code| Action::from_code(code));
+
+        let mut actions: Vec<Action> = held.chain(pressed).collect();
+        actions.sort();
+        actions.dedup();
+        actions
+    }
+}
+
 struct Walking;
 
 impl WalkTheDogState<Walking> {
     fn update(mut self, keystate: &KeyState) -> WalkingEndState {
-        if keystate.is_pressed("ArrowDown") {
-            self.walk.boy.slide()
+        // 押しっぱなしでは何度もやり直さないよう、押された瞬間だけを見る
+        if keystate.presses().iter().any(|code| code == "KeyR") {
+            return WalkingEndState::Restart(self.restart());
         }
-        if keystate.is_pressed("Space") {
-            self.walk.boy.jump();
+        if keystate.presses().iter().any(|code| code == "Escape") {
+            return WalkingEndState::Pause(self.pause());
         }
-        self.walk.boy.update();
-
-        let walking_speed = self.walk.velocity();
-        let [bg_fst, bg_snd] = &mut self.walk.backgrounds;
-        bg_fst.move_horizontally(walking_speed);
-        bg_snd.move_horizontally(walking_speed);
-
-        if bg_fst.right() < 0 {
-            bg_fst.set_x(bg_snd.right());
+        if keystate.presses().iter().any(|code| code == "KeyL") {
+            self.walk.dump_input_log();
         }
-        if bg_snd.right() < 0 {
-            bg_snd.set_x(bg_fst.right());
+        self.walk.run_debug_commands();
+        let state_before = self.walk.boy.state_name();
+        let actions = Action::queued(keystate);
+        for action in actions.iter() {
+            match action {
+                Action::Jump => self.walk.boy.jump(),
+                // 走っていればスライディング、空中なら早く落ちる
+                Action::Slide => {
+                    self.walk.boy.slide();
+                    self.walk.boy.fast_fall();
+                }
+                Action::Dash => self.walk.boy.dash(),
+                Action::Run => {}
+            }
+        }
+        if !actions.contains(&Action::Jump) {
+            self.walk.boy.release_jump();
+        }
+        let was_knocked_down = self.walk.knocked_down();
+        self.walk.boy.update();
+        self.walk.record_transition(state_before);
+        if let Some(input_log) = self.walk.input_log.as_mut() {
+            input_log.record(actions, self.walk.boy.state_name());
         }
 
-        self.walk.obstacles.retain(|obstacle| obstacle.right() > 0);
-
-        self.walk.obstacles.iter_mut().for_each(|obstacle| {
-            obstacle.move_horizontally(walking_speed);
-            obstacle.check_intersection(&mut self.walk.boy);
-        });
-
-        if self.walk.timeline < TIMELINE_MINIMUM {
-            self.walk.generate_next_segment();
-        } else {
-            self.walk.timeline += walking_speed;
+        self.walk.update_combo_flash();
+        // 倒れている間は世界を止め、やられるアニメーションだけを進める
+        if !self.walk.knocked_down() {
+            self.walk.update_world();
         }
+        self.walk.update_shake(was_knocked_down);
 
-        if self.walk.knocked_out() {
+        if self.walk.knocked_out() && self.walk.can_respawn() {
+            self.walk.respawn();
+            WalkingEndState::Continue(self)
+        } else if self.walk.knocked_out() || self.walk.won {
             WalkingEndState::Complete(self.end_game())
         } else {
             WalkingEndState::Continue(self)
         }
     }
 
-    fn end_game(self) -> WalkTheDogState<GameOver> {
+    // 倒れるのを待たずに、走り出す前からやり直す
+    fn restart(mut self) -> WalkTheDogState<Ready> {
+        self.walk.finish_run(false);
+        self.walk.reset();
+        WalkTheDogState {
+            _state: Ready,
+            walk: self.walk,
+        }
+    }
+
+    fn pause(mut self) -> WalkTheDogState<Paused> {
+        self.walk.pause_music();
+        WalkTheDogState {
+            _state: Paused {
+                menu: PauseMenu::default(),
+            },
+            walk: self.walk,
+        }
+    }
+
+    fn end_game(mut self) -> WalkTheDogState<GameOver> {
+        let died = !self.walk.won;
+        self.walk.finish_run(died);
+        self.walk.submit_score();
+        self.walk.pause_music();
         let receiver = browser::draw_ui("<button id='new_game'>New Game</button>")
             .and_then(|_unit| browser::find_html_element_by_id("new_game"))
             .map(|element| engine::add_click_handler(element))
@@ -202,6 +854,8 @@ impl From<WalkTheDogState<Walking>> for WalkTheDogStateMachine {
 enum WalkingEndState {
     Complete(WalkTheDogState<GameOver>),
     Continue(WalkTheDogState<Walking>),
+    Restart(WalkTheDogState<Ready>),
+    Pause(WalkTheDogState<Paused>),
 }
 
 impl From<WalkingEndState> for WalkTheDogStateMachine {
@@ -209,72 +863,292 @@ impl From<WalkingEndState> for WalkTheDogStateMachine {
         match state {
             WalkingEndState::Complete(game_over) => game_over.into(),
             WalkingEndState::Continue(walking) => walking.into(),
+            WalkingEndState::Restart(ready) => ready.into(),
+            WalkingEndState::Pause(paused) => paused.into(),
         }
     }
 }
 
-struct GameOver {
-    new_game_event: UnboundedReceiver<()>,
+// ポーズメニューの項目。上から順に並ぶ
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PauseOption {
+    Resume,
+    Restart,
+    MainMenu,
 }
 
-impl GameOver {
-    fn new_game_pressed(&mut self) -> bool {
-        matches!(self.new_game_event.try_next(), Ok(Some(())))
+impl PauseOption {
+    const ALL: [PauseOption; 3] = [
+        PauseOption::Resume,
+        PauseOption::Restart,
+        PauseOption::MainMenu,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            PauseOption::Resume => "Resume",
+            PauseOption::Restart => "Restart",
+            PauseOption::MainMenu => "Main Menu",
+        }
     }
 }
 
-impl WalkTheDogState<GameOver> {
-    fn update(mut self) -> GameOverEndState {
-        if self._state.new_game_pressed() {
-            GameOverEndState::Complete(self.new_game())
-        } else {
-            GameOverEndState::Continue(self)
+// 上下キーで選び、端まで行ったら反対の端に戻る
+#[derive(Default)]
+struct PauseMenu {
+    selected: usize,
+}
+
+impl PauseMenu {
+    fn select_next(&mut self) {
+        self.selected = (self.selected + 1) % PauseOption::ALL.len();
+    }
+
+    fn select_previous(&mut self) {
+        self.selected = (self.selected + PauseOption::ALL.len() - 1) % PauseOption::ALL.len();
+    }
+
+    fn selected(&self) -> PauseOption {
+        PauseOption::ALL[self.selected]
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        renderer.fill_rect(
+            &Rect::new_from_x_y(0, 0, WIDTH, HEIGHT),
+            PAUSE_MENU_DIM_COLOR,
+        );
+        let lines = std::iter::once("Paused".to_string()).chain(
+            PauseOption::ALL.iter().enumerate().map(|(index, option)| {
+                let marker = if index == self.selected { ">" } else { " " };
+                format!("{} {}", marker, option.label())
+            }),
+        );
+        for (index, line) in lines.enumerate() {
+            let location = Point {
+                x: 220,
+                y: 200 + index as i16 * 50,
+            };
+            if let Err(err) = renderer.draw_text(&line, &location) {
+                error!("Could not draw text {:#?}", err);
+            }
         }
     }
+}
 
-    fn new_game(self) -> WalkTheDogState<Ready> {
-        browser::hide_ui().unwrap();
+// 走っている途中でEscapeを押すと止まり、メニューから続きをどうするか選ぶ
+struct Paused {
+    menu: PauseMenu,
+}
+
+impl WalkTheDogState<Paused> {
+    fn update(mut self, keystate: &KeyState) -> PausedEndState {
+        let pressed = |code: &str| keystate.presses().iter().any(|press| press == code);
+        if pressed("Escape") {
+            return PausedEndState::Resume(self.resume());
+        }
+        if pressed("ArrowDown") {
+            self._state.menu.select_next();
+        }
+        if pressed("ArrowUp") {
+            self._state.menu.select_previous();
+        }
+        if !pressed("Enter") {
+            return PausedEndState::Continue(self);
+        }
+
+        match self._state.menu.selected() {
+            PauseOption::Resume => PausedEndState::Resume(self.resume()),
+            PauseOption::Restart => PausedEndState::Restart(self.restart()),
+            PauseOption::MainMenu => PausedEndState::MainMenu(self.main_menu()),
+        }
+    }
+
+    fn resume(mut self) -> WalkTheDogState<Walking> {
+        self.walk.resume_music();
+        WalkTheDogState {
+            _state: Walking,
+            walk: self.walk,
+        }
+    }
+
+    // Rキーでのやり直しと同じく、今の走りは倒れなかったものとして記録する
+    fn restart(mut self) -> WalkTheDogState<Ready> {
+        self.walk.finish_run(false);
+        self.walk.reset();
+        self.walk.resume_music();
         WalkTheDogState {
             _state: Ready,
-            walk: Walk::reset(self.walk),
+            walk: self.walk,
         }
     }
+
+    fn main_menu(mut self) -> WalkTheDogState<MainMenu> {
+        self.walk.finish_run(false);
+        self.walk.reset();
+        self.walk.resume_music();
+        WalkTheDogState::<MainMenu>::new(self.walk)
+    }
+
+    // 止まった画面の上に、暗くしてメニューを重ねる
+    fn draw_paused(&self, renderer: &Renderer) {
+        self.walk.draw(renderer, 1.0);
+        self._state.menu.draw(renderer);
+    }
 }
 
-impl From<WalkTheDogState<GameOver>> for WalkTheDogStateMachine {
-    fn from(state: WalkTheDogState<GameOver>) -> Self {
-        WalkTheDogStateMachine::GameOver(state)
+impl From<WalkTheDogState<Paused>> for WalkTheDogStateMachine {
+    fn from(state: WalkTheDogState<Paused>) -> Self {
+        WalkTheDogStateMachine::Paused(state)
     }
 }
 
-enum GameOverEndState {
-    Complete(WalkTheDogState<Ready>),
-    Continue(WalkTheDogState<GameOver>),
+enum PausedEndState {
+    Continue(WalkTheDogState<Paused>),
+    Resume(WalkTheDogState<Walking>),
+    Restart(WalkTheDogState<Ready>),
+    MainMenu(WalkTheDogState<MainMenu>),
 }
 
-impl From<GameOverEndState> for WalkTheDogStateMachine {
-    fn from(state: GameOverEndState) -> Self {
+impl From<PausedEndState> for WalkTheDogStateMachine {
+    fn from(state: PausedEndState) -> Self {
         match state {
-            GameOverEndState::Complete(ready) => ready.into(),
-            GameOverEndState::Continue(game_over) => game_over.into(),
+            PausedEndState::Continue(paused) => paused.into(),
+            PausedEndState::Resume(walking) => walking.into(),
+            PausedEndState::Restart(ready) => ready.into(),
+            PausedEndState::MainMenu(main_menu) => main_menu.into(),
+        }
+    }
+}
+
+struct GameOver {
+    new_game_event: UnboundedReceiver<()>,
+}
+
+impl GameOver {
+    fn new_game_pressed(&mut self) -> bool {
+        matches!(self.new_game_event.try_next(), Ok(Some(())))
+    }
+}
+
+impl WalkTheDogState<GameOver> {
+    fn update(mut self, keystate: &KeyState) -> GameOverEndState {
+        if self._state.new_game_pressed() || keystate.is_pressed("Enter") {
+            GameOverEndState::Complete(self.new_game())
+        } else {
+            GameOverEndState::Continue(self)
+        }
+    }
+
+    fn draw_game_over(&self, renderer: &Renderer, alpha: f32) {
+        self.walk.draw(renderer, alpha);
+
+        if self.walk.won {
+            if let Err(err) = renderer.draw_text("You win!", &Point { x: 250, y: 260 }) {
+                error!("Could not draw text {:#?}", err);
+            }
+        }
+
+        let lines = [
+            ("This session", &self.walk.stats),
+            ("All time", &self.walk.lifetime_stats),
+        ];
+        for (index, (title, stats)) in lines.iter().enumerate() {
+            let y = 320 + index as i16 * 60;
+            if let Err(err) = renderer
+                .draw_text(title, &Point { x: 120, y })
+                .and_then(|_| renderer.draw_text(&stats.summary(), &Point { x: 120, y: y + 25 }))
+            {
+                error!("Could not draw stats {:#?}", err);
+            }
+        }
+    }
+
+    fn new_game(self) -> WalkTheDogState<MainMenu> {
+        browser::hide_ui().unwrap();
+        let mut walk = self.walk;
+        walk.reset();
+        walk.resume_music();
+        WalkTheDogState::<MainMenu>::new(walk)
+    }
+}
+
+impl From<WalkTheDogState<GameOver>> for WalkTheDogStateMachine {
+    fn from(state: WalkTheDogState<GameOver>) -> Self {
+        WalkTheDogStateMachine::GameOver(state)
+    }
+}
+
+enum GameOverEndState {
+    Complete(WalkTheDogState<MainMenu>),
+    Continue(WalkTheDogState<GameOver>),
+}
+
+impl From<GameOverEndState> for WalkTheDogStateMachine {
+    fn from(state: GameOverEndState) -> Self {
+        match state {
+            GameOverEndState::Complete(main_menu) => main_menu.into(),
+            GameOverEndState::Continue(game_over) => game_over.into(),
         }
     }
 }
 
 pub trait Obstacle {
-    fn check_intersection(&self, boy: &mut RedHatBoy);
+    // 少年に当たって何かが起きたら、当たった当たり判定を返す
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect>;
     fn draw(&self, rendere: &Renderer);
     fn move_horizontally(&mut self, x: i16);
+    fn left(&self) -> i16;
     fn right(&self) -> i16;
+    // ハイコントラストモードで縁取りする矩形
+    fn outlines(&self) -> Vec<Rect>;
+    fn category(&self) -> ObstacleCategory {
+        ObstacleCategory::Hazard
+    }
+    #[allow(dead_code)]
+    fn width(&self) -> i16 {
+        self.right() - self.left()
+    }
+    // 作られてから消えるまで変わらない、障害物ごとに一意な番号
+    fn id(&self) -> u32;
+    // 抜けたときの点数（コンボ倍率をかける前）
+    fn score_value(&self) -> i32 {
+        OBSTACLE_SCORE
+    }
+    // 自分で動く障害物は、スクロールとは別にここで1フレーム分動く
+    fn update(&mut self) {}
+    // 倒されて、取り除くべきになったか
+    fn defeated(&self) -> bool {
+        false
+    }
+    // 難しいモードで、乗ると崩れるようにする。足場以外は何もしない
+    fn make_crumbling(&mut self) {}
+    // 取られて、取り除くべきになったか。点はscore_valueの分だけ入る
+    fn collected(&self) -> bool {
+        false
+    }
+    // 磁石が効いている間、毎フレーム少年の中心を渡される
+    fn attract(&mut self, _target: &Point) {}
 }
 
 pub struct Platform {
+    id: u32,
     sheet: Rc<SpriteSheet>,
     bounding_boxes: Vec<Rect>,
     sprites: Vec<Cell>,
     position: Point,
+    // 上に乗った少年を前（正）か後ろ（負）に運ぶ速さ
+    conveyor_speed: i16,
+    // 崩れる足場か、乗られてから崩れるまでの残りフレーム、崩れてから落ちた距離
+    crumbles: bool,
+    crumble_frames: Option<u8>,
+    fall_distance: i16,
 }
 
+// 崩れる足場は、乗ってからこのフレーム数で当たり判定がなくなる
+const CRUMBLE_FRAMES: u8 = 30;
+const CRUMBLE_SHAKE: i16 = 2;
+const CRUMBLE_FALL_SPEED: i16 = 6;
+
 impl Platform {
     pub fn new(
         sheet: Rc<SpriteSheet>,
@@ -302,41 +1176,83 @@ impl Platform {
             .collect();
 
         Platform {
+            id: next_obstacle_id(),
             sheet,
             position,
             sprites,
             bounding_boxes,
+            conveyor_speed: 0,
+            crumbles: false,
+            crumble_frames: None,
+            fall_distance: 0,
+        }
+    }
+
+    fn crumbled(&self) -> bool {
+        self.crumble_frames == Some(0)
+    }
+
+    // 崩れかけは左右に揺れ、崩れた後は下に落ちていく
+    fn draw_offset(&self) -> Point {
+        match self.crumble_frames {
+            Some(0) => Point {
+                x: 0,
+                y: self.fall_distance,
+            },
+            Some(frames) if frames & 1 == 0 => Point {
+                x: CRUMBLE_SHAKE,
+                y: 0,
+            },
+            Some(_) => Point {
+                x: -CRUMBLE_SHAKE,
+                y: 0,
+            },
+            None => Point::default(),
         }
     }
 
+    pub fn with_conveyor(mut self, speed: i16) -> Self {
+        self.conveyor_speed = speed;
+        self
+    }
+
     fn bounding_boxes(&self) -> &Vec<Rect> {
         &self.bounding_boxes
     }
+
+    // 各スプライトのタイルシート上の位置と、描画先の組
+    fn sprite_draws(&self) -> Vec<(Rect, Rect)> {
+        // この実装では、obstacleは複数行にわたらず、ぴったり一行に連なることを想定している（オフセットの取り方的に）
+        let mut x = 0;
+        let offset = self.draw_offset();
+        self.sprites
+            .iter()
+            .map(|sprite| {
+                let draw = (
+                    Rect::new_from_x_y(
+                        sprite.frame.x,
+                        sprite.frame.y,
+                        sprite.frame.w,
+                        sprite.frame.h,
+                    ),
+                    // Just use position and the standard width in the tilesheet
+                    Rect::new_from_x_y(
+                        self.position.x + x + offset.x, // 総体としての position に、オフセット x を加える
+                        self.position.y + offset.y,
+                        sprite.frame.w,
+                        sprite.frame.h,
+                    ),
+                );
+                x += sprite.frame.w;
+                draw
+            })
+            .collect()
+    }
 }
 
 impl Obstacle for Platform {
     fn draw(&self, renderer: &Renderer) {
-        // この実装では、obstacleは複数行にわたらず、ぴったり一行に連なることを想定している（オフセットの取り方的に）
-        let mut x = 0;
-        self.sprites.iter().for_each(|sprite| {
-            self.sheet.draw(
-                renderer,
-                &Rect::new_from_x_y(
-                    sprite.frame.x,
-                    sprite.frame.y,
-                    sprite.frame.w,
-                    sprite.frame.h,
-                ),
-                // Just use position and the standard width in the tilesheet
-                &Rect::new_from_x_y(
-                    self.position.x + x, // 総体としての position に、オフセット x を加える
-                    self.position.y,
-                    sprite.frame.w,
-                    sprite.frame.h,
-                ),
-            );
-            x += sprite.frame.w;
-        });
+        self.sheet.draw_batch(renderer, &self.sprite_draws());
 
         // debug用にbounding boxを描画
         for bbox in self.bounding_boxes() {
@@ -344,6 +1260,14 @@ impl Obstacle for Platform {
         }
     }
 
+    fn outlines(&self) -> Vec<Rect> {
+        self.bounding_boxes().clone()
+    }
+
+    fn category(&self) -> ObstacleCategory {
+        ObstacleCategory::Platform
+    }
+
     fn move_horizontally(&mut self, x: i16) {
         self.position.x += x;
         self.bounding_boxes.iter_mut().for_each(|bounding_box| {
@@ -351,26 +1275,62 @@ impl Obstacle for Platform {
         })
     }
 
-    fn check_intersection(&self, boy: &mut RedHatBoy) {
-        if let Some(box_to_land_on) = self
-            .bounding_boxes()
-            .iter()
-            .find(|&bounding_box| boy.bounding_box().intersects(bounding_box))
-        {
-            // y座標が大きいほど低いことに注意
-            if boy.velocity_y() > 0 && boy.pos_y() < self.position.y {
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect> {
+        if self.crumbled() {
+            return None;
+        }
+        let boy_box = boy.bounding_box();
+        let (box_to_land_on, side) = self.bounding_boxes().iter().find_map(|bounding_box| {
+            boy_box
+                .collision_side(bounding_box)
+                .map(|side| (*bounding_box, side))
+        })?;
+        match side {
+            // 上昇中に上面をかすめただけなら、そのまま通り抜ける
+            Side::Top if boy.velocity_y() > 0 => {
                 boy.land_on(box_to_land_on.y());
-            } else {
-                boy.knock_out();
+                boy.ride_conveyor(self.conveyor_speed);
+                if self.crumbles && self.crumble_frames.is_none() {
+                    self.crumble_frames = Some(CRUMBLE_FRAMES);
+                }
             }
+            Side::Top => return None,
+            Side::Bottom | Side::Left | Side::Right => boy.knock_out(),
         }
+        Some(box_to_land_on)
+    }
+
+    // 当たり判定のない足場は、位置だけで幅のないものとして扱う
+    fn left(&self) -> i16 {
+        self.bounding_boxes()
+            .first()
+            .map_or(self.position.x, |bounding_box| bounding_box.x())
     }
 
     fn right(&self) -> i16 {
         self.bounding_boxes()
             .last()
-            .unwrap_or(&Rect::default())
-            .right()
+            .map_or(self.position.x, |bounding_box| bounding_box.right())
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn score_value(&self) -> i32 {
+        PLATFORM_SCORE
+    }
+
+    fn update(&mut self) {
+        match self.crumble_frames {
+            Some(0) => self.fall_distance += CRUMBLE_FALL_SPEED,
+            Some(frames) => self.crumble_frames = Some(frames - 1),
+            None => {}
+        }
+    }
+
+    fn make_crumbling(&mut self) {
+        self.crumbles = true;
     }
 }
 
@@ -378,17 +1338,41 @@ pub struct RedHatBoy {
     state_machine: RedHatBoyStateMachine,
     sprite_sheet: Sheet,
     image: HtmlImageElement,
+    // 乗っているベルトコンベアの速さ。足場に着地したフレームだけ0以外になる
+    conveyor_speed: i16,
+    // 磁石の残りフレーム
+    magnet_frames: u16,
+    // 直近のフレームで描いた位置とスプライト名。古い順に並ぶ
+    trail: VecDeque<(Point, String)>,
+    // 一つ前の固定更新での位置。更新の間を補間して描くのに使う
+    prev_position: Point,
 }
 
 impl RedHatBoy {
-    fn new(sheet: Sheet, image: HtmlImageElement, audio: Audio, jump_sound: Sound) -> Self {
+    fn new(
+        sheet: Sheet,
+        image: HtmlImageElement,
+        audio: Audio,
+        jump_sound: Sound,
+        level: LevelConfig,
+    ) -> Self {
         RedHatBoy {
-            state_machine: RedHatBoyStateMachine::Idle(RedHatBoyState::new(audio, jump_sound)),
+            state_machine: RedHatBoyStateMachine::Idle(RedHatBoyState::new(
+                audio, jump_sound, level,
+            )),
             sprite_sheet: sheet,
             image,
+            conveyor_speed: 0,
+            magnet_frames: 0,
+            trail: VecDeque::with_capacity(TRAIL_LENGTH),
+            prev_position: Point::default(),
         }
     }
 
+    pub fn state_name(&self) -> &'static str {
+        self.state_machine.state_name()
+    }
+
     fn frame_name(&self) -> String {
         format!(
             "{} ({}).png",
@@ -398,14 +1382,27 @@ impl RedHatBoy {
     }
 
     fn walking_speed(&self) -> i16 {
-        self.state_machine.context().velocity.x
+        self.velocity().x
+    }
+
+    fn ride_conveyor(&mut self, speed: i16) {
+        self.conveyor_speed = speed;
+    }
+
+    fn conveyor_speed(&self) -> i16 {
+        self.conveyor_speed
     }
 
     fn current_sprite(&self) -> Option<&Cell> {
-        self.sprite_sheet.frames.get(&self.frame_name())
+        self.sprite(&self.frame_name())
     }
 
-    fn destination_box(&self) -> Rect {
+    fn sprite(&self, frame_name: &str) -> Option<&Cell> {
+        self.sprite_sheet.frames.get(frame_name)
+    }
+
+    // 補正前の、スプライトシートどおりの位置
+    fn sprite_box(&self) -> Rect {
         let sprite = self.current_sprite().expect("Cell not found");
 
         Rect::new_from_x_y(
@@ -416,27 +1413,128 @@ impl RedHatBoy {
         )
     }
 
+    // 実際に描く位置。状態ごとの補正を含む
+    fn destination_box(&self) -> Rect {
+        let offset = self.animation_offset();
+        let sprite_box = self.sprite_box();
+        Rect::new_from_x_y(
+            sprite_box.x() + offset.x,
+            sprite_box.y() + offset.y,
+            sprite_box.width,
+            sprite_box.height,
+        )
+    }
+
+    fn animation_offset(&self) -> Point {
+        animation_offset(self.state_name())
+    }
+
     fn bounding_box(&self) -> Rect {
         const X_OFFSET: i16 = 18;
         const Y_OFFSET: i16 = 14;
         const WIDTH_OFFSET: i16 = 28;
+        // スライディング中は頭の位置が下がるので、低い障害物をくぐれるように上辺を下げる
+        const SLIDING_Y_OFFSET: i16 = 25;
 
+        let y_offset = if self.sliding() {
+            Y_OFFSET + SLIDING_Y_OFFSET
+        } else {
+            Y_OFFSET
+        };
+
+        let sprite_box = self.sprite_box();
         Rect::new_from_x_y(
-            self.destination_box().x() + X_OFFSET,
-            self.destination_box().y() + Y_OFFSET,
-            self.destination_box().width - WIDTH_OFFSET,
-            self.destination_box().height - Y_OFFSET,
+            sprite_box.x() + X_OFFSET,
+            sprite_box.y() + y_offset,
+            sprite_box.width - WIDTH_OFFSET,
+            sprite_box.height - y_offset,
         )
     }
 
+    fn sliding(&self) -> bool {
+        matches!(self.state_machine, RedHatBoyStateMachine::Sliding(_))
+    }
+
     fn knocked_out(&self) -> bool {
         self.state_machine.knocked_out()
     }
 
-    fn draw(&self, renderer: &Renderer) {
+    fn knocked_down(&self) -> bool {
+        self.state_machine.knocked_down()
+    }
+
+    fn draw(&self, renderer: &Renderer, alpha: f32, palette: Palette) {
+        let context = self.state_machine.context();
+        let center = self.center();
+        if self.is_magnetized() {
+            renderer.draw_circle(&center, MAGNET_RADIUS, palette.color(ColorRole::Magnet));
+        }
+        // ダッシュ中の無敵を示すシールド
+        if context.is_dashing() {
+            renderer.draw_circle(&center, SHIELD_RADIUS, palette.color(ColorRole::DashShield));
+        }
+        if self.is_shielded() {
+            renderer.draw_circle(
+                &center,
+                SHIELD_RADIUS,
+                palette.color(ColorRole::PowerShield),
+            );
+        }
+        // 割れたシールドは、広がりながら消えていく
+        if context.shield_grace_frames > 0 {
+            let remaining = context.shield_grace_frames as f64 / SHIELD_GRACE_FRAMES as f64;
+            renderer.with_alpha(remaining, |renderer| {
+                renderer.draw_circle(
+                    &center,
+                    SHIELD_RADIUS * (2.0 - remaining),
+                    palette.color(ColorRole::ShieldBreak),
+                )
+            });
+        }
+
+        if self.shows_trail() {
+            self.draw_trail(renderer);
+        }
+        self.draw_at(renderer, self.interpolated_position(alpha));
+
+        // debug用
+        renderer.draw_rect(&self.bounding_box());
+        let velocity = self.velocity();
+        renderer.draw_line(
+            &center,
+            &Point {
+                x: center.x + velocity.x * VELOCITY_LINE_SCALE,
+                y: center.y + velocity.y * VELOCITY_LINE_SCALE,
+            },
+            palette.color(ColorRole::VelocityLine),
+        );
+    }
+
+    fn interpolated_position(&self, alpha: f32) -> Point {
+        self.prev_position.lerp(&self.position(), alpha)
+    }
+
+    // 状態が持つ位置ではなく、指定した位置に現在のフレームを描く（メニュー画面用）
+    fn draw_at(&self, renderer: &Renderer, position: Point) {
         let sprite = self.current_sprite().expect("Cell not found");
+        let offset = self.animation_offset();
+        self.draw_sprite(
+            renderer,
+            sprite,
+            Point {
+                x: position.x + offset.x,
+                y: position.y + offset.y,
+            },
+        );
+    }
+
+    // 左へ進んでいるときは左を向く
+    fn facing_left(&self) -> bool {
+        self.walking_speed() < 0
+    }
 
-        renderer.draw_image(
+    fn draw_sprite(&self, renderer: &Renderer, sprite: &Cell, position: Point) {
+        renderer.draw_image_flipped(
             &self.image,
             &Rect::new_from_x_y(
                 sprite.frame.x.into(),
@@ -445,34 +1543,92 @@ impl RedHatBoy {
                 sprite.frame.h.into(),
             ),
             &Rect::new_from_x_y(
-                (self.state_machine.context().position.x + sprite.sprite_source_size.x as i16)
-                    .into(),
-                (self.state_machine.context().position.y + sprite.sprite_source_size.y as i16)
-                    .into(),
+                (position.x + sprite.sprite_source_size.x as i16).into(),
+                (position.y + sprite.sprite_source_size.y as i16).into(),
                 sprite.frame.w.into(),
                 sprite.frame.h.into(),
             ),
+            self.facing_left(),
         );
+    }
 
-        // debug用
-        renderer.draw_rect(&self.bounding_box())
+    fn reset(&mut self) {
+        let context = self.state_machine.context();
+        // 設定から決まるものは、やり直しても引き継ぐ
+        let (auto_hop, invincible) = (context.auto_hop, context.invincible);
+        self.state_machine = RedHatBoyStateMachine::Idle(RedHatBoyState::new(
+            context.audio.clone(),
+            context.jump_sound.clone(),
+            context.level,
+        ))
+        .map_context(|context| context.set_auto_hop(auto_hop).set_invincible(invincible));
+        self.conveyor_speed = 0;
+        self.magnet_frames = 0;
+        self.trail.clear();
+        self.prev_position = self.position();
     }
 
-    fn reset(boy: Self) -> Self {
-        RedHatBoy::new(
-            boy.sprite_sheet,
-            boy.image,
-            boy.state_machine.context().audio.clone(),
-            boy.state_machine.context().jump_sound.clone(),
-        )
+    // 最初からではなく、指定した位置で立ち上がり直す
+    // 足場は残っていないので、高さは記録せず必ず地面に止まった状態で戻す
+    fn respawn_at(&mut self, x: i16) {
+        self.reset();
+        self.state_machine = self.state_machine.clone().map_context(|mut context| {
+            context.position = Point {
+                x,
+                y: context.level.floor,
+            };
+            context.velocity = Point::default();
+            context
+        });
+        self.prev_position = self.position();
     }
 
     fn update(&mut self) {
+        self.prev_position = self.position();
         self.state_machine = self.state_machine.clone().update();
+        self.magnet_frames = self.magnet_frames.saturating_sub(1);
+        self.update_trail();
     }
 
-    fn run_right(&mut self) {
-        self.state_machine = self.state_machine.clone().transition(Event::Run);
+    // 少年は画面上で止まっていて世界の方が流れるので、古い残像ほど後ろへずらす
+    fn update_trail(&mut self) {
+        let walking_speed = self.walking_speed();
+        self.trail
+            .iter_mut()
+            .for_each(|(position, _)| position.x -= walking_speed);
+        if self.trail.len() == TRAIL_LENGTH {
+            self.trail.pop_front();
+        }
+        let offset = self.animation_offset();
+        let position = self.position();
+        self.trail.push_back((
+            Point {
+                x: position.x + offset.x,
+                y: position.y + offset.y,
+            },
+            self.frame_name(),
+        ));
+    }
+
+    fn shows_trail(&self) -> bool {
+        self.walking_speed() >= TRAIL_MIN_SPEED || self.is_dashing()
+    }
+
+    // 古いものほど薄く描く。最新の位置は本体と重なるので描かない
+    fn draw_trail(&self, renderer: &Renderer) {
+        let count = self.trail.len().saturating_sub(1);
+        for (index, (position, frame_name)) in self.trail.iter().take(count).enumerate() {
+            if let Some(sprite) = self.sprite(frame_name) {
+                let alpha = TRAIL_ALPHA * (index + 1) as f64 / (count + 1) as f64;
+                renderer.with_alpha(alpha, |renderer| {
+                    self.draw_sprite(renderer, sprite, *position)
+                });
+            }
+        }
+    }
+
+    fn run_right(&mut self, speed: i16) {
+        self.state_machine = self.state_machine.clone().transition(Event::Run(speed));
     }
 
     fn slide(&mut self) {
@@ -483,6 +1639,44 @@ impl RedHatBoy {
         self.state_machine = self.state_machine.clone().transition(Event::Jump);
     }
 
+    fn fast_fall(&mut self) {
+        self.state_machine = self.state_machine.clone().transition(Event::FastFall);
+    }
+
+    fn release_jump(&mut self) {
+        self.state_machine = self.state_machine.clone().transition(Event::JumpReleased);
+    }
+
+    fn set_auto_hop(&mut self, enabled: bool) {
+        self.state_machine = self
+            .state_machine
+            .clone()
+            .map_context(|context| context.set_auto_hop(enabled));
+    }
+
+    fn set_invincible(&mut self, invincible: bool) {
+        self.state_machine = self
+            .state_machine
+            .clone()
+            .map_context(|context| context.set_invincible(invincible));
+    }
+
+    fn is_invincible(&self) -> bool {
+        self.state_machine.context().invincible
+    }
+
+    fn dash(&mut self) {
+        self.state_machine = self.state_machine.clone().transition(Event::Dash);
+    }
+
+    fn is_dashing(&self) -> bool {
+        self.state_machine.context().is_dashing()
+    }
+
+    fn dash_charge(&self) -> f64 {
+        self.state_machine.context().dash_charge()
+    }
+
     fn knock_out(&mut self) {
         self.state_machine = self.state_machine.clone().transition(Event::KnockOut);
     }
@@ -491,12 +1685,55 @@ impl RedHatBoy {
         self.state_machine = self.state_machine.clone().transition(Event::Land(pos));
     }
 
+    fn give_shield(&mut self) {
+        self.state_machine = self
+            .state_machine
+            .clone()
+            .map_context(|context| context.give_shield());
+    }
+
+    fn is_shielded(&self) -> bool {
+        self.state_machine.context().shielded
+    }
+
+    fn give_magnet(&mut self) {
+        self.magnet_frames = MAGNET_FRAMES;
+    }
+
+    fn is_magnetized(&self) -> bool {
+        self.magnet_frames > 0
+    }
+
+    fn center(&self) -> Point {
+        let bounding_box = self.bounding_box();
+        Point {
+            x: bounding_box.x() + bounding_box.width / 2,
+            y: bounding_box.y() + bounding_box.height / 2,
+        }
+    }
+
+    fn bounce(&mut self, velocity: i16) {
+        self.state_machine = self
+            .state_machine
+            .clone()
+            .transition(Event::Bounce(velocity));
+    }
+
+    // contextの位置と速度のコピー
+    fn position(&self) -> Point {
+        self.state_machine.context().position
+    }
+
+    fn velocity(&self) -> Point {
+        self.state_machine.context().velocity
+    }
+
     fn pos_y(&self) -> i16 {
-        self.state_machine.context().position.y
+        self.position().y
     }
 
     fn velocity_y(&self) -> i16 {
-        self.state_machine.context().velocity.y
+        self.velocity().y
     }
 }
 
@@ -511,37 +1748,142 @@ enum RedHatBoyStateMachine {
 }
 
 pub enum Event {
-    Run,
+    Run(i16),
     Jump,
+    JumpReleased,
     Slide,
+    // 空中で下を押して、早く落ちる
+    FastFall,
+    Dash,
     KnockOut,
     Update,
     Land(i16),
+    // 与えた上向きの速度で跳ね上がる
+    Bounce(i16),
 }
 
 impl RedHatBoyStateMachine {
     // 止まってる時もジャンプできるようにするのが自然？あとでやってみよう
-    fn transition(self, event: Event) -> Self {
+    fn transition(mut self, event: Event) -> Self {
+        if matches!(event, Event::KnockOut) {
+            let context = self.context();
+            // ダッシュ中と、シールドが割れた直後は無敵
+            if context.is_dashing() || context.shield_grace_frames > 0 {
+                return self;
+            }
+            // シールドがあれば、倒れる代わりにシールドが割れる
+            // 練習モードでは、いつでもシールドが割れたときと同じように光って走り続ける
+            if context.shielded || context.invincible {
+                return self.map_context(|context| context.break_shield());
+            }
+        }
+
+        // ジャンプが押しっぱなしかは、どの状態でも覚えておく。押し直しかどうかは変える前の値で見る
+        let jump_was_held = self.context().jump_held;
+        match event {
+            Event::Jump => self = self.map_context(|context| context.hold_jump(true)),
+            Event::JumpReleased => self = self.map_context(|context| context.hold_jump(false)),
+            _ => {}
+        }
+
         match (self.clone(), event) {
-            (RedHatBoyStateMachine::Idle(state), Event::Run) => state.run().into(),
+            (RedHatBoyStateMachine::Idle(state), Event::Run(speed)) => state.run(speed).into(),
             (RedHatBoyStateMachine::Idle(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::Running(state), Event::Slide) => state.slide().into(),
             (RedHatBoyStateMachine::Running(state), Event::Update) => state.update().into(),
-            (RedHatBoyStateMachine::Running(state), Event::Jump) => state.jump().into(),
+            (RedHatBoyStateMachine::Running(state), Event::Jump)
+                if state.context().can_jump() && (!jump_was_held || state.context().auto_hop) =>
+            {
+                state.jump().into()
+            }
+            (RedHatBoyStateMachine::Running(state), Event::Dash) => state.dash().into(),
             (RedHatBoyStateMachine::Running(state), Event::KnockOut) => state.knock_out().into(),
             (RedHatBoyStateMachine::Running(state), Event::Land(pos)) => state.land_on(pos).into(),
+            (RedHatBoyStateMachine::Running(state), Event::Bounce(velocity)) => {
+                state.bounce(velocity).into()
+            }
             (RedHatBoyStateMachine::Jumping(state), Event::Update) => state.update().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::Jump) if !jump_was_held => {
+                state.buffer_jump().into()
+            }
+            (RedHatBoyStateMachine::Jumping(state), Event::JumpReleased) => state.cut_jump().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::FastFall) => state.fast_fall().into(),
             (RedHatBoyStateMachine::Jumping(state), Event::Land(pos)) => state.land_on(pos).into(),
             (RedHatBoyStateMachine::Jumping(state), Event::KnockOut) => state.knock_out().into(),
+            (RedHatBoyStateMachine::Jumping(state), Event::Bounce(velocity)) => {
+                state.bounce(velocity).into()
+            }
             (RedHatBoyStateMachine::Sliding(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::Sliding(state), Event::KnockOut) => state.knock_out().into(),
             (RedHatBoyStateMachine::Sliding(state), Event::Land(pos)) => state.land_on(pos).into(),
+            (RedHatBoyStateMachine::Sliding(state), Event::Bounce(velocity)) => {
+                state.bounce(velocity).into()
+            }
             (RedHatBoyStateMachine::Falling(state), Event::Update) => state.update().into(),
             (RedHatBoyStateMachine::KnockedOut(state), Event::Update) => state.update().into(),
             _ => self,
         }
     }
 
+    // transitionがそのイベントを無視せずに扱うか。状態は変えずに調べる
+    // transitionの分岐と同じ並びにしておくこと
+    #[allow(dead_code)]
+    fn can_transition(&self, event: &Event) -> bool {
+        if matches!(event, Event::KnockOut) {
+            let context = self.context();
+            // シールドや練習モードで防いだときは、状態は変わらない
+            if context.is_dashing()
+                || context.shield_grace_frames > 0
+                || context.shielded
+                || context.invincible
+            {
+                return false;
+            }
+        }
+
+        match (self, event) {
+            (RedHatBoyStateMachine::Idle(_), Event::Run(_)) => true,
+            (RedHatBoyStateMachine::Idle(_), Event::Update) => true,
+            (RedHatBoyStateMachine::Running(_), Event::Slide) => true,
+            (RedHatBoyStateMachine::Running(_), Event::Update) => true,
+            (RedHatBoyStateMachine::Running(state), Event::Jump) => {
+                let context = state.context();
+                context.can_jump() && (!context.jump_held || context.auto_hop)
+            }
+            (RedHatBoyStateMachine::Running(_), Event::Dash) => true,
+            (RedHatBoyStateMachine::Running(_), Event::KnockOut) => true,
+            (RedHatBoyStateMachine::Running(_), Event::Land(_)) => true,
+            (RedHatBoyStateMachine::Running(_), Event::Bounce(_)) => true,
+            (RedHatBoyStateMachine::Jumping(_), Event::Update) => true,
+            // 押しっぱなしのジャンプは、空中では先行入力にならない
+            (RedHatBoyStateMachine::Jumping(state), Event::Jump) => !state.context().jump_held,
+            (RedHatBoyStateMachine::Jumping(_), Event::JumpReleased) => true,
+            (RedHatBoyStateMachine::Jumping(_), Event::FastFall) => true,
+            (RedHatBoyStateMachine::Jumping(_), Event::Land(_)) => true,
+            (RedHatBoyStateMachine::Jumping(_), Event::KnockOut) => true,
+            (RedHatBoyStateMachine::Jumping(_), Event::Bounce(_)) => true,
+            (RedHatBoyStateMachine::Sliding(_), Event::Update) => true,
+            (RedHatBoyStateMachine::Sliding(_), Event::KnockOut) => true,
+            (RedHatBoyStateMachine::Sliding(_), Event::Land(_)) => true,
+            (RedHatBoyStateMachine::Sliding(_), Event::Bounce(_)) => true,
+            (RedHatBoyStateMachine::Falling(_), Event::Update) => true,
+            (RedHatBoyStateMachine::KnockedOut(_), Event::Update) => true,
+            _ => false,
+        }
+    }
+
+    // frame_nameはスプライト名の接頭辞（FallingもKnockedOutも"Dead"）なので、状態の識別には使えない
+    fn state_name(&self) -> &'static str {
+        match self {
+            RedHatBoyStateMachine::Idle(_) => "Idle",
+            RedHatBoyStateMachine::Running(_) => "Running",
+            RedHatBoyStateMachine::Sliding(_) => "Sliding",
+            RedHatBoyStateMachine::Jumping(_) => "Jumping",
+            RedHatBoyStateMachine::Falling(_) => "Falling",
+            RedHatBoyStateMachine::KnockedOut(_) => "KnockedOut",
+        }
+    }
+
     fn frame_name(&self) -> &str {
         match self {
             RedHatBoyStateMachine::Idle(state) => state.frame_name(),
@@ -553,6 +1895,17 @@ impl RedHatBoyStateMachine {
         }
     }
 
+    fn map_context(self, f: impl FnOnce(RedHatBoyContext) -> RedHatBoyContext) -> Self {
+        match self {
+            RedHatBoyStateMachine::Idle(state) => state.map_context(f).into(),
+            RedHatBoyStateMachine::Running(state) => state.map_context(f).into(),
+            RedHatBoyStateMachine::Sliding(state) => state.map_context(f).into(),
+            RedHatBoyStateMachine::Jumping(state) => state.map_context(f).into(),
+            RedHatBoyStateMachine::Falling(state) => state.map_context(f).into(),
+            RedHatBoyStateMachine::KnockedOut(state) => state.map_context(f).into(),
+        }
+    }
+
     fn context(&self) -> &RedHatBoyContext {
         match self {
             RedHatBoyStateMachine::Idle(state) => &state.context(),
@@ -571,6 +1924,14 @@ impl RedHatBoyStateMachine {
     fn knocked_out(&self) -> bool {
         matches!(self, RedHatBoyStateMachine::KnockedOut(_))
     }
+
+    // 倒れている途中（Falling）も含めて、もう走れない状態かどうか
+    fn knocked_down(&self) -> bool {
+        matches!(
+            self,
+            RedHatBoyStateMachine::Falling(_) | RedHatBoyStateMachine::KnockedOut(_)
+        )
+    }
 }
 
 impl From<RedHatBoyState<Idle>> for RedHatBoyStateMachine {
@@ -610,12 +1971,10 @@ impl From<RedHatBoyState<KnockedOut>> for RedHatBoyStateMachine {
 }
 
 mod red_hat_boy_states {
-    use super::HEIGHT;
+    use super::LevelConfig;
     use crate::engine::{Audio, Point, Sound};
 
     use super::RedHatBoyStateMachine;
-    const FLOOR: i16 = 479;
-    const PLAYER_HEIGHT: i16 = HEIGHT - FLOOR;
     const STARTING_POINT: i16 = -20;
     const IDLE_FRAME_NAME: &str = "Idle";
     const SLIDING_NAME: &str = "Slide";
@@ -629,11 +1988,22 @@ mod red_hat_boy_states {
     const JUMP_FRAMES: u8 = 35;
     const FALL_FRAMES: u8 = 29;
 
-    const RUNNING_SPEED: i16 = 4;
     const JUMP_SPEED: i16 = -23;
+    // スペースを離した後、上昇中だけ余分にかける重力
+    const JUMP_CUT_GRAVITY: i16 = 2;
+    // 空中で下を押している間、余分にかける重力
+    const FAST_FALL_GRAVITY: i16 = 3;
 
-    const GRAVITY: i16 = 1;
-    const TERMINAL_VELOCITY: i16 = 20;
+    // 足場を離れてからもジャンプを受け付けるフレーム数
+    const COYOTE_FRAMES: u8 = 6;
+    // 着地前に押されたジャンプを覚えておくフレーム数
+    const JUMP_BUFFER_FRAMES: u8 = 6;
+
+    pub const SHIELD_GRACE_FRAMES: u8 = 30;
+
+    const DASH_SPEED: i16 = 6;
+    const DASH_FRAMES: u8 = 12;
+    const DASH_COOLDOWN: u8 = 60;
 
     #[derive(Clone, Copy)]
     pub struct Idle;
@@ -663,30 +2033,46 @@ mod red_hat_boy_states {
         pub fn context(&self) -> &RedHatBoyContext {
             &self.context
         }
+
+        // 状態は変えずに、contextだけを書き換える
+        pub fn map_context(mut self, f: impl FnOnce(RedHatBoyContext) -> RedHatBoyContext) -> Self {
+            self.context = f(self.context);
+            self
+        }
     }
 
     // これがタイプステートパターンなのかな？
     // すごい、Idleの部分が値みたいな直観があるせいで、依存型に見える
     impl RedHatBoyState<Idle> {
-        pub fn new(audio: Audio, jump_sound: Sound) -> Self {
+        pub fn new(audio: Audio, jump_sound: Sound, level: LevelConfig) -> Self {
             RedHatBoyState {
                 context: RedHatBoyContext {
                     frame: 0,
                     position: Point {
                         x: STARTING_POINT,
-                        y: FLOOR,
+                        y: level.floor,
                     },
                     velocity: Point { x: 0, y: 0 },
                     audio,
                     jump_sound,
+                    dash_frames: 0,
+                    dash_cooldown: 0,
+                    coyote_frames: COYOTE_FRAMES,
+                    jump_buffer_frames: 0,
+                    level,
+                    shielded: false,
+                    shield_grace_frames: 0,
+                    jump_held: false,
+                    auto_hop: false,
+                    invincible: false,
                 },
                 _state: Idle {},
             }
         }
 
-        pub fn run(self) -> RedHatBoyState<Running> {
+        pub fn run(self, speed: i16) -> RedHatBoyState<Running> {
             RedHatBoyState {
-                context: self.context.reset_frame().run_right(),
+                context: self.context.reset_frame().run_right(speed),
                 _state: Running {},
             }
         }
@@ -719,16 +2105,30 @@ mod red_hat_boy_states {
         }
 
         pub fn jump(self) -> RedHatBoyState<Jumping> {
+            self.bounce(JUMP_SPEED)
+        }
+
+        pub fn bounce(self, velocity: i16) -> RedHatBoyState<Jumping> {
             RedHatBoyState {
                 context: self
                     .context
-                    .set_vertical_velocity(JUMP_SPEED)
+                    .set_vertical_velocity(velocity)
+                    .leave_ground()
+                    .clear_jump_buffer()
                     .reset_frame()
                     .play_jump_sound(),
                 _state: Jumping {},
             }
         }
 
+        // クールダウン中は何もしない
+        pub fn dash(mut self) -> Self {
+            if self.context.can_dash() {
+                self.context = self.context.start_dash();
+            }
+            self
+        }
+
         pub fn knock_out(self) -> RedHatBoyState<Falling> {
             RedHatBoyState {
                 context: self.context.reset_frame().stop(),
@@ -780,6 +2180,11 @@ mod red_hat_boy_states {
                 _state: Sliding,
             }
         }
+
+        // 滑っている途中でも、起き上がってそのまま跳ね上がる
+        pub fn bounce(self, velocity: i16) -> RedHatBoyState<Jumping> {
+            self.stand().bounce(velocity)
+        }
     }
 
     pub enum SlidingEndState {
@@ -807,18 +2212,65 @@ mod red_hat_boy_states {
             // これの閾値を JUMPING FRAMEでやろうとすると空中ジャンプする
             // 地面につくまでの時間（必要フレーム数）は、初速度と重力に依存するので、
             // frame数が毎回それに合致しているとは限らない
-            if self.context.position.y >= FLOOR {
-                JumpingEndState::Complete(self.land_on(HEIGHT.into()))
+            if self.context.position.y >= self.context.level.floor {
+                let height = self.context.level.height;
+                self.land_on(height)
             } else {
                 JumpingEndState::Jumping(self)
             }
         }
 
-        pub fn land_on(self, pos: i16) -> RedHatBoyState<Running> {
-            RedHatBoyState {
+        // 着地の直前にジャンプが押されていたら、着地と同時にもう一度跳ぶ
+        // オートホップが有効なら、押しっぱなしのまま着地したときも跳ぶ
+        pub fn land_on(self, pos: i16) -> JumpingEndState {
+            let buffered = self.context.jump_buffer_frames > 0
+                || (self.context.auto_hop && self.context.jump_held);
+            let running = RedHatBoyState {
                 context: self.context.reset_frame().set_on(pos),
                 _state: Running,
+            };
+
+            if buffered {
+                JumpingEndState::Jumping(running.jump())
+            } else {
+                JumpingEndState::Complete(running)
+            }
+        }
+
+        // 空中で跳ね上げられたら、そのまま新しい上昇に切り替える
+        pub fn bounce(self, velocity: i16) -> Self {
+            RedHatBoyState {
+                context: self
+                    .context
+                    .set_vertical_velocity(velocity)
+                    .clear_jump_buffer()
+                    .reset_frame()
+                    .play_jump_sound(),
+                _state: Jumping {},
+            }
+        }
+
+        pub fn buffer_jump(mut self) -> Self {
+            self.context.jump_buffer_frames = JUMP_BUFFER_FRAMES;
+            self
+        }
+
+        // スペースを早く離すほど上昇が早く止まり、低いジャンプになる
+        // 普通のジャンプより速く上昇している（跳ね上げられた）間は削らない
+        pub fn cut_jump(mut self) -> Self {
+            if (JUMP_SPEED..0).contains(&self.context.velocity.y) {
+                let velocity = self.context.velocity.y + JUMP_CUT_GRAVITY;
+                self.context = self.context.set_vertical_velocity(velocity.min(0));
             }
+            self
+        }
+
+        // 終端速度までは速くするが、跳ね上げなどで既に超えていれば遅くはしない
+        pub fn fast_fall(mut self) -> Self {
+            let velocity = self.context.velocity.y;
+            let boosted = (velocity + FAST_FALL_GRAVITY).min(self.context.level.terminal_velocity);
+            self.context = self.context.set_vertical_velocity(boosted.max(velocity));
+            self
         }
 
         pub fn knock_out(self) -> RedHatBoyState<Falling> {
@@ -898,13 +2350,31 @@ mod red_hat_boy_states {
         pub velocity: Point,
         pub audio: Audio,
         pub jump_sound: Sound,
+        // ダッシュの残りフレームと、次にダッシュできるまでのフレーム
+        pub dash_frames: u8,
+        pub dash_cooldown: u8,
+        // 地面や足場から離れても、まだジャンプできる残りフレーム
+        pub coyote_frames: u8,
+        pub jump_buffer_frames: u8,
+        pub level: LevelConfig,
+        // 次の一撃を防ぐシールドと、それが割れてからの無敵の残りフレーム
+        pub shielded: bool,
+        pub shield_grace_frames: u8,
+        // ジャンプキーが押しっぱなしか。オートホップが有効なら、押しっぱなしで着地すると跳び直す
+        pub jump_held: bool,
+        pub auto_hop: bool,
+        // 練習モード。ぶつかっても倒れない
+        pub invincible: bool,
     }
 
     impl RedHatBoyContext {
         pub fn update(mut self, frame_count: u8) -> Self {
-            if self.velocity.y < TERMINAL_VELOCITY {
-                self.velocity.y += GRAVITY;
-            }
+            self = self.update_dash();
+            self.coyote_frames = self.coyote_frames.saturating_sub(1);
+            self.jump_buffer_frames = self.jump_buffer_frames.saturating_sub(1);
+            self.shield_grace_frames = self.shield_grace_frames.saturating_sub(1);
+
+            self = self.apply_gravity();
             //log!("Gravity {}", self.velocity.y);
             if self.frame < frame_count {
                 self.frame += 1;
@@ -914,30 +2384,52 @@ mod red_hat_boy_states {
 
             self.position.y += self.velocity.y;
 
-            if self.position.y > FLOOR {
+            if self.position.y >= self.level.floor {
                 // ここで速度も0にしてもいいかも
                 // 今速度を着地後も適当にしているのは、状態が変わって、状態が変わるとcontextがリセットされて問題ないからか
-                self.position.y = FLOOR;
+                self.position.y = self.level.floor;
+                self.coyote_frames = COYOTE_FRAMES;
             }
 
-            self
+            self.bump_ceiling()
         }
 
         // ch05最後の演習問題の解答でオリジナルなので、後々整合性が取れなくなったらまずここを疑う
         pub fn update_with_fixed_frame(mut self) -> Self {
-            if self.velocity.y < TERMINAL_VELOCITY {
-                self.velocity.y += GRAVITY;
-            }
+            self = self.apply_gravity();
             //log!("Gravity {}", self.velocity.y);
 
             self.position.y += self.velocity.y;
 
-            if self.position.y > FLOOR {
+            if self.position.y > self.level.floor {
                 // ここで速度も0にしてもいいかも
                 // 今速度を着地後も適当にしているのは、状態が変わって、状態が変わるとcontextがリセットされて問題ないからか
-                self.position.y = FLOOR;
+                self.position.y = self.level.floor;
+            }
+
+            self.bump_ceiling()
+        }
+
+        fn apply_gravity(mut self) -> Self {
+            let gravity = if self.velocity.y < 0 {
+                self.level.rise_gravity
+            } else {
+                self.level.fall_gravity
+            };
+            if self.velocity.y < self.level.terminal_velocity {
+                self.velocity.y = (self.velocity.y + gravity).min(self.level.terminal_velocity);
             }
+            self
+        }
 
+        // 画面の上に飛び出さないよう、天井にぶつかったら上向きの速度を消す
+        fn bump_ceiling(mut self) -> Self {
+            if self.position.y < self.level.ceiling {
+                self.position.y = self.level.ceiling;
+                if self.velocity.y < 0 {
+                    self.velocity.y = 0;
+                }
+            }
             self
         }
 
@@ -946,8 +2438,85 @@ mod red_hat_boy_states {
             self
         }
 
-        pub fn run_right(mut self) -> Self {
-            self.velocity.x += RUNNING_SPEED;
+        pub fn can_jump(&self) -> bool {
+            self.coyote_frames > 0
+        }
+
+        fn leave_ground(mut self) -> Self {
+            self.coyote_frames = 0;
+            self
+        }
+
+        fn clear_jump_buffer(mut self) -> Self {
+            self.jump_buffer_frames = 0;
+            self
+        }
+
+        pub fn is_dashing(&self) -> bool {
+            self.dash_frames > 0
+        }
+
+        pub fn give_shield(mut self) -> Self {
+            self.shielded = true;
+            self
+        }
+
+        pub fn hold_jump(mut self, held: bool) -> Self {
+            self.jump_held = held;
+            self
+        }
+
+        pub fn set_auto_hop(mut self, enabled: bool) -> Self {
+            self.auto_hop = enabled;
+            self
+        }
+
+        pub fn set_invincible(mut self, invincible: bool) -> Self {
+            self.invincible = invincible;
+            self
+        }
+
+        // 割れた直後は同じ障害物に当たり続けるので、しばらく無敵にする
+        pub fn break_shield(mut self) -> Self {
+            self.shielded = false;
+            self.shield_grace_frames = SHIELD_GRACE_FRAMES;
+            self
+        }
+
+        fn can_dash(&self) -> bool {
+            !self.is_dashing() && self.dash_cooldown == 0
+        }
+
+        // 次のダッシュまでの溜まり具合（1.0で使える）
+        pub fn dash_charge(&self) -> f64 {
+            if self.is_dashing() {
+                0.0
+            } else {
+                1.0 - self.dash_cooldown as f64 / DASH_COOLDOWN as f64
+            }
+        }
+
+        fn start_dash(mut self) -> Self {
+            self.velocity.x += DASH_SPEED;
+            self.dash_frames = DASH_FRAMES;
+            self
+        }
+
+        fn update_dash(mut self) -> Self {
+            if self.dash_frames > 0 {
+                self.dash_frames -= 1;
+                if self.dash_frames == 0 {
+                    self.velocity.x -= DASH_SPEED;
+                    self.dash_cooldown = DASH_COOLDOWN;
+                }
+            } else if self.dash_cooldown > 0 {
+                self.dash_cooldown -= 1;
+            }
+            self
+        }
+
+        pub fn run_right(mut self, speed: i16) -> Self {
+            self.velocity.x += speed;
             self
         }
 
@@ -962,17 +2531,167 @@ mod red_hat_boy_states {
         }
 
         fn set_on(mut self, position: i16) -> Self {
-            let position = position - PLAYER_HEIGHT;
+            let position = position - self.level.player_height();
             self.position.y = position;
+            self.coyote_frames = COYOTE_FRAMES;
             self
         }
 
         fn play_jump_sound(self) -> Self {
             if let Err(err) = self.audio.play_sound(&self.jump_sound) {
-                log!("Error playing jump sound {:#?}", err);
+                log_warn!("Error playing jump sound {:#?}", err);
+            }
+            self
+        }
+    }
+
+    // テスト用。Idleの初期値から始めて、フレームや位置、速度を好きな場面に合わせる
+    #[cfg(test)]
+    impl RedHatBoyContext {
+        pub fn builder(
+            audio: Audio,
+            jump_sound: Sound,
+            level: LevelConfig,
+        ) -> RedHatBoyContextBuilder {
+            RedHatBoyContextBuilder {
+                context: RedHatBoyState::new(audio, jump_sound, level).context,
             }
+        }
+    }
+
+    #[cfg(test)]
+    pub struct RedHatBoyContextBuilder {
+        context: RedHatBoyContext,
+    }
+
+    #[cfg(test)]
+    impl RedHatBoyContextBuilder {
+        pub fn frame(mut self, frame: u8) -> Self {
+            self.context.frame = frame;
+            self
+        }
+
+        pub fn position(mut self, position: Point) -> Self {
+            self.context.position = position;
+            self
+        }
+
+        pub fn velocity(mut self, velocity: Point) -> Self {
+            self.context.velocity = velocity;
             self
         }
+
+        pub fn build(self) -> RedHatBoyContext {
+            self.context
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use wasm_bindgen_test::wasm_bindgen_test;
+        use web_sys::{AudioBuffer, AudioBufferOptions};
+
+        use super::*;
+
+        fn test_builder(level: LevelConfig) -> RedHatBoyContextBuilder {
+            let options = AudioBufferOptions::new(1, 3000.0);
+            let sound = Sound {
+                buffer: AudioBuffer::new(&options).unwrap(),
+            };
+            RedHatBoyContext::builder(Audio::new().unwrap(), sound, level)
+        }
+
+        fn test_context(level: LevelConfig) -> RedHatBoyContext {
+            test_builder(level).build()
+        }
+
+        #[wasm_bindgen_test]
+        fn test_builder_starts_a_fast_fall_from_any_frame_and_position() {
+            let level = LevelConfig::default();
+            let context = test_builder(level)
+                .frame(JUMP_FRAMES)
+                .position(Point { x: 100, y: 200 })
+                .velocity(Point { x: 4, y: 15 })
+                .build();
+
+            let context = context.update(JUMP_FRAMES);
+            assert_eq!(context.frame, 0);
+            assert_eq!(context.velocity.y, 16);
+            assert_eq!(context.position.x, 100);
+            assert_eq!(context.position.y, 216);
+
+            // 終端速度で頭打ちになりつつ、床を突き抜けずに止まる
+            let mut context = context;
+            for _ in 0..30 {
+                context = context.update(JUMP_FRAMES);
+            }
+            assert_eq!(context.velocity.y, level.terminal_velocity);
+            assert_eq!(context.position.y, level.floor);
+            assert!(context.can_jump());
+        }
+
+        #[wasm_bindgen_test]
+        fn test_boy_cannot_go_above_the_ceiling() {
+            let level = LevelConfig::default();
+            let mut context = test_context(level).set_vertical_velocity(-200);
+
+            for _ in 0..10 {
+                context = context.update(JUMP_FRAMES);
+                assert!(context.position.y >= level.ceiling);
+            }
+        }
+
+        #[wasm_bindgen_test]
+        fn test_hitting_the_ceiling_stops_upward_velocity() {
+            let level = LevelConfig::default();
+            let context = test_context(level)
+                .set_vertical_velocity(-600)
+                .update(JUMP_FRAMES);
+
+            assert_eq!(context.position.y, level.ceiling);
+            assert_eq!(context.velocity.y, 0);
+        }
+
+        #[wasm_bindgen_test]
+        fn test_boy_clamps_to_a_custom_floor() {
+            let level = LevelConfig {
+                floor: 300,
+                height: 421,
+                ..LevelConfig::default()
+            };
+            let mut context = test_context(level);
+            assert_eq!(context.position.y, 300);
+
+            for _ in 0..30 {
+                context = context.update(RUNNING_FRAMES);
+            }
+            assert_eq!(context.position.y, 300);
+
+            let context = context.set_on(421);
+            assert_eq!(context.position.y, 300);
+        }
+
+        #[wasm_bindgen_test]
+        fn test_rise_and_fall_use_their_own_gravity() {
+            let level = LevelConfig {
+                rise_gravity: 1,
+                fall_gravity: 3,
+                terminal_velocity: 8,
+                ..LevelConfig::default()
+            };
+            let rising = test_context(level)
+                .set_vertical_velocity(-10)
+                .update(JUMP_FRAMES);
+            assert_eq!(rising.velocity.y, -9);
+
+            let falling = test_context(level)
+                .set_vertical_velocity(2)
+                .update(JUMP_FRAMES);
+            assert_eq!(falling.velocity.y, 5);
+
+            let fast = falling.update(JUMP_FRAMES).update(JUMP_FRAMES);
+            assert_eq!(fast.velocity.y, 8);
+        }
     }
 }
 
@@ -980,249 +2699,4788 @@ pub struct Walk {
     obstacle_sheet: Rc<SpriteSheet>,
     boy: RedHatBoy,
     backgrounds: [Image; 2],
+    // 背景テーマの画像と、今スクロールしてくるテーマの番号
+    themes: Vec<HtmlImageElement>,
+    theme: usize,
+    // 切り替わる前のテーマ。薄くしながら今のテーマの上に重ねる
+    theme_fade: Option<ThemeFade>,
     obstacles: Vec<Box<dyn Obstacle>>,
     stone: HtmlImageElement,
+    props: PropImages,
+    // segments.json で定義したセグメント。組み込みのものと合わせてランダムに選ぶ
+    segments: Vec<SegmentData>,
+    // 次のセグメントを選ぶための乱数。?seed=で固定すると毎回同じ並びになる
+    rng: StdRng,
+    seed: Option<u64>,
+    // セグメントを乱数で選ぶか、決まった順に並べるか。segment_indexはこの走りで生成した数
+    segment_source: SegmentSource,
+    segment_index: usize,
     timeline: i16,
+    difficulty: DifficultySettings,
+    // 点の付け方と、それで付いた今の点
+    score_model: Box<dyn ScoreModel>,
+    score: i32,
+    // ノーミスで連続して抜けた障害物の数
+    combo: i32,
+    // コンボが途切れた合図の、残りの光るフレーム数と鳴らす音
+    combo_break_frames: u8,
+    combo_lost_sound: Sound,
+    audio: Audio,
+    shake_frames: u8,
+    distance: i32,
+    mode: GameMode,
+    last_checkpoint: Option<Checkpoint>,
+    // ゴールの線を置いたか、ゴールまで走りきったか
+    finish_line_placed: bool,
+    won: bool,
+    // 次のチェックポイントの距離、越えたチェックポイントの数、障害物を止めている残りフレーム
+    next_checkpoint: i32,
+    checkpoints: u32,
+    checkpoint_pause: u16,
+    notifications: Notifications,
+    has_dashed: bool,
+    // 抜けたと数え済みの障害物のid
+    passed_obstacles: Vec<u32>,
+    // 今回の走りの記録と、これまでで一番遠くまで走れた記録
+    ghost: GhostTrack,
+    best_run: Option<GhostTrack>,
+    music: Option<Music>,
+    // ?debug=1 のときだけ使う、開発用の設定
+    debug_console: Option<DebugConsole>,
+    // デバッグ時だけ、直近の入力と少年の状態を残しておく
+    input_log: Option<InputLog>,
+    god_mode: bool,
+    speed_override: Option<i16>,
+    // このセッションでの累計と、localStorageに残している全期間の累計
+    stats: Stats,
+    lifetime_stats: Stats,
+    // アトラクトモードのデモで走っているか
+    demo: bool,
+    // 直近のupdate_worldで少年に当たった当たり判定。デバッグ表示で赤く塗る
+    last_collision: Option<Rect>,
+    // WalkTheDogが持つ設定の写し。メニューで切り替えられるたびに渡し直される
+    settings: Settings,
+    // セグメントを生成するたびに、種類と障害物の数を渡して呼ぶ。テストや分析用
+    on_segment_spawned: Option<Box<dyn FnMut(SegmentKind, usize)>>,
+    // ?leaderboard= で指定されたランキング。ゲームオーバーで点を送り、メニューで上位を出す
+    leaderboard: Leaderboard,
 }
 
 impl Walk {
-    fn reset(walk: Self) -> Self {
-        let starting_obstacles =
-            stone_and_platform(walk.stone.clone(), walk.obstacle_sheet.clone(), 0);
-        let timeline = rightmost(&starting_obstacles);
+    // 少年の操作に関わる設定は、少年にも渡しておく
+    fn apply_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+        self.boy.set_auto_hop(settings.auto_hop);
+        self.boy.set_invincible(settings.invincible);
+    }
 
-        Walk {
-            boy: RedHatBoy::reset(walk.boy),
-            backgrounds: walk.backgrounds,
-            obstacles: starting_obstacles,
-            obstacle_sheet: walk.obstacle_sheet,
-            stone: walk.stone,
-            timeline,
+    // 画像や音などの読み込み済みの素材はそのままに、走りの状態だけを最初に戻す
+    fn reset(&mut self) {
+        self.boy.reset();
+
+        let ghost = std::mem::take(&mut self.ghost);
+        let longest = match self.best_run.take() {
+            Some(best_run) if best_run.distance() >= ghost.distance() => best_run,
+            _ => ghost,
+        };
+        self.best_run = Some(longest);
+
+        self.theme = 0;
+        self.theme_fade = None;
+        let [bg_fst, bg_snd] = &mut self.backgrounds;
+        bg_fst.set_element(self.themes[0].clone());
+        bg_snd.set_element(self.themes[0].clone());
+        bg_fst.set_x(0);
+        bg_snd.set_x(bg_fst.right());
+
+        self.obstacles = tutorial(self.stone.clone(), self.difficulty.grace_distance);
+        self.timeline = rightmost(&self.obstacles);
+        self.passed_obstacles.clear();
+        self.segment_index = 0;
+        // シードが決まっていれば、やり直しても同じ並びになるよう引き直す
+        if let Some(seed) = self.seed {
+            self.rng = StdRng::seed_from_u64(seed);
         }
-    }
 
-    fn knocked_out(&self) -> bool {
-        self.boy.knocked_out()
+        self.score_model.reset();
+        self.score = 0;
+        self.combo = 0;
+        self.combo_break_frames = 0;
+        self.shake_frames = 0;
+        self.distance = 0;
+        self.last_checkpoint = None;
+        self.last_collision = None;
+        self.finish_line_placed = false;
+        self.won = false;
+        self.next_checkpoint = CHECKPOINT_DISTANCE;
+        self.checkpoints = 0;
+        self.checkpoint_pause = 0;
+        self.notifications = Notifications::default();
+        self.has_dashed = false;
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        self.backgrounds.iter().for_each(|background| {
-            background.draw(renderer);
-        });
-        self.boy.draw(renderer);
+    // ゴールまでの残りの距離。終わりのないモードではNone
+    fn remaining_distance(&self) -> Option<i32> {
+        match self.mode {
+            GameMode::Endless => None,
+            GameMode::Level { length } => Some(length - self.distance),
+        }
+    }
 
-        self.obstacles.iter().for_each(|obstacle| {
-            obstacle.draw(renderer);
-        });
+    // チェックポイントの分だけ難しくした、今の難易度
+    fn current_difficulty(&self) -> DifficultySettings {
+        self.difficulty.harder_by(self.checkpoints)
     }
 
-    fn velocity(&self) -> i16 {
-        -self.boy.walking_speed()
+    fn update_checkpoint(&mut self) {
+        self.checkpoint_pause = self.checkpoint_pause.saturating_sub(1);
+        if self.distance >= self.next_checkpoint {
+            self.checkpoints += 1;
+            self.next_checkpoint += CHECKPOINT_DISTANCE;
+            self.checkpoint_pause = CHECKPOINT_PAUSE_FRAMES;
+            self.notifications
+                .push(format!("Checkpoint {}!", self.checkpoints));
+            self.last_checkpoint = Some(Checkpoint {
+                distance: self.distance,
+                x: self.boy.position().x,
+            });
+        }
     }
 
-    fn generate_next_segment(&mut self) {
-        let mut rng = thread_rng();
-        let next_segment = rng.gen_range(0..2);
+    // 倒れたとき、ゲームオーバーにせずチェックポイントからやり直せるか
+    fn can_respawn(&self) -> bool {
+        self.settings.respawn && self.mode != GameMode::Endless && self.last_checkpoint.is_some()
+    }
 
-        let mut next_obstacles = match next_segment {
-            0 => stone_and_platform(
-                self.stone.clone(),
-                self.obstacle_sheet.clone(),
-                self.timeline + OBSTACLE_BUFFER,
-            ),
-            1 => platform_and_stone(
-                self.stone.clone(),
-                self.obstacle_sheet.clone(),
-                self.timeline + OBSTACLE_BUFFER,
-            ),
-            _ => vec![],
+    // 障害物は残しておけないので、チェックポイントの距離から新しく生成し直す
+    // 点とチェックポイントの数はそのまま持ち越す
+    fn respawn(&mut self) {
+        let checkpoint = match self.last_checkpoint {
+            Some(checkpoint) => checkpoint,
+            None => return,
         };
+        self.count(|stats| stats.deaths += 1);
+        self.boy.respawn_at(checkpoint.x);
+        self.boy.run_right(self.difficulty.running_speed);
+        self.distance = checkpoint.distance;
+        self.obstacles.clear();
+        self.passed_obstacles.clear();
+        self.timeline = WIDTH;
+        self.finish_line_placed = false;
+        self.combo = 0;
+        self.shake_frames = 0;
+        // 立ち上がってすぐに障害物が来ないようにする
+        self.checkpoint_pause = CHECKPOINT_PAUSE_FRAMES;
+    }
 
-        self.timeline = rightmost(&next_obstacles);
-        self.obstacles.append(&mut next_obstacles);
+    fn knocked_out(&self) -> bool {
+        self.boy.knocked_out()
     }
-}
 
-pub struct Barrier {
-    image: Image,
-}
+    // デモと練習モードの走りは、記録にも自己ベストにも残さない
+    fn records_run(&self) -> bool {
+        !self.demo && !self.boy.is_invincible()
+    }
 
-impl Barrier {
-    pub fn new(image: Image) -> Self {
-        Barrier { image }
+    fn count(&mut self, increment: impl Fn(&mut Stats)) {
+        if !self.records_run() {
+            return;
+        }
+        increment(&mut self.stats);
+        increment(&mut self.lifetime_stats);
     }
-}
 
-impl Obstacle for Barrier {
-    fn check_intersection(&self, boy: &mut RedHatBoy) {
-        if boy.bounding_box().intersects(self.image.bounding_box()) {
-            boy.knock_out()
+    // このフレームで新しくジャンプやスライディングに入っていたら数える
+    fn record_transition(&mut self, state_before: &str) {
+        let state_after = self.boy.state_name();
+        if state_after == state_before {
+            return;
+        }
+        match state_after {
+            "Jumping" => self.count(|stats| stats.jumps += 1),
+            "Sliding" => self.count(|stats| stats.slides += 1),
+            _ => {}
         }
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        self.image.draw(renderer)
+    // 不具合の報告に貼ってもらえるよう、記録をJSONでコンソールに出す
+    fn dump_input_log(&self) {
+        if let Some(input_log) = &self.input_log {
+            match input_log.to_json() {
+                Ok(json) => browser::print(&json),
+                Err(err) => error!("Could not dump input log {:#?}", err),
+            }
+        }
     }
 
-    fn move_horizontally(&mut self, x: i16) {
-        self.image.move_horizontally(x);
+    // 走りを終えるとき（倒れたときとやり直したとき）に、距離と死亡回数を数えて保存する
+    fn finish_run(&mut self, died: bool) {
+        if !self.records_run() {
+            return;
+        }
+        let meters = (self.distance / PIXELS_PER_METER).max(0) as u32;
+        self.count(|stats| {
+            stats.distance += meters;
+            if died {
+                stats.deaths += 1;
+            }
+        });
+        if let Err(err) = self.lifetime_stats.save() {
+            error!("Could not save stats {:#?}", err);
+        }
     }
 
-    fn right(&self) -> i16 {
-        self.image.right()
+    fn score_submission(&self) -> ScoreSubmission {
+        ScoreSubmission {
+            score: self.score,
+            distance: (self.distance / PIXELS_PER_METER).max(0) as u32,
+            seed: self.seed.map(|seed| seed.to_string()),
+            sequence: self.segment_source.indices(),
+            stats: self.stats,
+        }
     }
-}
 
-#[async_trait(?Send)]
-impl Game for WalkTheDog {
-    async fn initialize(&self) -> Result<Box<dyn Game>> {
-        match self.machine {
-            None => {
-                let json = browser::fetch_json("rhb.json").await?;
-                let background = engine::load_image("BG.png").await?;
-                let stone = engine::load_image("Stone.png").await?;
-
-                let tiles = browser::fetch_json("tiles.json").await?;
-                let sprite_sheet = Rc::new(SpriteSheet::new(
-                    tiles.into_serde()?,
-                    engine::load_image("tiles.png").await?,
+    // ランキングのURLが設定されていれば、点を送る。返事は待たないので、送れなくてもすぐやり直せる
+    fn submit_score(&self) {
+        let url = match self.leaderboard.url() {
+            Some(url) if self.records_run() => url.to_string(),
+            _ => return,
+        };
+        let body = match self.score_submission().to_json() {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Could not serialize score {:#?}", err);
+                return;
+            }
+        };
+        browser::spawn_local(async move {
+            if let Err(err) = browser::post_json(&url, &body).await {
+                log_warn!("Could not submit score {:#?}", err);
+            }
+        });
+    }
+
+    fn run_debug_commands(&mut self) {
+        let commands = match self.debug_console.as_mut() {
+            Some(console) => console.commands(),
+            None => return,
+        };
+        for command in commands {
+            self.run_debug_command(command);
+        }
+    }
+
+    fn run_debug_command(&mut self, command: DebugCommand) {
+        match command {
+            DebugCommand::SpawnStone => {
+                let stones = lone_stone(self.stone.clone(), WIDTH);
+                self.obstacles.extend(stones);
+            }
+            DebugCommand::SetSpeed(speed) => self.speed_override = Some(speed),
+            DebugCommand::Kill => self.boy.knock_out(),
+            DebugCommand::God => {
+                self.god_mode = !self.god_mode;
+                self.notifications.push(format!(
+                    "God mode {}",
+                    if self.god_mode { "on" } else { "off" }
                 ));
+            }
+            DebugCommand::Peek => self
+                .notifications
+                .push(format!("Next: {:?}", self.peek_next_segment())),
+        }
+    }
 
-                let audio = Audio::new()?;
-                let sound = audio.load_sound("SFX_Jump_23.mp3").await?;
+    fn pause_music(&mut self) {
+        if let Some(Err(err)) = self.music.as_mut().map(Music::pause) {
+            error!("Error pausing music {:#?}", err);
+        }
+    }
 
-                // うるさいので消しておく
-                // let background_sound = audio.load_sound("background_song.mp3").await?;
-                // audio.play_looping_sound(&background_sound)?;
-                //
+    fn resume_music(&mut self) {
+        if let Some(Err(err)) = self.music.as_mut().map(Music::resume) {
+            error!("Error resuming music {:#?}", err);
+        }
+    }
 
-                let rhb = RedHatBoy::new(
-                    json.into_serde::<Sheet>()?,
-                    engine::load_image("rhb.png").await?,
-                    audio,
-                    sound,
-                );
+    fn knocked_down(&self) -> bool {
+        self.boy.knocked_down()
+    }
 
-                let background_width = background.width() as i16;
-                let starting_obstacles = stone_and_platform(stone.clone(), sprite_sheet.clone(), 0);
-                let timeline = rightmost(&starting_obstacles);
-                let machine = WalkTheDogStateMachine::new(Walk {
-                    boy: rhb,
-                    backgrounds: [
-                        Image::new(background.clone(), Point { x: 0, y: 0 }),
-                        Image::new(
-                            background,
-                            Point {
-                                x: background_width,
-                                y: 0,
-                            },
-                        ),
-                    ],
-                    obstacles: starting_obstacles,
-                    obstacle_sheet: sprite_sheet,
-                    stone,
-                    timeline,
-                });
+    fn update_world(&mut self) {
+        let walking_speed = self.velocity();
+        let previous_meters = self.distance / PIXELS_PER_METER;
+        self.distance -= walking_speed as i32;
+        let meters = self.distance / PIXELS_PER_METER;
+        self.score = self.score_model.on_frame(self.distance);
+        if matches!(self.remaining_distance(), Some(remaining) if remaining <= 0) {
+            self.won = true;
+        }
+        if meters / DISTANCE_MILESTONE > previous_meters / DISTANCE_MILESTONE {
+            self.notifications.push(format!(
+                "{}m!",
+                meters / DISTANCE_MILESTONE * DISTANCE_MILESTONE
+            ));
+        }
+        self.update_checkpoint();
+        if !self.has_dashed && self.boy.is_dashing() {
+            self.has_dashed = true;
+            self.notifications.push("First dash!".to_string());
+        }
+        self.notifications.update();
+        if !self.boy.is_invincible() {
+            self.ghost
+                .record(self.distance, self.boy.pos_y(), self.boy.frame_name());
+        }
 
-                Ok(Box::new(WalkTheDog {
-                    machine: Some(machine),
-                }))
+        scroll_backgrounds(&mut self.backgrounds, walking_speed);
+        if let Some(fade) = self.theme_fade.as_mut() {
+            scroll_backgrounds(&mut fade.backgrounds, walking_speed);
+            fade.frames_left = fade.frames_left.saturating_sub(1);
+        }
+        if matches!(&self.theme_fade, Some(fade) if fade.frames_left == 0) {
+            self.theme_fade = None;
+        }
+
+        self.obstacles.retain(|obstacle| obstacle.right() > 0);
+        let obstacles = &self.obstacles;
+        self.passed_obstacles
+            .retain(|id| obstacles.iter().any(|obstacle| obstacle.id() == *id));
+
+        // 右端が少年の左端より左に来た障害物を、一度だけ「抜けた」と数える
+        let boy_left = self.boy.bounding_box().x();
+        let mut passed = vec![];
+        self.obstacles.iter_mut().for_each(|obstacle| {
+            obstacle.move_horizontally(walking_speed);
+            obstacle.update();
+        });
+        // 今フレームも足場に着地していれば、また乗せ直される
+        self.boy.ride_conveyor(0);
+        if self.boy.is_magnetized() {
+            let center = self.boy.center();
+            self.obstacles
+                .iter_mut()
+                .for_each(|obstacle| obstacle.attract(&center));
+        }
+        let boy_box = self.boy.bounding_box();
+        // 無敵モード中は当たり判定そのものをしない
+        let candidates = if self.god_mode {
+            vec![]
+        } else {
+            broadphase(&self.obstacles, boy_box.x(), boy_box.right())
+        };
+        self.last_collision = None;
+        for index in candidates {
+            let obstacle = &mut self.obstacles[index];
+            if let Some(hit) = obstacle.check_intersection(&mut self.boy) {
+                // 足場に乗っただけのときは、当たりとして残さない
+                let landed =
+                    obstacle.category() == ObstacleCategory::Platform && !self.boy.knocked_down();
+                if !landed {
+                    self.last_collision = Some(hit);
+                }
+            }
+        }
+        // 倒した敵と取ったアイテムはその場で取り除く
+        // 敵は抜けたときと同じように、アイテムはコインとして点にする
+        let (removed, obstacles): (Vec<_>, Vec<_>) = std::mem::take(&mut self.obstacles)
+            .into_iter()
+            .partition(|obstacle| obstacle.defeated() || obstacle.collected());
+        self.obstacles = obstacles;
+        let (defeated, collected): (Vec<_>, Vec<_>) =
+            removed.iter().partition(|obstacle| obstacle.defeated());
+        passed.extend(defeated.iter().map(|enemy| enemy.score_value()));
+        let coins: Vec<i32> = collected
+            .iter()
+            .map(|item| item.score_value())
+            .filter(|score_value| *score_value > 0)
+            .collect();
+        for obstacle in self.obstacles.iter() {
+            if obstacle.right() < boy_left && !self.passed_obstacles.contains(&obstacle.id()) {
+                self.passed_obstacles.push(obstacle.id());
+                passed.push(obstacle.score_value());
+            }
+        }
+
+        if self.knocked_down() {
+            if self.combo > 0 {
+                self.break_combo();
+            }
+            self.combo = 0;
+            self.score = self.score_model.on_hit();
+        } else {
+            passed
+                .into_iter()
+                .for_each(|score_value| self.pass_obstacle(score_value));
+            for score_value in coins {
+                self.score = self.score_model.on_coin(score_value);
+                self.count(|stats| stats.coins += 1);
             }
+        }
 
-            Some(_) => Err(anyhow!("Error: Game is already initialized!")),
+        // retainで画面外の障害物を消した後の数で判断する
+        // ゴールが生成済みの範囲に入ったら、もう生成せずにゴールの線を置く
+        let finish_in_range = self
+            .remaining_distance()
+            .filter(|remaining| *remaining <= i32::from(self.timeline) - i32::from(boy_left));
+        if let Some(remaining) = finish_in_range {
+            if !self.finish_line_placed {
+                self.finish_line_placed = true;
+                self.obstacles.push(Box::new(FinishLine::new(
+                    boy_left + remaining.max(0) as i16,
+                )));
+            }
+            self.timeline += walking_speed;
+        } else if self.checkpoint_pause > 0 {
+            // 止めている間に、次のセグメントが画面の中に湧かないようにする
+            self.timeline = (self.timeline + walking_speed).max(WIDTH);
+        } else if self.timeline < self.current_difficulty().timeline_minimum
+            && self.obstacles.len() < MAX_ACTIVE_OBSTACLES
+            && self.distance >= i32::from(self.difficulty.grace_distance)
+        {
+            self.generate_next_segment();
+        } else {
+            self.timeline += walking_speed;
         }
     }
 
-    fn update(&mut self, keystate: &KeyState) {
-        if let Some(machine) = self.machine.take() {
-            self.machine.replace(machine.update(keystate));
+    fn break_combo(&mut self) {
+        self.combo_break_frames = COMBO_BREAK_FLASH_FRAMES;
+        if let Err(err) = self.audio.play_sound(&self.combo_lost_sound) {
+            log_warn!("Error playing combo lost sound {:#?}", err);
         }
-        assert!(self.machine.is_some());
     }
 
-    fn draw(&self, renderer: &Renderer) {
-        renderer.clear(&Rect::new_from_x_y(0, 0, 600, HEIGHT));
+    // 倒れている間も、光るのは決まったフレーム数だけ
+    fn update_combo_flash(&mut self) {
+        self.combo_break_frames = self.combo_break_frames.saturating_sub(1);
+    }
 
-        if let Some(machine) = &self.machine {
-            machine.draw(renderer);
+    // 倒れた瞬間に一度だけ揺らし始める
+    fn update_shake(&mut self, was_knocked_down: bool) {
+        if !was_knocked_down && self.knocked_down() {
+            self.shake_frames = SHAKE_FRAMES;
+        } else {
+            self.shake_frames = self.shake_frames.saturating_sub(1);
         }
     }
-}
 
-fn rightmost(obstacle_list: &Vec<Box<dyn Obstacle>>) -> i16 {
-    obstacle_list
-        .iter()
-        .map(|obstacle| obstacle.right())
-        .max_by(|x, y| x.cmp(&y))
-        .unwrap_or(0)
-}
+    // 倒れた瞬間の1フレームだけ画面を赤く光らせる
+    fn damage_flashing(&self) -> bool {
+        self.shake_frames == SHAKE_FRAMES
+    }
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashMap;
+    fn shake_offset(&self) -> Point {
+        if self.shake_frames == 0 {
+            return Point::default();
+        }
 
-    use futures::channel::mpsc::unbounded;
-    use wasm_bindgen_test::wasm_bindgen_test;
-    use web_sys::{AudioBuffer, AudioBufferOptions};
+        let intensity = SHAKE_INTENSITY * self.shake_frames as i16 / SHAKE_FRAMES as i16;
+        let mut rng = thread_rng();
+        Point {
+            x: rng.gen_range(-intensity..=intensity),
+            y: rng.gen_range(-intensity..=intensity),
+        }
+    }
 
-    use crate::browser;
+    fn draw_outlines(&self, renderer: &Renderer, scroll_offset: &Point) {
+        renderer.stroke_rect(
+            &self.boy.bounding_box(),
+            self.settings.palette.color(ColorRole::Boy),
+            HIGH_CONTRAST_LINE_WIDTH,
+        );
+        renderer.with_offset(scroll_offset, |renderer| {
+            self.obstacles.iter().for_each(|obstacle| {
+                let color = self
+                    .settings
+                    .palette
+                    .color(ColorRole::Obstacle(obstacle.category()));
+                obstacle.outlines().iter().for_each(|outline| {
+                    renderer.stroke_rect(outline, color, HIGH_CONTRAST_LINE_WIDTH)
+                });
+            });
+        });
+    }
 
-    use super::*;
+    // 背景と障害物は前回の更新でvelocityだけ動いたので、その手前まで戻して描く
+    fn scroll_offset(&self, alpha: f32) -> Point {
+        Point {
+            x: -self.velocity(),
+            y: 0,
+        }
+        .lerp(&Point::default(), alpha)
+    }
+
+    // 今のテーマを敷いてから、切り替わり中なら前のテーマを薄くして重ねる
+    fn draw_backgrounds(&self, renderer: &Renderer) {
+        self.backgrounds.iter().for_each(|background| {
+            background.draw(renderer);
+        });
+        if let Some(fade) = &self.theme_fade {
+            renderer.with_alpha(fade.alpha(), |renderer| {
+                fade.backgrounds.iter().for_each(|background| {
+                    background.draw(renderer);
+                });
+            });
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer, alpha: f32) {
+        let scroll_offset = self.scroll_offset(alpha);
+        renderer.with_offset(&self.shake_offset(), |renderer| {
+            renderer.with_offset(&scroll_offset, |renderer| {
+                self.draw_backgrounds(renderer);
+            });
+            if self.settings.high_contrast {
+                renderer.fill_rect(
+                    &Rect::new_from_x_y(0, 0, WIDTH, HEIGHT),
+                    HIGH_CONTRAST_DIM_COLOR,
+                );
+            }
+            self.draw_ghost(renderer);
+            self.boy.draw(renderer, alpha, self.settings.palette);
+
+            renderer.with_offset(&scroll_offset, |renderer| {
+                self.obstacles.iter().for_each(|obstacle| {
+                    obstacle.draw(renderer);
+                });
+            });
+            if self.settings.high_contrast {
+                self.draw_outlines(renderer, &scroll_offset);
+            }
+            if self.settings.debug {
+                if let Some(hit) = &self.last_collision {
+                    renderer.fill_rect(hit, self.settings.palette.color(ColorRole::Hit));
+                }
+            }
+        });
+
+        let night_alpha = night_alpha(self.distance);
+        if night_alpha > 0.0 {
+            renderer.fill_rect(
+                &Rect::new_from_x_y(0, 0, WIDTH, HEIGHT),
+                &format!("rgba(10, 20, 70, {})", night_alpha),
+            );
+        }
+
+        if self.damage_flashing() {
+            renderer.clear_with_color(
+                &Rect::new_from_x_y(0, 0, WIDTH, HEIGHT),
+                self.settings.palette.color(ColorRole::DamageFlash),
+            );
+        } else if self.combo_break_frames > 0 {
+            // 上から薄く重ねるだけなので、やられるアニメーションは透けて見える
+            renderer.fill_rect(
+                &Rect::new_from_x_y(0, 0, WIDTH, HEIGHT),
+                self.settings.palette.color(ColorRole::ComboBreakFlash),
+            );
+        }
+
+        self.draw_score(renderer);
+        renderer.draw_bar(
+            &Rect::new_from_x_y(10, 40, 100, 8),
+            self.boy.dash_charge(),
+            self.settings.palette.color(ColorRole::DashMeter),
+            DASH_METER_BACKGROUND,
+        );
+        self.notifications.draw(renderer);
+    }
+
+    // ゴーストは距離で同期しているので、横位置は少年と同じになる
+    fn draw_ghost(&self, renderer: &Renderer) {
+        let frame = match self
+            .best_run
+            .as_ref()
+            .and_then(|best| best.at(self.distance))
+        {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        if let Some(sprite) = self.boy.sprite(&frame.sprite) {
+            let position = Point {
+                x: self.boy.position().x,
+                y: frame.y,
+            };
+            renderer.with_alpha(GHOST_ALPHA, |renderer| {
+                self.boy.draw_sprite(renderer, sprite, position)
+            });
+        }
+    }
+
+    fn draw_score(&self, renderer: &Renderer) {
+        let mut text = format!("Score {}", self.score);
+        if self.combo > 0 {
+            text = format!("{}  Combo x{}", text, self.combo_multiplier());
+        }
+        if self.boy.is_invincible() {
+            text = format!("{}  Practice", text);
+        }
+
+        if let Err(err) = renderer.draw_text(&text, &Point { x: 10, y: 30 }) {
+            error!("Could not draw text {:#?}", err);
+        }
+    }
+
+    // コンベアの速さは、前のフレームで着地した足場のものが乗る
+    fn velocity(&self) -> i16 {
+        let conveyor_speed = self.boy.conveyor_speed();
+        match (self.speed_override, self.boy.walking_speed()) {
+            // 止まっているときは止まったままにする
+            (Some(speed), walking_speed) if walking_speed != 0 => -(speed + conveyor_speed),
+            (_, walking_speed) => -(walking_speed + conveyor_speed),
+        }
+    }
+
+    fn pass_obstacle(&mut self, score_value: i32) {
+        self.score = self
+            .score_model
+            .on_obstacle_passed(score_value * self.combo_multiplier());
+        self.combo += 1;
+        if self.combo + 1 == MAX_COMBO_MULTIPLIER {
+            self.notifications
+                .push(format!("Combo x{}!", MAX_COMBO_MULTIPLIER));
+        }
+    }
+
+    fn combo_multiplier(&self) -> i32 {
+        (1 + self.combo).min(MAX_COMBO_MULTIPLIER)
+    }
+
+    // 乱数を複製して引くので、実際の生成の順番は変わらない
+    pub fn peek_next_segment(&self) -> SegmentKind {
+        self.segment_source.pick(
+            self.segment_index,
+            &mut self.rng.clone(),
+            self.segments.len(),
+        )
+    }
+
+    fn build_segment(&self, kind: SegmentKind, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
+        match kind {
+            SegmentKind::StoneAndPlatform => {
+                stone_and_platform(self.stone.clone(), self.obstacle_sheet.clone(), offset_x, 0)
+            }
+            SegmentKind::PlatformAndStone => {
+                platform_and_stone(self.stone.clone(), self.obstacle_sheet.clone(), offset_x, 0)
+            }
+            SegmentKind::LowBarrier => low_barrier(self.stone.clone(), offset_x),
+            SegmentKind::Loaded(index) => load_segment(
+                &self.segments[index],
+                self.stone.clone(),
+                self.obstacle_sheet.clone(),
+                &self.props,
+                offset_x,
+            ),
+        }
+    }
+
+    fn generate_next_segment(&mut self) {
+        let offset_x = self.timeline + self.current_difficulty().obstacle_buffer;
+        let kind = self
+            .segment_source
+            .pick(self.segment_index, &mut self.rng, self.segments.len());
+        self.segment_index += 1;
+        let mut next_obstacles = self.build_segment(kind, offset_x);
+
+        if self.difficulty.crumbling_platforms {
+            next_obstacles
+                .iter_mut()
+                .for_each(|obstacle| obstacle.make_crumbling());
+        }
+        if let Some(on_segment_spawned) = self.on_segment_spawned.as_mut() {
+            on_segment_spawned(kind, next_obstacles.len());
+        }
+        self.timeline = rightmost(&next_obstacles);
+        self.obstacles.append(&mut next_obstacles);
+        self.change_theme((self.theme + 1) % self.themes.len());
+    }
+
+    // 背景を次のテーマに張り替え、前のテーマはTHEME_FADE_FRAMESかけて消していく
+    fn change_theme(&mut self, theme: usize) {
+        if theme == self.theme {
+            return;
+        }
+        let previous = self.theme;
+        let fading = [
+            Image::new(
+                self.themes[previous].clone(),
+                self.backgrounds[0].bounding_box().position,
+            ),
+            Image::new(
+                self.themes[previous].clone(),
+                self.backgrounds[1].bounding_box().position,
+            ),
+        ];
+        self.theme_fade = Some(ThemeFade {
+            backgrounds: fading,
+            frames_left: THEME_FADE_FRAMES,
+        });
+
+        self.theme = theme;
+        let [bg_fst, bg_snd] = &mut self.backgrounds;
+        bg_fst.set_element(self.themes[theme].clone());
+        bg_snd.set_element(self.themes[theme].clone());
+        // 幅が変わっても隙間ができないよう、左にある方に右の方をつなげ直す
+        if bg_fst.bounding_box().x() <= bg_snd.bounding_box().x() {
+            bg_snd.set_x(bg_fst.right());
+        } else {
+            bg_fst.set_x(bg_snd.right());
+        }
+    }
+}
+
+// テーマの切り替わりで消えていく前のテーマの背景
+struct ThemeFade {
+    backgrounds: [Image; 2],
+    frames_left: u8,
+}
+
+impl ThemeFade {
+    fn alpha(&self) -> f64 {
+        self.frames_left as f64 / THEME_FADE_FRAMES as f64
+    }
+}
+
+// 2枚の背景をスクロールさせ、画面外に出た方をもう一方の右に回す
+fn scroll_backgrounds(backgrounds: &mut [Image; 2], distance: i16) {
+    let [bg_fst, bg_snd] = backgrounds;
+    bg_fst.move_horizontally(distance);
+    bg_snd.move_horizontally(distance);
+    if bg_fst.right() < 0 {
+        bg_fst.set_x(bg_snd.right());
+    }
+    if bg_snd.right() < 0 {
+        bg_snd.set_x(bg_fst.right());
+    }
+}
+
+// generate_next_segmentが作るセグメントの種類
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SegmentKind {
+    StoneAndPlatform,
+    PlatformAndStone,
+    LowBarrier,
+    // segments.jsonのindex番目
+    Loaded(usize),
+}
+
+impl SegmentKind {
+    fn pick(rng: &mut StdRng, loaded: usize) -> Self {
+        SegmentKind::from_index(rng.gen_range(0..BUILTIN_SEGMENTS + loaded))
+    }
+
+    // 組み込みのセグメントが先、segments.jsonのものがその後に続く通し番号
+    fn from_index(index: usize) -> Self {
+        match index {
+            0 => SegmentKind::StoneAndPlatform,
+            1 => SegmentKind::PlatformAndStone,
+            2 => SegmentKind::LowBarrier,
+            index => SegmentKind::Loaded(index - BUILTIN_SEGMENTS),
+        }
+    }
+
+    fn index(self) -> usize {
+        match self {
+            SegmentKind::StoneAndPlatform => 0,
+            SegmentKind::PlatformAndStone => 1,
+            SegmentKind::LowBarrier => 2,
+            SegmentKind::Loaded(index) => BUILTIN_SEGMENTS + index,
+        }
+    }
+}
+
+// 次に生成するセグメントの選び方
+#[derive(Debug, PartialEq)]
+pub enum SegmentSource {
+    Random,
+    // 乱数を使わず、並べた順に繰り返す。走り同士を同じ条件で比べられる
+    Sequence(Vec<SegmentKind>),
+}
+
+impl SegmentSource {
+    // ?sequence=0,2,1 のような通し番号の並び。使える番号が一つもなければ乱数で選ぶ
+    fn from_indices(indices: &str, loaded: usize) -> Self {
+        let kinds: Vec<SegmentKind> = indices
+            .split(',')
+            .filter_map(|index| index.trim().parse().ok())
+            .filter(|index| *index < BUILTIN_SEGMENTS + loaded)
+            .map(SegmentKind::from_index)
+            .collect();
+        if kinds.is_empty() {
+            SegmentSource::Random
+        } else {
+            SegmentSource::Sequence(kinds)
+        }
+    }
+
+    // 決まった順に並べているときの、from_indicesに渡せる通し番号
+    fn indices(&self) -> Option<Vec<usize>> {
+        match self {
+            SegmentSource::Random => None,
+            SegmentSource::Sequence(kinds) => Some(kinds.iter().map(|kind| kind.index()).collect()),
+        }
+    }
+
+    // index番目に生成するセグメント
+    fn pick(&self, index: usize, rng: &mut StdRng, loaded: usize) -> SegmentKind {
+        match self {
+            SegmentSource::Sequence(kinds) if !kinds.is_empty() => kinds[index % kinds.len()],
+            _ => SegmentKind::pick(rng, loaded),
+        }
+    }
+}
+
+// 遊び方ごとに点の付け方を差し替えられるようにする
+// どのメソッドも、呼ばれた後の点を返す
+trait ScoreModel {
+    // 毎フレーム、そこまでの走行距離（ピクセル）を渡される
+    fn on_frame(&mut self, distance: i32) -> i32;
+    fn on_coin(&mut self, score_value: i32) -> i32;
+    // score_valueにはコンボの倍率がかかっている
+    fn on_obstacle_passed(&mut self, score_value: i32) -> i32;
+    // 倒れている間は毎フレーム呼ばれる
+    fn on_hit(&mut self) -> i32;
+    fn reset(&mut self);
+}
+
+// 今までどおり、抜けた障害物とコインの点を足していく
+#[derive(Default)]
+struct ObstacleScore {
+    score: i32,
+}
+
+impl ScoreModel for ObstacleScore {
+    fn on_frame(&mut self, _distance: i32) -> i32 {
+        self.score
+    }
+
+    fn on_coin(&mut self, score_value: i32) -> i32 {
+        self.score += score_value;
+        self.score
+    }
+
+    fn on_obstacle_passed(&mut self, score_value: i32) -> i32 {
+        self.score += score_value;
+        self.score
+    }
+
+    fn on_hit(&mut self) -> i32 {
+        self.score
+    }
+
+    fn reset(&mut self) {
+        self.score = 0;
+    }
+}
+
+// コイン集め用。コインだけを、重みをかけて数える
+const COIN_WEIGHT: i32 = 10;
+
+#[derive(Default)]
+struct CoinScore {
+    score: i32,
+}
+
+impl ScoreModel for CoinScore {
+    fn on_frame(&mut self, _distance: i32) -> i32 {
+        self.score
+    }
+
+    fn on_coin(&mut self, score_value: i32) -> i32 {
+        self.score += score_value * COIN_WEIGHT;
+        self.score
+    }
+
+    fn on_obstacle_passed(&mut self, _score_value: i32) -> i32 {
+        self.score
+    }
+
+    fn on_hit(&mut self) -> i32 {
+        self.score
+    }
+
+    fn reset(&mut self) {
+        self.score = 0;
+    }
+}
+
+// URLパラメータ（?scoring=coins）で点の付け方を選ぶ。その回だけで、設定としては保存しない
+fn score_model_from_name(name: Option<&str>) -> Box<dyn ScoreModel> {
+    match name.map(|name| name.to_ascii_lowercase()).as_deref() {
+        Some("coins") => Box::new(CoinScore::default()),
+        _ => Box::new(ObstacleScore::default()),
+    }
+}
+
+const SETTINGS_KEY: &str = "walk_the_dog_settings";
+
+// 遊び方の設定。メニューで切り替えるとlocalStorageに保存して、次に開いたときもそのまま使う
+// 古い保存データに無い項目はデフォルトで埋める
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct Settings {
+    difficulty: Difficulty,
+    music: bool,
+    debug: bool,
+    respawn: bool,
+    // 少年と障害物をはっきりした色で縁取り、背景を暗くする
+    high_contrast: bool,
+    palette: Palette,
+    // ジャンプを押しっぱなしで着地したら、すぐに跳び直す
+    auto_hop: bool,
+    // 練習モード。ぶつかっても倒れず、記録にも残らない
+    // ?practice=1 で開いたときだけのもので、保存しない。以前に保存されたものも読まない
+    #[serde(skip)]
+    invincible: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            difficulty: Difficulty::Normal,
+            music: false,
+            debug: false,
+            respawn: false,
+            high_contrast: false,
+            palette: Palette::Standard,
+            auto_hop: false,
+            invincible: false,
+        }
+    }
+}
+
+impl Settings {
+    fn load() -> Result<Self> {
+        match browser::load_item(SETTINGS_KEY)? {
+            Some(json) => js_sys::JSON::parse(&json)
+                .map_err(|err| anyhow!("Could not parse settings {:#?}", err))?
+                .into_serde()
+                .context("Could not deserialize settings"),
+            None => Ok(Settings::default()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let json: String = js_sys::JSON::stringify(&JsValue::from_serde(self)?)
+            .map_err(|err| anyhow!("Could not stringify settings {:#?}", err))?
+            .into();
+        browser::save_item(SETTINGS_KEY, &json)
+    }
+
+    // メニューに出す、キーで切り替えられる設定の今の値
+    fn menu_lines(&self) -> Vec<String> {
+        let on_off = |on: bool| if on { "on" } else { "off" };
+        vec![
+            format!("C  High contrast: {}", on_off(self.high_contrast)),
+            format!(
+                "B  Colorblind palette: {}",
+                on_off(self.palette == Palette::Colorblind)
+            ),
+            format!("H  Auto-hop: {}", on_off(self.auto_hop)),
+        ]
+    }
+
+    // URLパラメータ（?difficulty=hard&music=on&debug=1&respawn=1&contrast=high&palette=colorblind&autohop=on&practice=1）で指定された項目だけ上書きする
+    fn with_query_params(self) -> Result<Self> {
+        let mut settings = self;
+        if let Some(difficulty) =
+            browser::query_param("difficulty")?.and_then(|name| Difficulty::from_name(&name))
+        {
+            settings.difficulty = difficulty;
+        }
+        if let Some(music) = browser::query_param("music")? {
+            settings.music = music == "on";
+        }
+        if let Some(debug) = browser::query_param("debug")? {
+            settings.debug = debug == "1";
+        }
+        if let Some(respawn) = browser::query_param("respawn")? {
+            settings.respawn = respawn == "1";
+        }
+        if let Some(contrast) = browser::query_param("contrast")? {
+            settings.high_contrast = contrast == "high";
+        }
+        if let Some(palette) =
+            browser::query_param("palette")?.and_then(|name| Palette::from_name(&name))
+        {
+            settings.palette = palette;
+        }
+        if let Some(auto_hop) = browser::query_param("autohop")? {
+            settings.auto_hop = auto_hop == "on";
+        }
+        if let Some(practice) = browser::query_param("practice")? {
+            settings.invincible = practice == "1";
+        }
+        Ok(settings)
+    }
+}
+
+// メニューでキーを押すと切り替わる設定
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum SettingToggle {
+    Contrast,
+    Palette,
+    AutoHop,
+}
+
+impl SettingToggle {
+    fn from_code(code: &str) -> Option<Self> {
+        match code {
+            "KeyC" => Some(SettingToggle::Contrast),
+            "KeyB" => Some(SettingToggle::Palette),
+            "KeyH" => Some(SettingToggle::AutoHop),
+            _ => None,
+        }
+    }
+
+    // 今効いている値を切り替え、その値だけを保存する設定にも写す
+    // URLパラメータで上書きしている他の項目は、保存する設定に混ざらない
+    fn apply(self, settings: &mut Settings, saved: &mut Settings) {
+        match self {
+            SettingToggle::Contrast => {
+                settings.high_contrast = !settings.high_contrast;
+                saved.high_contrast = settings.high_contrast;
+            }
+            SettingToggle::Palette => {
+                settings.palette = settings.palette.toggled();
+                saved.palette = settings.palette;
+            }
+            SettingToggle::AutoHop => {
+                settings.auto_hop = !settings.auto_hop;
+                saved.auto_hop = settings.auto_hop;
+            }
+        }
+    }
+}
+
+const LIFETIME_STATS_KEY: &str = "walk_the_dog_lifetime_stats";
+
+// 遊んだ記録の累計
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug, PartialEq)]
+struct Stats {
+    jumps: u32,
+    slides: u32,
+    // メートル
+    distance: u32,
+    deaths: u32,
+    // コインより前に保存された累計には無いので、無ければ0から数える
+    #[serde(default)]
+    coins: u32,
+}
+
+impl Stats {
+    fn load_lifetime() -> Result<Self> {
+        match browser::load_item(LIFETIME_STATS_KEY)? {
+            Some(json) => js_sys::JSON::parse(&json)
+                .map_err(|err| anyhow!("Could not parse stats {:#?}", err))?
+                .into_serde()
+                .context("Could not deserialize stats"),
+            None => Ok(Stats::default()),
+        }
+    }
+
+    fn save(&self) -> Result<()> {
+        let json: String = js_sys::JSON::stringify(&JsValue::from_serde(self)?)
+            .map_err(|err| anyhow!("Could not stringify stats {:#?}", err))?
+            .into();
+        browser::save_item(LIFETIME_STATS_KEY, &json)
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} jumps  {} slides  {}m  {} deaths  {} coins",
+            self.jumps, self.slides, self.distance, self.deaths, self.coins
+        )
+    }
+}
+
+// メニューに出すランキングの行数
+const LEADERBOARD_ROWS: usize = 10;
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+struct LeaderboardEntry {
+    name: String,
+    score: i32,
+}
+
+// 読み込みは待たずに進めるので、結果は共有したセルに入れる
+// 最後に取れた上位を覚えておき、取れなかったときはそれを出し続ける
+#[derive(Default)]
+struct Leaderboard {
+    url: Option<String>,
+    entries: Rc<RefCell<Option<Vec<LeaderboardEntry>>>>,
+    unavailable: Rc<std::cell::Cell<bool>>,
+}
+
+impl Leaderboard {
+    fn new(url: Option<String>) -> Self {
+        Leaderboard {
+            url,
+            ..Leaderboard::default()
+        }
+    }
+
+    fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    fn refresh(&self) {
+        let url = match &self.url {
+            Some(url) => url.clone(),
+            None => return,
+        };
+        let entries = self.entries.clone();
+        let unavailable = self.unavailable.clone();
+        browser::spawn_local(async move {
+            match engine::load_json::<Vec<LeaderboardEntry>>(&url).await {
+                Ok(fetched) => {
+                    *entries.borrow_mut() = Some(top_entries(fetched));
+                    unavailable.set(false);
+                }
+                Err(err) => {
+                    log_warn!("Could not load leaderboard {:#?}", err);
+                    unavailable.set(true);
+                }
+            }
+        });
+    }
+
+    fn rows(&self) -> Vec<String> {
+        match self.entries.borrow().as_ref() {
+            Some(entries) => entries
+                .iter()
+                .enumerate()
+                .map(|(index, entry)| format!("{}. {} {}", index + 1, entry.name, entry.score))
+                .collect(),
+            None if self.unavailable.get() => vec!["Leaderboard unavailable.".to_string()],
+            None => vec!["Loading leaderboard...".to_string()],
+        }
+    }
+
+    // URLが無ければ何も出さない
+    fn draw(&self, renderer: &Renderer) {
+        if self.url.is_none() {
+            return;
+        }
+        for (index, row) in self.rows().iter().enumerate() {
+            let location = Point {
+                x: 20,
+                y: 300 + index as i16 * 24,
+            };
+            if let Err(err) = renderer.draw_text(row, &location) {
+                error!("Could not draw leaderboard {:#?}", err);
+            }
+        }
+    }
+}
+
+// 点の高い順に上位だけを残す
+fn top_entries(mut entries: Vec<LeaderboardEntry>) -> Vec<LeaderboardEntry> {
+    entries.sort_by_key(|entry| std::cmp::Reverse(entry.score));
+    entries.truncate(LEADERBOARD_ROWS);
+    entries
+}
+
+// ゲームオーバーのときにランキングへ送る内容。seedかsequenceがあれば同じ並びで走ったかが分かる
+#[derive(Serialize)]
+struct ScoreSubmission {
+    score: i32,
+    // メートル
+    distance: u32,
+    // JSONの数値では2^53を超えると丸められるので、文字列で送る
+    seed: Option<String>,
+    // ?sequence= で並べたセグメントの通し番号
+    sequence: Option<Vec<usize>>,
+    stats: Stats,
+}
+
+impl ScoreSubmission {
+    fn to_json(&self) -> Result<String> {
+        Ok(js_sys::JSON::stringify(&JsValue::from_serde(self)?)
+            .map_err(|err| anyhow!("Could not stringify score {:#?}", err))?
+            .into())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GhostFrame {
+    distance: i32,
+    y: i16,
+    sprite: String,
+}
+
+// Lキーで書き出す入力の記録の長さ。60fpsで2秒分
+const INPUT_LOG_FRAMES: usize = 120;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct InputFrame {
+    // 記録を始めてからの通し番号
+    frame: u32,
+    actions: Vec<Action>,
+    // 入力を処理した後の少年の状態
+    state: String,
+}
+
+// 直近capacityフレームの入力だけを残すリングバッファ
+struct InputLog {
+    frames: VecDeque<InputFrame>,
+    capacity: usize,
+    next_frame: u32,
+}
+
+impl InputLog {
+    fn new(capacity: usize) -> Self {
+        InputLog {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            next_frame: 0,
+        }
+    }
+
+    fn record(&mut self, actions: Vec<Action>, state: &str) {
+        if self.frames.len() == self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(InputFrame {
+            frame: self.next_frame,
+            actions,
+            state: state.to_string(),
+        });
+        self.next_frame += 1;
+    }
+
+    fn to_json(&self) -> Result<String> {
+        Ok(js_sys::JSON::stringify(&JsValue::from_serde(&self.frames)?)
+            .map_err(|err| anyhow!("Could not stringify input log {:#?}", err))?
+            .into())
+    }
+}
+
+// 1フレームごとの少年の高さとスプライトを、走行距離と一緒に記録したもの
+#[derive(Serialize, Deserialize, Clone, Default)]
+pub struct GhostTrack {
+    frames: Vec<GhostFrame>,
+}
+
+impl GhostTrack {
+    fn record(&mut self, distance: i32, y: i16, sprite: String) {
+        self.frames.push(GhostFrame {
+            distance,
+            y,
+            sprite,
+        });
+    }
+
+    fn distance(&self) -> i32 {
+        self.frames.last().map_or(0, |frame| frame.distance)
+    }
+
+    // 指定した距離の時点のフレーム。記録が終わった先（ゴーストが倒れた後）ではNone
+    fn at(&self, distance: i32) -> Option<&GhostFrame> {
+        if distance > self.distance() {
+            return None;
+        }
+        let index = self
+            .frames
+            .partition_point(|frame| frame.distance <= distance);
+        index.checked_sub(1).map(|index| &self.frames[index])
+    }
+}
+
+// 衝突判定の前に、x方向でざっくり絞り込むときのバケツの幅
+const BROADPHASE_BUCKET_WIDTH: i16 = 128;
+
+// x方向の範囲[left, right]に重なりうる障害物の添字を、元の並び順で返す
+// 障害物をBROADPHASE_BUCKET_WIDTHごとのバケツに振り分け、範囲がかかるバケツの中身だけを候補にする
+fn broadphase(obstacles: &[Box<dyn Obstacle>], left: i16, right: i16) -> Vec<usize> {
+    let bucket = |x: i16| x.div_euclid(BROADPHASE_BUCKET_WIDTH);
+    let mut buckets: HashMap<i16, Vec<usize>> = HashMap::new();
+    for (index, obstacle) in obstacles.iter().enumerate() {
+        for key in bucket(obstacle.left())..=bucket(obstacle.right()) {
+            buckets.entry(key).or_default().push(index);
+        }
+    }
+
+    let mut candidates: Vec<usize> = (bucket(left)..=bucket(right))
+        .filter_map(|key| buckets.get(&key))
+        .flatten()
+        .copied()
+        .collect();
+    candidates.sort_unstable();
+    candidates.dedup();
+    candidates
+}
+
+#[derive(Debug, PartialEq)]
+enum DebugCommand {
+    SpawnStone,
+    SetSpeed(i16),
+    Kill,
+    God,
+    // 次に出てくるセグメントを通知に出す
+    Peek,
+}
+
+// "set_speed 8" のような一行を解釈する。知らないコマンドや引数の過不足はNone
+fn parse_command(input: &str) -> Option<DebugCommand> {
+    let mut words = input.split_whitespace();
+    let command = match (words.next()?, words.next()) {
+        ("spawn_stone", None) => DebugCommand::SpawnStone,
+        ("set_speed", Some(speed)) => DebugCommand::SetSpeed(speed.parse().ok()?),
+        ("kill", None) => DebugCommand::Kill,
+        ("god", None) => DebugCommand::God,
+        ("peek", None) => DebugCommand::Peek,
+        _ => return None,
+    };
+    match words.next() {
+        Some(_) => None,
+        None => Some(command),
+    }
+}
+
+// 開発用に、画面下の入力欄から打ったコマンドを受け取る
+struct DebugConsole {
+    submitted: UnboundedReceiver<String>,
+}
+
+impl DebugConsole {
+    fn new() -> Result<Self> {
+        let input = browser::create_text_input("debug_console")?;
+        Ok(DebugConsole {
+            submitted: engine::add_submit_handler(input),
+        })
+    }
+
+    // 前回から打たれたコマンドをすべて取り出す
+    fn commands(&mut self) -> Vec<DebugCommand> {
+        let mut commands = vec![];
+        while let Ok(Some(line)) = self.submitted.try_next() {
+            match parse_command(&line) {
+                Some(command) => commands.push(command),
+                None => log!("Unknown debug command: {}", line),
+            }
+        }
+        commands
+    }
+}
+
+// ループで流すBGMと、いま鳴っているそのハンドル
+struct Music {
+    audio: Audio,
+    sound: Sound,
+    handle: SoundHandle,
+}
+
+impl Music {
+    fn play(audio: Audio, sound: Sound) -> Result<Self> {
+        let handle = audio.play_looping_sound(&sound)?;
+        Ok(Music {
+            audio,
+            sound,
+            handle,
+        })
+    }
+
+    fn pause(&mut self) -> Result<()> {
+        self.audio.pause(&mut self.handle)
+    }
+
+    fn resume(&mut self) -> Result<()> {
+        self.audio.resume(&self.sound, &mut self.handle)
+    }
+}
+
+// 起動時に読み込む素材。読み込みにも、ゲージの分母にもこの名前を使う
+const RHB_JSON: &str = "rhb.json";
+const RHB_IMAGE: &str = "rhb.png";
+const STONE_IMAGE: &str = "Stone.png";
+const TILES_JSON: &str = "tiles.json";
+const TILES_IMAGE: &str = "tiles.png";
+const JUMP_SOUND: &str = "SFX_Jump_23.mp3";
+const SEGMENTS_JSON: &str = "segments.json";
+const STARTUP_ASSETS: [&str; 7] = [
+    RHB_JSON,
+    RHB_IMAGE,
+    STONE_IMAGE,
+    TILES_JSON,
+    TILES_IMAGE,
+    JUMP_SOUND,
+    SEGMENTS_JSON,
+];
+// segments.jsonで置く小物の画像。PropImagesのフィールドと同じ並び
+const PROP_IMAGES: [&str; 5] = [
+    "Spring.png",
+    "Dog.png",
+    "Shield.png",
+    "Magnet.png",
+    "Coin.png",
+];
+const LOADING_ASSETS: usize = STARTUP_ASSETS.len() + PROP_IMAGES.len() + BACKGROUND_THEMES.len();
+
+// 素材を読み込んでいる間、いくつ読み終わったかをゲージで見せる
+struct Loading {
+    loaded: std::cell::Cell<usize>,
+    total: usize,
+}
+
+impl Loading {
+    fn new(total: usize) -> Self {
+        Loading {
+            loaded: std::cell::Cell::new(0),
+            total,
+        }
+    }
+
+    fn progress(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.loaded.get() as f64 / self.total as f64
+        }
+    }
+
+    // 素材の読み込みを待ち、終わったら進み具合を描き直す
+    // 同時に読み込めるよう、&selfのまま数える
+    async fn track<T>(
+        &self,
+        renderer: &Renderer,
+        name: &str,
+        asset: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let asset = asset
+            .await
+            .with_context(|| format!("Could not load {}", name))?;
+        self.loaded.set(self.loaded.get() + 1);
+        self.draw(renderer);
+        Ok(asset)
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        renderer.clear(&Rect::new_from_x_y(0, 0, WIDTH, HEIGHT));
+        if let Err(err) = renderer.draw_text("Loading...", &Point { x: 240, y: 280 }) {
+            error!("Could not draw text {:#?}", err);
+        }
+        renderer.draw_bar(
+            &Rect::new_from_x_y(150, 300, 300, 16),
+            self.progress(),
+            LOADING_BAR_COLOR,
+            LOADING_BAR_BACKGROUND,
+        );
+    }
+}
+
+// segments.json から置くものの画像を、まとめて読み込む
+async fn load_props(loading: &Loading, renderer: &Renderer) -> Result<PropImages> {
+    let images = try_join_all(
+        PROP_IMAGES
+            .iter()
+            .map(|name| loading.track(renderer, name, engine::load_image(name))),
+    )
+    .await?;
+    let [springboard, dog, shield, magnet, coin]: [HtmlImageElement; PROP_IMAGES.len()] = images
+        .try_into()
+        .map_err(|_| anyhow!("Expected {} prop images", PROP_IMAGES.len()))?;
+    Ok(PropImages {
+        springboard,
+        dog,
+        shield,
+        magnet,
+        coin,
+    })
+}
+
+struct Notification {
+    text: String,
+    frames_left: u8,
+}
+
+// 一度に一つずつ、画面上部に短いメッセージを出す
+#[derive(Default)]
+struct Notifications {
+    queue: VecDeque<Notification>,
+}
+
+impl Notifications {
+    fn push(&mut self, text: String) {
+        self.queue.push_back(Notification {
+            text,
+            frames_left: NOTIFICATION_FRAMES,
+        });
+    }
+
+    fn current(&self) -> Option<&Notification> {
+        self.queue.front()
+    }
+
+    fn update(&mut self) {
+        if let Some(notification) = self.queue.front_mut() {
+            notification.frames_left -= 1;
+            if notification.frames_left == 0 {
+                self.queue.pop_front();
+            }
+        }
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        if let Some(notification) = self.current() {
+            let alpha =
+                (notification.frames_left as f64 / NOTIFICATION_FADE_FRAMES as f64).min(1.0);
+            renderer.with_alpha(alpha, |renderer| {
+                if let Err(err) = renderer.draw_text(&notification.text, &Point { x: 250, y: 80 }) {
+                    error!("Could not draw text {:#?}", err);
+                }
+            });
+        }
+    }
+}
+
+// 画像の当たり判定が少年と重なっていれば、その当たり判定
+fn hit_box(boy: &RedHatBoy, image: &Image) -> Option<Rect> {
+    if boy.bounding_box().intersects(image.bounding_box()) {
+        Some(*image.bounding_box())
+    } else {
+        None
+    }
+}
+
+pub struct Barrier {
+    id: u32,
+    image: Image,
+}
+
+impl Barrier {
+    pub fn new(image: Image) -> Self {
+        Barrier {
+            id: next_obstacle_id(),
+            image,
+        }
+    }
+}
+
+impl Obstacle for Barrier {
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect> {
+        let hit = hit_box(boy, &self.image)?;
+        boy.knock_out();
+        Some(hit)
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.image.draw(renderer)
+    }
+
+    fn outlines(&self) -> Vec<Rect> {
+        vec![*self.image.bounding_box()]
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn left(&self) -> i16 {
+        self.image.bounding_box().x()
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+// 頭上に張り出した障害物。立ったままだとぶつかるが、スライディングならくぐれる
+pub struct LowBarrier {
+    id: u32,
+    image: Image,
+}
+
+// 頭に当たるのは張り出しの真ん中のこの幅だけ。一回のスライディングの間にくぐり抜けられる細さにしておく
+const LOW_BARRIER_HIT_WIDTH: i16 = 4;
+
+impl LowBarrier {
+    pub fn new(image: Image) -> Self {
+        LowBarrier {
+            id: next_obstacle_id(),
+            image,
+        }
+    }
+
+    fn hit_area(&self) -> Rect {
+        let image = self.image.bounding_box();
+        let width = LOW_BARRIER_HIT_WIDTH.min(image.width);
+        Rect::new_from_x_y(
+            image.x() + (image.width - width) / 2,
+            image.y(),
+            width,
+            image.height,
+        )
+    }
+}
+
+// 触れた少年を、普通のジャンプより高く跳ね上げる
+const SPRINGBOARD_VELOCITY: i16 = -32;
+
+pub struct Springboard {
+    id: u32,
+    image: Image,
+}
+
+impl Springboard {
+    pub fn new(image: Image) -> Self {
+        Springboard {
+            id: next_obstacle_id(),
+            image,
+        }
+    }
+}
+
+impl Obstacle for Springboard {
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect> {
+        let hit = hit_box(boy, &self.image)?;
+        boy.bounce(SPRINGBOARD_VELOCITY);
+        Some(hit)
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.image.draw(renderer)
+    }
+
+    fn outlines(&self) -> Vec<Rect> {
+        vec![*self.image.bounding_box()]
+    }
+
+    fn category(&self) -> ObstacleCategory {
+        ObstacleCategory::Platform
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn left(&self) -> i16 {
+        self.image.bounding_box().x()
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PowerUpKind {
+    // 次の一撃を一度だけ防ぐ
+    Shield,
+    // しばらくの間、近くのコインを引き寄せる
+    Magnet,
+}
+
+pub struct PowerUp {
+    id: u32,
+    image: Image,
+    kind: PowerUpKind,
+    collected: bool,
+}
+
+impl PowerUp {
+    pub fn new(image: Image) -> Self {
+        PowerUp::with_kind(image, PowerUpKind::Shield)
+    }
+
+    pub fn with_kind(image: Image, kind: PowerUpKind) -> Self {
+        PowerUp {
+            id: next_obstacle_id(),
+            image,
+            kind,
+            collected: false,
+        }
+    }
+}
+
+impl Obstacle for PowerUp {
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect> {
+        if self.collected {
+            return None;
+        }
+        let hit = hit_box(boy, &self.image)?;
+        self.collected = true;
+        match self.kind {
+            PowerUpKind::Shield => boy.give_shield(),
+            PowerUpKind::Magnet => boy.give_magnet(),
+        }
+        Some(hit)
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.image.draw(renderer)
+    }
+
+    fn outlines(&self) -> Vec<Rect> {
+        vec![*self.image.bounding_box()]
+    }
+
+    fn category(&self) -> ObstacleCategory {
+        ObstacleCategory::Pickup
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn left(&self) -> i16 {
+        self.image.bounding_box().x()
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn score_value(&self) -> i32 {
+        0
+    }
+
+    fn collected(&self) -> bool {
+        self.collected
+    }
+}
+
+const FINISH_LINE_WIDTH: i16 = 8;
+const FINISH_LINE_COLOR: &str = "rgba(255, 255, 255, 0.8)";
+
+// ステージのゴールを示す線。当たり判定はなく、点にもならない
+pub struct FinishLine {
+    id: u32,
+    x: i16,
+}
+
+impl FinishLine {
+    pub fn new(x: i16) -> Self {
+        FinishLine {
+            id: next_obstacle_id(),
+            x,
+        }
+    }
+}
+
+impl Obstacle for FinishLine {
+    fn check_intersection(&mut self, _boy: &mut RedHatBoy) -> Option<Rect> {
+        None
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        renderer.fill_rect(
+            &Rect::new_from_x_y(self.x, 0, FINISH_LINE_WIDTH, HEIGHT),
+            FINISH_LINE_COLOR,
+        );
+    }
+
+    fn outlines(&self) -> Vec<Rect> {
+        vec![Rect::new_from_x_y(self.x, 0, FINISH_LINE_WIDTH, HEIGHT)]
+    }
+
+    fn category(&self) -> ObstacleCategory {
+        ObstacleCategory::Goal
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.x += x;
+    }
+
+    fn left(&self) -> i16 {
+        self.x
+    }
+
+    fn right(&self) -> i16 {
+        self.x + FINISH_LINE_WIDTH
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn score_value(&self) -> i32 {
+        0
+    }
+}
+
+// 取ると点になる。磁石が効いていると少年に寄っていく
+pub struct Coin {
+    id: u32,
+    image: Image,
+    collected: bool,
+}
+
+impl Coin {
+    pub fn new(image: Image) -> Self {
+        Coin {
+            id: next_obstacle_id(),
+            image,
+            collected: false,
+        }
+    }
+
+    fn center(&self) -> Point {
+        let bounding_box = self.image.bounding_box();
+        Point {
+            x: bounding_box.x() + bounding_box.width / 2,
+            y: bounding_box.y() + bounding_box.height / 2,
+        }
+    }
+}
+
+impl Obstacle for Coin {
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect> {
+        let hit = hit_box(boy, &self.image)?;
+        self.collected = true;
+        Some(hit)
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.image.draw(renderer)
+    }
+
+    fn outlines(&self) -> Vec<Rect> {
+        vec![*self.image.bounding_box()]
+    }
+
+    fn category(&self) -> ObstacleCategory {
+        ObstacleCategory::Pickup
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn left(&self) -> i16 {
+        self.image.bounding_box().x()
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn score_value(&self) -> i32 {
+        COIN_SCORE
+    }
+
+    fn collected(&self) -> bool {
+        self.collected
+    }
+
+    fn attract(&mut self, target: &Point) {
+        let center = self.center();
+        let dx = target.x as f64 - center.x as f64;
+        let dy = target.y as f64 - center.y as f64;
+        if dx.hypot(dy) > MAGNET_RADIUS {
+            return;
+        }
+        self.image
+            .move_horizontally((dx as i16).clamp(-MAGNET_PULL, MAGNET_PULL));
+        self.image
+            .move_vertically((dy as i16).clamp(-MAGNET_PULL, MAGNET_PULL));
+    }
+}
+
+// 犬はスクロールより速く左へ走ってくる
+const DOG_SPEED: i16 = 2;
+const DOG_SCORE: i32 = 30;
+// 踏みつけたときに跳ね返る速さ
+const STOMP_VELOCITY: i16 = -15;
+
+// 横からぶつかると倒されるが、上から踏みつければ倒せる敵
+pub struct Dog {
+    id: u32,
+    image: Image,
+    defeated: bool,
+}
+
+impl Dog {
+    pub fn new(image: Image) -> Self {
+        Dog {
+            id: next_obstacle_id(),
+            image,
+            defeated: false,
+        }
+    }
+}
+
+impl Obstacle for Dog {
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect> {
+        match boy
+            .bounding_box()
+            .collision_side(self.image.bounding_box())?
+        {
+            Side::Top if boy.velocity_y() > 0 => {
+                self.defeated = true;
+                boy.bounce(STOMP_VELOCITY);
+            }
+            _ => boy.knock_out(),
+        }
+        Some(*self.image.bounding_box())
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.image.draw(renderer)
+    }
+
+    fn outlines(&self) -> Vec<Rect> {
+        vec![*self.image.bounding_box()]
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn left(&self) -> i16 {
+        self.image.bounding_box().x()
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn score_value(&self) -> i32 {
+        DOG_SCORE
+    }
+
+    fn update(&mut self) {
+        self.image.move_horizontally(-DOG_SPEED);
+    }
+
+    fn defeated(&self) -> bool {
+        self.defeated
+    }
+}
+
+impl Obstacle for LowBarrier {
+    fn check_intersection(&mut self, boy: &mut RedHatBoy) -> Option<Rect> {
+        let hit = self.hit_area();
+        if !boy.bounding_box().intersects(&hit) {
+            return None;
+        }
+        boy.knock_out();
+        Some(hit)
+    }
+
+    fn draw(&self, renderer: &Renderer) {
+        self.image.draw(renderer)
+    }
+
+    fn outlines(&self) -> Vec<Rect> {
+        vec![*self.image.bounding_box()]
+    }
+
+    fn move_horizontally(&mut self, x: i16) {
+        self.image.move_horizontally(x);
+    }
+
+    fn left(&self) -> i16 {
+        self.image.bounding_box().x()
+    }
+
+    fn right(&self) -> i16 {
+        self.image.right()
+    }
+
+    fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn score_value(&self) -> i32 {
+        LOW_BARRIER_SCORE
+    }
+}
+
+#[async_trait(?Send)]
+impl Game for WalkTheDog {
+    async fn initialize(&self) -> Result<Box<dyn Game>> {
+        match self.machine {
+            None => {
+                let saved_settings = Settings::load().unwrap_or_else(|err| {
+                    log_warn!("Could not load settings {:#?}", err);
+                    Settings::default()
+                });
+                // URLパラメータはこのセッションだけの上書きなので、保存しない
+                let settings = saved_settings.with_query_params()?;
+                let mode = GameMode::from_length(browser::query_param("length")?);
+
+                let renderer = Renderer::new(browser::context()?);
+                let loading = Loading::new(LOADING_ASSETS);
+                loading.draw(&renderer);
+
+                // 互いに依存しない読み込みなので、まとめて投げて全部そろうのを待つ
+                let audio = Audio::new()?;
+                audio.resume_on_gesture()?;
+                let (json, themes, stone, props, tiles, tiles_image, sound, rhb_image, segments) =
+                    try_join!(
+                        loading.track(&renderer, RHB_JSON, engine::load_json::<Sheet>(RHB_JSON)),
+                        try_join_all(BACKGROUND_THEMES.iter().map(|theme| {
+                            loading.track(&renderer, theme, engine::load_image(theme))
+                        })),
+                        loading.track(&renderer, STONE_IMAGE, engine::load_image(STONE_IMAGE)),
+                        load_props(&loading, &renderer),
+                        loading.track(
+                            &renderer,
+                            TILES_JSON,
+                            engine::load_json::<Sheet>(TILES_JSON)
+                        ),
+                        loading.track(&renderer, TILES_IMAGE, engine::load_image(TILES_IMAGE)),
+                        loading.track(&renderer, JUMP_SOUND, audio.load_sound(JUMP_SOUND)),
+                        loading.track(&renderer, RHB_IMAGE, engine::load_image(RHB_IMAGE)),
+                        loading.track(
+                            &renderer,
+                            SEGMENTS_JSON,
+                            engine::load_json::<Vec<SegmentData>>(SEGMENTS_JSON)
+                        ),
+                    )?;
+
+                let background = themes[0].clone();
+                let sprite_sheet = Rc::new(SpriteSheet::new(tiles, tiles_image));
+
+                // うるさいので、設定でオンにしたときだけBGMを流す
+                let music = if settings.music {
+                    let background_sound = audio.load_sound("background_song.mp3").await?;
+                    Some(Music::play(audio.clone(), background_sound)?)
+                } else {
+                    None
+                };
+
+                let debug_console = if settings.debug {
+                    Some(DebugConsole::new()?)
+                } else {
+                    None
+                };
+
+                let lifetime_stats = Stats::load_lifetime().unwrap_or_else(|err| {
+                    log_warn!("Could not load stats {:#?}", err);
+                    Stats::default()
+                });
+
+                let combo_lost_sound = audio.create_tone(440.0, 220.0, 0.25)?;
+                let mut rhb = RedHatBoy::new(json, rhb_image, audio.clone(), sound, self.level);
+                rhb.set_auto_hop(settings.auto_hop);
+                rhb.set_invincible(settings.invincible);
+
+                let background_width = background.width() as i16;
+                let starting_obstacles =
+                    tutorial(stone.clone(), settings.difficulty.settings().grace_distance);
+                let timeline = rightmost(&starting_obstacles);
+                let seed: Option<u64> =
+                    browser::query_param("seed")?.and_then(|seed| seed.parse().ok());
+                let segment_source = match browser::query_param("sequence")? {
+                    Some(indices) => SegmentSource::from_indices(&indices, segments.len()),
+                    None => SegmentSource::Random,
+                };
+                let machine = WalkTheDogStateMachine::new(Walk {
+                    boy: rhb,
+                    backgrounds: [
+                        Image::new(background.clone(), Point { x: 0, y: 0 }),
+                        Image::new(
+                            background,
+                            Point {
+                                x: background_width,
+                                y: 0,
+                            },
+                        ),
+                    ],
+                    themes,
+                    theme: 0,
+                    theme_fade: None,
+                    obstacles: starting_obstacles,
+                    obstacle_sheet: sprite_sheet,
+                    stone,
+                    props,
+                    segments,
+                    rng: seed
+                        .map(StdRng::seed_from_u64)
+                        .unwrap_or_else(StdRng::from_entropy),
+                    seed,
+                    segment_source,
+                    segment_index: 0,
+                    timeline,
+                    difficulty: settings.difficulty.settings().with_query_params()?,
+                    score_model: score_model_from_name(browser::query_param("scoring")?.as_deref()),
+                    score: 0,
+                    combo: 0,
+                    combo_break_frames: 0,
+                    combo_lost_sound,
+                    audio,
+                    shake_frames: 0,
+                    distance: 0,
+                    mode,
+                    last_checkpoint: None,
+                    finish_line_placed: false,
+                    won: false,
+                    next_checkpoint: CHECKPOINT_DISTANCE,
+                    checkpoints: 0,
+                    checkpoint_pause: 0,
+                    notifications: Notifications::default(),
+                    has_dashed: false,
+                    passed_obstacles: vec![],
+                    ghost: GhostTrack::default(),
+                    best_run: None,
+                    music,
+                    debug_console,
+                    input_log: if settings.debug {
+                        Some(InputLog::new(INPUT_LOG_FRAMES))
+                    } else {
+                        None
+                    },
+                    god_mode: false,
+                    speed_override: None,
+                    stats: Stats::default(),
+                    lifetime_stats,
+                    demo: false,
+                    last_collision: None,
+                    settings,
+                    on_segment_spawned: None,
+                    leaderboard: Leaderboard::new(browser::query_param("leaderboard")?),
+                });
+
+                Ok(Box::new(WalkTheDog {
+                    machine: Some(machine),
+                    level: self.level,
+                    idle_frames: 0,
+                    demo: None,
+                    settings,
+                    saved_settings,
+                }))
+            }
+
+            Some(_) => Err(anyhow!("Error: Game is already initialized!")),
+        }
+    }
+
+    fn update(&mut self, keystate: &KeyState) {
+        if let Some(machine) = self.machine.take() {
+            let machine = if self.demo.is_some() {
+                self.update_demo(machine, keystate)
+            } else {
+                self.update_idle(machine, keystate)
+            };
+            self.machine.replace(machine);
+        }
+        assert!(self.machine.is_some());
+    }
+
+    fn draw(&self, renderer: &Renderer, alpha: f32) {
+        renderer.clear(&Rect::new_from_x_y(0, 0, WIDTH, HEIGHT));
+
+        if let Some(machine) = &self.machine {
+            machine.draw(renderer, alpha);
+        }
+
+        if cfg!(debug_assertions) {
+            if let Some(state_name) = self.boy_state_name() {
+                if let Err(err) = renderer.draw_text(state_name, &Point { x: 400, y: 130 }) {
+                    error!("Could not draw text {:#?}", err);
+                }
+            }
+        }
+    }
+
+    // タブが隠れている間はBGMも止める。ゲームオーバー中とポーズ中は元から止まっている
+    fn on_pause(&mut self) {
+        if let Some(machine) = &mut self.machine {
+            machine.walk_mut().pause_music();
+        }
+    }
+
+    fn on_resume(&mut self) {
+        if let Some(machine) = &mut self.machine {
+            if !matches!(
+                machine,
+                WalkTheDogStateMachine::GameOver(_) | WalkTheDogStateMachine::Paused(_)
+            ) {
+                machine.walk_mut().resume_music();
+            }
+        }
+    }
+}
+
+// 走行距離に応じた夜の暗さ。一周の中で0から上限まで上がり、また0に戻る
+fn night_alpha(distance: i32) -> f64 {
+    let phase = distance.rem_euclid(DAY_LENGTH) as f64 / DAY_LENGTH as f64;
+    MAX_NIGHT_ALPHA * (1.0 - (phase * 2.0 - 1.0).abs())
+}
+
+fn rightmost(obstacle_list: &Vec<Box<dyn Obstacle>>) -> i16 {
+    obstacle_list
+        .iter()
+        .map(|obstacle| obstacle.right())
+        .max_by(|x, y| x.cmp(&y))
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use std::cell::RefCell;
+
+    use futures::channel::{mpsc::unbounded, oneshot};
+    use wasm_bindgen::JsValue;
+    use wasm_bindgen_test::wasm_bindgen_test;
+    use web_sys::{AudioBuffer, AudioBufferOptions, KeyboardEvent};
+
+    use crate::{browser, engine::SheetRect, segment::ObstacleData};
+
+    use super::*;
 
     wasm_bindgen_test::wasm_bindgen_test_configure!(run_in_browser);
 
+    // rhb.json の代表的なフレームの寸法を真似た、すべての状態で引けるシート
+    fn test_sheet() -> Sheet {
+        let mut frames = HashMap::new();
+        for name in ["Idle", "Run", "Slide", "Jump", "Dead"] {
+            let (w, h, y) = if name == "Slide" {
+                (86, 100, 27)
+            } else {
+                (75, 117, 4)
+            };
+            for index in 1..=12 {
+                frames.insert(
+                    format!("{} ({}).png", name, index),
+                    Cell {
+                        frame: SheetRect { x: 0, y: 0, w, h },
+                        sprite_source_size: SheetRect { x: 56, y, w, h },
+                    },
+                );
+            }
+        }
+        Sheet { frames }
+    }
+
+    fn test_walk() -> Walk {
+        let image = HtmlImageElement::new().unwrap();
+        let audio = Audio::new().unwrap();
+        let options = AudioBufferOptions::new(1, 3000.0);
+        let sound = Sound {
+            buffer: AudioBuffer::new(&options).unwrap(),
+        };
+        let rhb = RedHatBoy::new(
+            test_sheet(),
+            image.clone(),
+            audio.clone(),
+            sound.clone(),
+            LevelConfig::default(),
+        );
+
+        let sprite_sheet = SpriteSheet::new(
+            Sheet {
+                frames: HashMap::new(),
+            },
+            image.clone(),
+        );
+        Walk {
+            boy: rhb,
+            backgrounds: [
+                Image::new(image.clone(), Point { x: 0, y: 0 }),
+                Image::new(image.clone(), Point { x: 0, y: 0 }),
+            ],
+            themes: vec![image.clone()],
+            theme: 0,
+            theme_fade: None,
+            obstacles: vec![],
+            obstacle_sheet: Rc::new(sprite_sheet),
+            stone: image.clone(),
+            props: PropImages {
+                springboard: image.clone(),
+                dog: image.clone(),
+                shield: image.clone(),
+                magnet: image.clone(),
+                coin: image,
+            },
+            segments: vec![],
+            rng: StdRng::seed_from_u64(0),
+            seed: Some(0),
+            segment_source: SegmentSource::Random,
+            segment_index: 0,
+            timeline: 0,
+            difficulty: Difficulty::Normal.settings(),
+            score_model: Box::new(ObstacleScore::default()),
+            score: 0,
+            combo: 0,
+            combo_break_frames: 0,
+            combo_lost_sound: sound,
+            audio,
+            shake_frames: 0,
+            distance: 0,
+            mode: GameMode::Endless,
+            last_checkpoint: None,
+            finish_line_placed: false,
+            won: false,
+            next_checkpoint: CHECKPOINT_DISTANCE,
+            checkpoints: 0,
+            checkpoint_pause: 0,
+            notifications: Notifications::default(),
+            has_dashed: false,
+            passed_obstacles: vec![],
+            ghost: GhostTrack::default(),
+            best_run: None,
+            music: None,
+            debug_console: None,
+            input_log: None,
+            god_mode: false,
+            speed_override: None,
+            stats: Stats::default(),
+            lifetime_stats: Stats::default(),
+            demo: false,
+            last_collision: None,
+            settings: Settings::default(),
+            on_segment_spawned: None,
+            leaderboard: Leaderboard::default(),
+        }
+    }
+
+    fn barrier_at(x: i16) -> Box<dyn Obstacle> {
+        Box::new(Barrier::new(Image::new(
+            HtmlImageElement::new().unwrap(),
+            Point { x, y: 0 },
+        )))
+    }
+
+    // bounding_boxはxからの相対位置
+    fn platform_at(x: i16, bounding_box: Rect) -> Box<dyn Obstacle> {
+        Box::new(test_platform(x, bounding_box))
+    }
+
+    #[wasm_bindgen_test]
+    fn test_platform_without_bounding_boxes_ends_at_its_position() {
+        let sheet = SpriteSheet::new(
+            Sheet {
+                frames: HashMap::new(),
+            },
+            HtmlImageElement::new().unwrap(),
+        );
+        let mut platform = Platform::new(Rc::new(sheet), Point { x: 250, y: 0 }, &[], &[]);
+        assert_eq!(platform.right(), 250);
+
+        platform.move_horizontally(-10);
+        assert_eq!(platform.right(), 240);
+        assert_eq!(platform.left(), 240);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_obstacle_width_matches_its_extent() {
+        let mut frames = HashMap::new();
+        for (name, w) in [("left.png", 60), ("middle.png", 100)] {
+            frames.insert(
+                name.to_string(),
+                Cell {
+                    frame: SheetRect {
+                        x: 0,
+                        y: 0,
+                        w,
+                        h: 20,
+                    },
+                    sprite_source_size: SheetRect {
+                        x: 0,
+                        y: 0,
+                        w,
+                        h: 20,
+                    },
+                },
+            );
+        }
+        let sheet = SpriteSheet::new(Sheet { frames }, HtmlImageElement::new().unwrap());
+        let platform = Platform::new(
+            Rc::new(sheet),
+            Point { x: 30, y: 0 },
+            &["left.png", "middle.png"],
+            &[Rect::new_from_x_y(10, 0, 140, 20)],
+        );
+        let stone = HtmlImageElement::new().unwrap();
+        stone.set_width(90);
+        stone.set_height(54);
+        let barrier = Barrier::new(Image::new(stone, Point { x: 200, y: 0 }));
+
+        // 足場の幅は、描いているタイルではなく当たり判定の幅
+        assert_eq!(platform.width(), 140);
+        let obstacles: [&dyn Obstacle; 2] = [&platform, &barrier];
+        for obstacle in obstacles.iter() {
+            assert!(obstacle.width() > 0);
+            assert_eq!(obstacle.width(), obstacle.right() - obstacle.left());
+        }
+    }
+
+    fn test_platform(x: i16, bounding_box: Rect) -> Platform {
+        let sheet = SpriteSheet::new(
+            Sheet {
+                frames: HashMap::new(),
+            },
+            HtmlImageElement::new().unwrap(),
+        );
+        Platform::new(Rc::new(sheet), Point { x, y: 0 }, &[], &[bounding_box])
+    }
+
+    fn keystate_with(codes: &[&str]) -> KeyState {
+        let mut keystate = KeyState::new();
+        for code in codes {
+            keystate.set_pressed(code, KeyboardEvent::new("keydown").unwrap());
+        }
+        keystate
+    }
+
+    #[wasm_bindgen_test]
+    fn test_demo_starts_when_idle_and_stops_on_input() {
+        let mut game = WalkTheDog {
+            machine: Some(WalkTheDogStateMachine::new(test_walk())),
+            level: LevelConfig::default(),
+            idle_frames: 0,
+            demo: None,
+            settings: Settings::default(),
+            saved_settings: Settings::default(),
+        };
+
+        for _ in 0..DEMO_IDLE_FRAMES {
+            game.update(&KeyState::new());
+        }
+        assert!(game.demo.is_some());
+        assert!(matches!(
+            game.machine,
+            Some(WalkTheDogStateMachine::Walking(_))
+        ));
+
+        game.update(&keystate_with(&["Enter"]));
+        assert!(game.demo.is_none());
+        assert!(matches!(
+            game.machine,
+            Some(WalkTheDogStateMachine::MainMenu(_))
+        ));
+        assert_eq!(
+            game.machine.as_ref().unwrap().walk().stats,
+            Stats::default()
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_transition_from_game_over_to_new_game() {
+        let (_, receiver) = unbounded();
+        let walk = test_walk();
+
+        let document = browser::document().unwrap();
+        document
+            .body()
+            .unwrap()
+            .insert_adjacent_html("afterbegin", "<div id='ui'></div>")
+            .unwrap();
+        browser::draw_ui("<p>This is the UI</p>").unwrap();
+        let state = WalkTheDogState {
+            _state: GameOver {
+                new_game_event: receiver,
+            },
+            walk,
+        };
+
+        state.new_game();
+        let ui = browser::find_html_element_by_id("ui").unwrap();
+        assert_eq!(ui.child_element_count(), 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_game_starts_in_main_menu() {
+        let machine = WalkTheDogStateMachine::new(test_walk());
+
+        assert!(matches!(machine, WalkTheDogStateMachine::MainMenu(_)));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_transition_from_main_menu_to_ready_on_enter() {
+        let machine = WalkTheDogStateMachine::new(test_walk())
+            .update(&keystate_with(&[]))
+            .update(&keystate_with(&["Enter"]));
+
+        assert!(matches!(machine, WalkTheDogStateMachine::Ready(_)));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_main_menu_ignores_held_enter() {
+        let machine = WalkTheDogStateMachine::new(test_walk()).update(&keystate_with(&["Enter"]));
+
+        assert!(matches!(machine, WalkTheDogStateMachine::MainMenu(_)));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_position_and_velocity_follow_the_context() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        boy.update();
+
+        let context = boy.state_machine.context();
+        assert_eq!(boy.position().x, context.position.x);
+        assert_eq!(boy.position().y, context.position.y);
+        assert_eq!(
+            boy.velocity().x,
+            Difficulty::Normal.settings().running_speed
+        );
+
+        boy.jump();
+        boy.update();
+
+        assert!(boy.velocity().y < 0);
+        assert_eq!(boy.position().y, boy.state_machine.context().position.y);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_destination_box_includes_the_slide_offset() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        boy.slide();
+        let offset = animation_offset("Sliding");
+        let position = boy.position();
+
+        let destination = boy.destination_box();
+
+        // test_sheetのSlideはsprite_source_sizeが(56, 27)
+        assert_eq!(destination.x(), position.x + 56 + offset.x);
+        assert_eq!(destination.y(), position.y + 27 + offset.y);
+        assert!(offset.x != 0 || offset.y != 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_boy_stays_idle_on_the_floor_in_main_menu() {
+        let walk = test_walk();
+        let floor = walk.boy.pos_y();
+        let mut state = WalkTheDogState::<MainMenu>::new(walk);
+
+        for _ in 0..10 {
+            state = match state.update(&keystate_with(&[])) {
+                MainMenuEndState::Continue(state) => state,
+                MainMenuEndState::Complete(_) => panic!("Left the main menu without Enter"),
+            };
+        }
+
+        assert!(matches!(
+            state.walk.boy.state_machine,
+            RedHatBoyStateMachine::Idle(_)
+        ));
+        assert_eq!(state.walk.boy.pos_y(), floor);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_transition_from_walking_to_game_over_on_knock_out() {
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.boy.knock_out();
+        let mut machine = WalkTheDogStateMachine::Walking(WalkTheDogState {
+            _state: Walking,
+            walk,
+        });
+
+        for _ in 0..60 {
+            machine = machine.update(&keystate_with(&[]));
+        }
+
+        assert!(matches!(machine, WalkTheDogStateMachine::GameOver(_)));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_running_to_the_end_of_a_level_wins() {
+        let mut walk = test_walk();
+        walk.mode = GameMode::Level { length: 200 };
+        walk.timeline = 1000;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let mut machine = WalkTheDogStateMachine::Walking(WalkTheDogState {
+            _state: Walking,
+            walk,
+        });
+
+        for _ in 0..120 {
+            machine = machine.update(&keystate_with(&[]));
+        }
+
+        assert!(matches!(machine, WalkTheDogStateMachine::GameOver(_)));
+        let walk = machine.walk();
+        assert!(walk.won);
+        assert!(walk.finish_line_placed);
+        assert_eq!(walk.stats.deaths, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_dying_after_a_checkpoint_respawns_there() {
+        let mut walk = test_walk();
+        walk.mode = GameMode::Level {
+            length: CHECKPOINT_DISTANCE * 3,
+        };
+        walk.settings.respawn = true;
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.distance = CHECKPOINT_DISTANCE - 1;
+        // 跳んでいる途中でチェックポイントを越える
+        walk.boy.jump();
+        walk.boy.update();
+        assert!(walk.boy.position().y < LevelConfig::default().floor);
+        walk.update_world();
+        let checkpoint = walk.last_checkpoint.unwrap();
+        for _ in 0..10 {
+            walk.update_world();
+        }
+        walk.boy.knock_out();
+        let mut machine = WalkTheDogStateMachine::Walking(WalkTheDogState {
+            _state: Walking,
+            walk,
+        });
+
+        for _ in 0..60 {
+            machine = machine.update(&keystate_with(&[]));
+            if machine.walk().boy.state_name() == "Running" {
+                break;
+            }
+        }
+
+        assert!(matches!(machine, WalkTheDogStateMachine::Walking(_)));
+        let walk = machine.walk();
+        assert_eq!(walk.boy.state_name(), "Running");
+        assert_eq!(walk.boy.position().x, checkpoint.x);
+        assert_eq!(walk.boy.position().y, LevelConfig::default().floor);
+        assert!(walk.distance - checkpoint.distance <= walk.difficulty.running_speed as i32);
+        assert_eq!(walk.stats.deaths, 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_respawn_stands_the_boy_still_on_the_floor() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        boy.jump();
+        boy.update();
+        assert!(boy.velocity_y() != 0);
+
+        boy.respawn_at(100);
+
+        assert_eq!(boy.position().x, 100);
+        assert_eq!(boy.position().y, LevelConfig::default().floor);
+        assert_eq!(boy.velocity_y(), 0);
+    }
+
+    #[test]
+    fn game_mode_is_parsed_from_the_length_in_meters() {
+        assert_eq!(
+            GameMode::from_length(Some("50".to_string())),
+            GameMode::Level {
+                length: 50 * PIXELS_PER_METER
+            }
+        );
+        assert_eq!(GameMode::from_length(None), GameMode::Endless);
+        assert_eq!(
+            GameMode::from_length(Some("far".to_string())),
+            GameMode::Endless
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_passing_a_platform_scores_more_than_passing_a_stone() {
+        let score_for = |obstacle: fn(i16) -> Box<dyn Obstacle>| {
+            let mut walk = test_walk();
+            walk.timeline = i16::MAX / 2;
+            walk.boy
+                .run_right(Difficulty::Normal.settings().running_speed);
+            let boy_left = walk.boy.bounding_box().x();
+            walk.obstacles = vec![obstacle(boy_left + 2)];
+            for _ in 0..20 {
+                walk.update_world();
+            }
+            walk.score
+        };
+        let small_platform_at = |x: i16| platform_at(x, Rect::new_from_x_y(0, 0, 10, 10));
+
+        assert_eq!(score_for(barrier_at), OBSTACLE_SCORE);
+        assert!(score_for(small_platform_at) > score_for(barrier_at));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_pressing_r_mid_run_restarts_from_the_start() {
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.score = 120;
+        walk.distance = -5000;
+        let mut machine = WalkTheDogStateMachine::Walking(WalkTheDogState {
+            _state: Walking,
+            walk,
+        });
+
+        let mut keystate = KeyState::new();
+        keystate.record_press("KeyR");
+        machine = machine.update(&keystate);
+
+        assert!(matches!(machine, WalkTheDogStateMachine::Ready(_)));
+        let walk = machine.walk();
+        assert_eq!(walk.boy.state_name(), "Idle");
+        assert_eq!(walk.boy.walking_speed(), 0);
+        assert_eq!(walk.score, 0);
+        assert_eq!(walk.distance, 0);
+    }
+
+    #[test]
+    fn pause_menu_selection_wraps_around() {
+        let mut menu = PauseMenu::default();
+        assert_eq!(menu.selected(), PauseOption::Resume);
+
+        menu.select_next();
+        assert_eq!(menu.selected(), PauseOption::Restart);
+        menu.select_next();
+        menu.select_next();
+        assert_eq!(menu.selected(), PauseOption::Resume);
+        menu.select_previous();
+        assert_eq!(menu.selected(), PauseOption::MainMenu);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_pause_menu_options_resume_restart_and_return_to_the_menu() {
+        let choose = |downs: usize| {
+            let mut walk = test_walk();
+            walk.boy
+                .run_right(Difficulty::Normal.settings().running_speed);
+            walk.distance = 500;
+            let mut machine = WalkTheDogStateMachine::Walking(WalkTheDogState {
+                _state: Walking,
+                walk,
+            });
+
+            let mut keystate = KeyState::new();
+            keystate.record_press("Escape");
+            machine = machine.update(&keystate);
+            assert!(matches!(machine, WalkTheDogStateMachine::Paused(_)));
+            let distance = machine.walk().distance;
+            for _ in 0..downs {
+                let mut keystate = KeyState::new();
+                keystate.record_press("ArrowDown");
+                machine = machine.update(&keystate);
+            }
+            // ポーズ中は世界が止まっている
+            assert_eq!(machine.walk().distance, distance);
+
+            let mut keystate = KeyState::new();
+            keystate.record_press("Enter");
+            machine.update(&keystate)
+        };
+
+        let resumed = choose(0);
+        assert!(matches!(resumed, WalkTheDogStateMachine::Walking(_)));
+        assert_eq!(resumed.walk().boy.state_name(), "Running");
+
+        let restarted = choose(1);
+        assert!(matches!(restarted, WalkTheDogStateMachine::Ready(_)));
+        assert_eq!(restarted.walk().distance, 0);
+
+        let main_menu = choose(2);
+        assert!(matches!(main_menu, WalkTheDogStateMachine::MainMenu(_)));
+        assert_eq!(main_menu.walk().boy.state_name(), "Idle");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_no_obstacles_spawn_after_knock_out() {
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.boy.knock_out();
+        let mut state = WalkTheDogState {
+            _state: Walking,
+            walk,
+        };
+
+        for _ in 0..10 {
+            state = match state.update(&keystate_with(&[])) {
+                WalkingEndState::Continue(state) => state,
+                WalkingEndState::Complete(_) => panic!("Knocked out too early"),
+                WalkingEndState::Restart(_) => panic!("Restarted unexpectedly"),
+                WalkingEndState::Pause(_) => panic!("Paused unexpectedly"),
+            };
+        }
+
+        assert_eq!(state.walk.obstacles.len(), 0);
+        assert_eq!(state.walk.timeline, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_hard_difficulty_generates_denser_segments_than_easy() {
+        let obstacles_per_distance = |difficulty: Difficulty| {
+            let mut walk = test_walk();
+            walk.difficulty = difficulty.settings();
+            for _ in 0..5 {
+                walk.generate_next_segment();
+            }
+            walk.obstacles.len() as f32 / walk.timeline as f32
+        };
+
+        assert!(
+            obstacles_per_distance(Difficulty::Hard) > obstacles_per_distance(Difficulty::Easy)
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_sequence_source_generates_segments_in_order_regardless_of_seed() {
+        let order = vec![
+            SegmentKind::LowBarrier,
+            SegmentKind::StoneAndPlatform,
+            SegmentKind::LowBarrier,
+            SegmentKind::PlatformAndStone,
+        ];
+        let generated = |seed: u64| {
+            let spawned = Rc::new(RefCell::new(vec![]));
+            let mut walk = test_walk();
+            walk.rng = StdRng::seed_from_u64(seed);
+            walk.segment_source = SegmentSource::Sequence(order.clone());
+            let log = spawned.clone();
+            walk.on_segment_spawned = Some(Box::new(move |kind, _count| {
+                log.borrow_mut().push(kind);
+            }));
+            for _ in 0..order.len() + 1 {
+                walk.generate_next_segment();
+            }
+            let kinds = spawned.borrow().clone();
+            kinds
+        };
+
+        let mut expected = order.clone();
+        expected.push(order[0]);
+        assert_eq!(generated(1), expected);
+        assert_eq!(generated(42), expected);
+    }
+
+    #[test]
+    fn segment_sequence_is_parsed_from_indices() {
+        assert_eq!(
+            SegmentSource::from_indices("2, 0,4,x,9", 2),
+            SegmentSource::Sequence(vec![
+                SegmentKind::LowBarrier,
+                SegmentKind::StoneAndPlatform,
+                SegmentKind::Loaded(1),
+            ])
+        );
+        assert_eq!(SegmentSource::from_indices("", 2), SegmentSource::Random);
+        assert_eq!(
+            SegmentSource::from_indices("2, 0,4,x,9", 2).indices(),
+            Some(vec![2, 0, 4])
+        );
+        assert_eq!(SegmentSource::Random.indices(), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_segment_spawn_callback_reports_each_generated_segment() {
+        let spawned = Rc::new(RefCell::new(vec![]));
+        let mut walk = test_walk();
+        let log = spawned.clone();
+        walk.on_segment_spawned = Some(Box::new(move |kind, count| {
+            log.borrow_mut().push((kind, count));
+        }));
+
+        let mut expected = vec![];
+        for _ in 0..4 {
+            let kind = walk.peek_next_segment();
+            let before = walk.obstacles.len();
+            walk.generate_next_segment();
+            expected.push((kind, walk.obstacles.len() - before));
+        }
+
+        assert_eq!(*spawned.borrow(), expected);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_restart_with_a_seed_repeats_the_same_segments() {
+        let mut walk = test_walk();
+        walk.seed = Some(42);
+        walk.rng = StdRng::seed_from_u64(42);
+        let segments = |walk: &mut Walk| -> Vec<SegmentKind> {
+            (0..8)
+                .map(|_| {
+                    let kind = walk.peek_next_segment();
+                    walk.generate_next_segment();
+                    kind
+                })
+                .collect()
+        };
+
+        let first_run = segments(&mut walk);
+        walk.reset();
+        assert_eq!(segments(&mut walk), first_run);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_larger_obstacle_buffer_leaves_more_space_between_segments() {
+        let gap_before_next_segment = |obstacle_buffer: i16| {
+            let mut walk = test_walk();
+            walk.difficulty.obstacle_buffer = obstacle_buffer;
+            walk.generate_next_segment();
+            let end_of_first = walk.timeline;
+            let first_count = walk.obstacles.len();
+            walk.generate_next_segment();
+            let start_of_second = walk.obstacles[first_count..]
+                .iter()
+                .map(|obstacle| obstacle.left())
+                .min()
+                .unwrap();
+            start_of_second - end_of_first
+        };
+
+        let default_gap = gap_before_next_segment(Difficulty::Normal.settings().obstacle_buffer);
+        assert_eq!(gap_before_next_segment(200) - default_gap, 180);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_checkpoint_fires_once_and_raises_difficulty() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.distance = CHECKPOINT_DISTANCE - 1;
+        let buffer = walk.current_difficulty().obstacle_buffer;
+
+        for _ in 0..10 {
+            walk.update_world();
+        }
+
+        assert_eq!(walk.checkpoints, 1);
+        assert!(walk.current_difficulty().obstacle_buffer < buffer);
+        assert_eq!(
+            walk.notifications
+                .queue
+                .iter()
+                .filter(|notification| notification.text.starts_with("Checkpoint"))
+                .count(),
+            1
+        );
+        assert!(walk.checkpoint_pause > 0);
+    }
+
+    #[test]
+    fn scoring_is_chosen_by_name() {
+        let mut coins = score_model_from_name(Some("Coins"));
+        assert_eq!(coins.on_obstacle_passed(OBSTACLE_SCORE), 0);
+        assert_eq!(coins.on_coin(COIN_SCORE), COIN_SCORE * COIN_WEIGHT);
+
+        for name in [None, Some("obstacles"), Some("unknown")] {
+            let mut obstacles = score_model_from_name(name);
+            assert_eq!(obstacles.on_obstacle_passed(OBSTACLE_SCORE), OBSTACLE_SCORE);
+            assert_eq!(obstacles.on_coin(COIN_SCORE), OBSTACLE_SCORE + COIN_SCORE);
+        }
+    }
+
+    #[test]
+    fn difficulty_stops_rising_after_the_step_cap() {
+        let settings = Difficulty::Normal.settings();
+        let capped = settings.harder_by(MAX_DIFFICULTY_STEPS);
+
+        let beyond = settings.harder_by(MAX_DIFFICULTY_STEPS + 1);
+        assert_eq!(beyond.obstacle_buffer, capped.obstacle_buffer);
+        assert_eq!(beyond.timeline_minimum, capped.timeline_minimum);
+
+        let huge = settings.harder_by(u32::MAX);
+        assert_eq!(huge.obstacle_buffer, capped.obstacle_buffer);
+        assert_eq!(huge.timeline_minimum, capped.timeline_minimum);
+        assert!(capped.timeline_minimum > settings.timeline_minimum);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_coin_score_counts_coins_but_not_distance_or_obstacles() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.score_model = Box::new(CoinScore::default());
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        let image = HtmlImageElement::new().unwrap();
+        image.set_width(10);
+        image.set_height(10);
+        walk.obstacles = vec![
+            barrier_at(boy_box.x() - 20),
+            Box::new(Coin::new(Image::new(
+                image,
+                Point {
+                    x: boy_box.x() + 5,
+                    y: boy_box.y() + 5,
+                },
+            ))),
+        ];
+
+        for _ in 0..20 {
+            walk.update_world();
+        }
+
+        assert!(walk.distance > 0);
+        assert_eq!(walk.score, COIN_SCORE * COIN_WEIGHT);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_combo_multiplier_climbs_and_resets_on_hit() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_left = walk.boy.bounding_box().x();
+        walk.obstacles = vec![
+            barrier_at(boy_left + 2),
+            barrier_at(boy_left + 30),
+            barrier_at(boy_left + 60),
+        ];
+
+        for _ in 0..20 {
+            walk.update_world();
+        }
+
+        assert_eq!(walk.combo, 3);
+        assert_eq!(walk.combo_multiplier(), 4);
+        assert_eq!(walk.score, OBSTACLE_SCORE * (1 + 2 + 3));
+
+        walk.boy.knock_out();
+        walk.update_world();
+
+        assert_eq!(walk.combo, 0);
+        assert_eq!(walk.combo_multiplier(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_breaking_a_combo_flashes_and_plays_a_sound() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.combo = 3;
+        let queued = walk.audio.queued_count();
+        let boy_box = walk.boy.bounding_box();
+        let stone = HtmlImageElement::new().unwrap();
+        stone.set_width(boy_box.width as u32);
+        stone.set_height(boy_box.height as u32);
+        walk.obstacles = vec![Box::new(Barrier::new(Image::new(stone, boy_box.position)))];
+        let mut machine = WalkTheDogStateMachine::Walking(WalkTheDogState {
+            _state: Walking,
+            walk,
+        });
+        machine = machine.update(&keystate_with(&[]));
+
+        assert!(machine.walk().knocked_down());
+        assert_eq!(machine.walk().combo, 0);
+        assert_eq!(machine.walk().combo_break_frames, COMBO_BREAK_FLASH_FRAMES);
+        assert_eq!(machine.walk().audio.queued_count(), queued + 1);
+
+        // 倒れている間もすぐに消え、もう一度は鳴らない
+        for _ in 0..COMBO_BREAK_FLASH_FRAMES {
+            machine = machine.update(&keystate_with(&[]));
+        }
+        assert_eq!(machine.walk().combo_break_frames, 0);
+        assert_eq!(machine.walk().audio.queued_count(), queued + 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_dash_boosts_speed_and_ignores_knock_out() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        let running_speed = boy.walking_speed();
+
+        boy.dash();
+        assert!(boy.walking_speed() > running_speed);
+
+        boy.knock_out();
+        assert!(matches!(
+            boy.state_machine,
+            RedHatBoyStateMachine::Running(_)
+        ));
+
+        for _ in 0..12 {
+            boy.update();
+        }
+        assert_eq!(boy.walking_speed(), running_speed);
+
+        boy.knock_out();
+        assert!(matches!(
+            boy.state_machine,
+            RedHatBoyStateMachine::Falling(_)
+        ));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_dash_is_blocked_during_cooldown() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        let running_speed = boy.walking_speed();
+
+        boy.dash();
+        for _ in 0..12 {
+            boy.update();
+        }
+        boy.dash();
+        assert_eq!(boy.walking_speed(), running_speed);
+
+        for _ in 0..60 {
+            boy.update();
+        }
+        boy.dash();
+        assert!(boy.walking_speed() > running_speed);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_each_state_has_a_unique_name() {
+        let mut boy = test_walk().boy;
+        let mut names = vec![boy.state_name()];
+
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        names.push(boy.state_name());
+        boy.slide();
+        names.push(boy.state_name());
+
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        boy.jump();
+        names.push(boy.state_name());
+        boy.knock_out();
+        names.push(boy.state_name());
+        for _ in 0..30 {
+            boy.update();
+        }
+        names.push(boy.state_name());
+
+        assert_eq!(
+            names,
+            vec![
+                "Idle",
+                "Running",
+                "Sliding",
+                "Jumping",
+                "Falling",
+                "KnockedOut"
+            ]
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_platform_draws_its_tiles_in_a_single_row() {
+        let mut frames = HashMap::new();
+        for (index, name) in ["13.png", "14.png", "15.png"].iter().enumerate() {
+            let rect = SheetRect {
+                x: index as i16 * 128,
+                y: 0,
+                w: 128,
+                h: 93,
+            };
+            frames.insert(
+                name.to_string(),
+                Cell {
+                    frame: rect,
+                    sprite_source_size: rect,
+                },
+            );
+        }
+        let sheet = Rc::new(SpriteSheet::new(
+            Sheet { frames },
+            HtmlImageElement::new().unwrap(),
+        ));
+        let platform = Platform::new(
+            sheet,
+            Point { x: 100, y: 400 },
+            &["13.png", "14.png", "15.png"],
+            &[],
+        );
+
+        let draws = platform.sprite_draws();
+
+        assert_eq!(draws.len(), 3);
+        let destinations: Vec<(i16, i16)> = draws
+            .iter()
+            .map(|(_, destination)| (destination.x(), destination.y()))
+            .collect();
+        assert_eq!(destinations, vec![(100, 400), (228, 400), (356, 400)]);
+        assert_eq!(draws[2].0.x(), 256);
+
+        // 足場の描画は、タイルの数だけのdrawImageを一回のバッチで出す
+        let canvas = browser::create_canvas(WIDTH as u32, HEIGHT as u32).unwrap();
+        let renderer = Renderer::new(browser::context_of(&canvas).unwrap());
+        renderer.count_image_draws();
+        platform.draw(&renderer);
+        assert_eq!(renderer.image_draws(), draws.len() as u32);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_screen_shakes_once_on_knock_out() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        walk.obstacles = vec![Box::new(Barrier::new(Image::new(
+            HtmlImageElement::new().unwrap(),
+            Point {
+                x: boy_box.x() + boy_box.width / 2,
+                y: boy_box.y() + boy_box.height / 2,
+            },
+        )))];
+        let mut state = WalkTheDogState {
+            _state: Walking,
+            walk,
+        };
+
+        let mut shake_frames = vec![];
+        let mut flashes = vec![];
+        for _ in 0..20 {
+            state = match state.update(&keystate_with(&[])) {
+                WalkingEndState::Continue(state) => state,
+                WalkingEndState::Complete(_) => panic!("Knocked out too early"),
+                WalkingEndState::Restart(_) => panic!("Restarted unexpectedly"),
+                WalkingEndState::Pause(_) => panic!("Paused unexpectedly"),
+            };
+            shake_frames.push(state.walk.shake_frames);
+            flashes.push(state.walk.damage_flashing());
+        }
+
+        assert_eq!(flashes.iter().filter(|flash| **flash).count(), 1);
+        assert!(flashes[0]);
+        assert_eq!(shake_frames[0], SHAKE_FRAMES);
+        assert_eq!(shake_frames[SHAKE_FRAMES as usize], 0);
+        assert!(shake_frames[SHAKE_FRAMES as usize..]
+            .iter()
+            .all(|&frames| frames == 0));
+    }
+
+    fn walking_for(walk: Walk, keys: &[&str], frames: usize) -> WalkTheDogStateMachine {
+        let mut machine = WalkTheDogStateMachine::Walking(WalkTheDogState {
+            _state: Walking,
+            walk,
+        });
+        for _ in 0..frames {
+            machine = machine.update(&keystate_with(keys));
+        }
+        machine
+    }
+
+    #[wasm_bindgen_test]
+    fn test_settings_round_trip_and_fill_in_missing_fields() {
+        let settings = Settings {
+            difficulty: Difficulty::Hard,
+            music: true,
+            debug: false,
+            respawn: true,
+            high_contrast: true,
+            palette: Palette::Colorblind,
+            auto_hop: false,
+            invincible: false,
+        };
+        let restored: Settings = JsValue::from_serde(&settings)
+            .unwrap()
+            .into_serde()
+            .unwrap();
+        assert_eq!(restored, settings);
+
+        // 古い保存データには無い項目や、知らない項目が混ざっていても読める
+        let partial: Settings = js_sys::JSON::parse(r#"{ "music": true, "volume": 3 }"#)
+            .unwrap()
+            .into_serde()
+            .unwrap();
+        assert_eq!(
+            partial,
+            Settings {
+                music: true,
+                ..Settings::default()
+            }
+        );
+
+        // 練習モードは保存されず、保存されていても読まない
+        let practice = Settings {
+            invincible: true,
+            ..Settings::default()
+        };
+        let json: String = js_sys::JSON::stringify(&JsValue::from_serde(&practice).unwrap())
+            .unwrap()
+            .into();
+        assert!(!json.contains("invincible"));
+        let stored: Settings = js_sys::JSON::parse(r#"{ "invincible": true }"#)
+            .unwrap()
+            .into_serde()
+            .unwrap();
+        assert!(!stored.invincible);
+    }
+
+    #[test]
+    fn toggling_a_setting_saves_only_that_setting() {
+        let saved = Settings::default();
+        // ?practice=1&contrast=high で開いたセッション
+        let mut settings = Settings {
+            invincible: true,
+            high_contrast: true,
+            ..saved
+        };
+        let mut saved = saved;
+
+        SettingToggle::Palette.apply(&mut settings, &mut saved);
+        assert_eq!(settings.palette, Palette::Colorblind);
+        assert_eq!(
+            saved,
+            Settings {
+                palette: Palette::Colorblind,
+                ..Settings::default()
+            }
+        );
+
+        // URLで上書きされていた値を切り替えたら、切り替えた後の値を保存する
+        SettingToggle::Contrast.apply(&mut settings, &mut saved);
+        assert!(!settings.high_contrast);
+        assert!(!saved.high_contrast);
+        assert!(!saved.invincible);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_menu_keys_change_the_settings_the_walk_reads() {
+        let mut game = WalkTheDog {
+            machine: Some(WalkTheDogStateMachine::new(test_walk())),
+            level: LevelConfig::default(),
+            idle_frames: 0,
+            demo: None,
+            settings: Settings::default(),
+            saved_settings: Settings::default(),
+        };
+        let mut keystate = KeyState::new();
+        keystate.record_press("KeyC");
+        keystate.record_press("KeyH");
+
+        game.update(&keystate);
+
+        let walk = game.machine.as_ref().unwrap().walk();
+        assert!(walk.settings.high_contrast);
+        assert_eq!(walk.settings, game.settings);
+        assert_eq!(game.saved_settings, game.settings);
+        assert_eq!(
+            walk.boy.state_machine.context().auto_hop,
+            game.settings.auto_hop
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_score_submission_carries_score_seed_and_stats() {
+        let mut walk = test_walk();
+        walk.score = 120;
+        walk.distance = 2500;
+        walk.stats.jumps = 7;
+
+        let json = walk.score_submission().to_json().unwrap();
+
+        let parsed = js_sys::JSON::parse(&json).unwrap();
+        let field = |name: &str| js_sys::Reflect::get(&parsed, &name.into()).unwrap();
+        assert_eq!(field("score").as_f64(), Some(120.0));
+        assert_eq!(field("distance").as_f64(), Some(250.0));
+        assert_eq!(field("seed").as_string(), Some("0".to_string()));
+        assert!(field("sequence").is_null());
+        let stats: Stats = field("stats").into_serde().unwrap();
+        assert_eq!(stats.jumps, 7);
+
+        walk.seed = Some(u64::MAX);
+        walk.segment_source = SegmentSource::from_indices("0,2,3", 1);
+        let json = walk.score_submission().to_json().unwrap();
+        let parsed = js_sys::JSON::parse(&json).unwrap();
+        let field = |name: &str| js_sys::Reflect::get(&parsed, &name.into()).unwrap();
+        assert_eq!(
+            field("seed").as_string(),
+            Some("18446744073709551615".to_string())
+        );
+        let sequence: Vec<usize> = field("sequence").into_serde().unwrap();
+        assert_eq!(sequence, vec![0, 2, 3]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_leaderboard_shows_the_top_rows_of_the_payload() {
+        let entries: Vec<LeaderboardEntry> = js_sys::JSON::parse(
+            r#"[
+                { "name": "ann", "score": 40 },
+                { "name": "bob", "score": 90 },
+                { "name": "cy", "score": 10 }
+            ]"#,
+        )
+        .unwrap()
+        .into_serde()
+        .unwrap();
+        let many: Vec<LeaderboardEntry> = (0..15)
+            .map(|score| LeaderboardEntry {
+                name: "runner".to_string(),
+                score,
+            })
+            .collect();
+
+        let leaderboard = Leaderboard::new(Some("scores.json".to_string()));
+        assert_eq!(leaderboard.rows(), vec!["Loading leaderboard..."]);
+        leaderboard.unavailable.set(true);
+        assert_eq!(leaderboard.rows(), vec!["Leaderboard unavailable."]);
+
+        *leaderboard.entries.borrow_mut() = Some(top_entries(entries));
+        assert_eq!(
+            leaderboard.rows(),
+            vec!["1. bob 90", "2. ann 40", "3. cy 10"]
+        );
+        *leaderboard.entries.borrow_mut() = Some(top_entries(many));
+        assert_eq!(leaderboard.rows().len(), LEADERBOARD_ROWS);
+        assert_eq!(leaderboard.rows()[0], "1. runner 14");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_input_log_keeps_only_the_latest_frames() {
+        let mut input_log = InputLog::new(3);
+        for frame in 0..5 {
+            let actions = if frame == 4 {
+                vec![Action::Jump, Action::Dash]
+            } else {
+                vec![]
+            };
+            input_log.record(actions, "Running");
+        }
+
+        let frames: Vec<InputFrame> = js_sys::JSON::parse(&input_log.to_json().unwrap())
+            .unwrap()
+            .into_serde()
+            .unwrap();
+        let numbers: Vec<u32> = frames.iter().map(|frame| frame.frame).collect();
+        assert_eq!(numbers, vec![2, 3, 4]);
+        assert_eq!(
+            frames[2],
+            InputFrame {
+                frame: 4,
+                actions: vec![Action::Jump, Action::Dash],
+                state: "Running".to_string(),
+            }
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_stats_count_jumps_slides_and_deaths() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+
+        let mut machine = walking_for(walk, &["Space"], 1);
+        for _ in 0..60 {
+            machine = machine.update(&keystate_with(&[]));
+        }
+        machine = machine.update(&keystate_with(&["ArrowDown"]));
+        if let WalkTheDogStateMachine::Walking(state) = &mut machine {
+            state.walk.boy.knock_out();
+        }
+        for _ in 0..60 {
+            machine = machine.update(&keystate_with(&[]));
+        }
+
+        assert!(matches!(machine, WalkTheDogStateMachine::GameOver(_)));
+        let stats = machine.walk().stats;
+        assert_eq!(stats.jumps, 1);
+        assert_eq!(stats.slides, 1);
+        assert_eq!(stats.deaths, 1);
+    }
+
+    fn walk_with_low_barrier_ahead() -> Walk {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+
+        let stone = HtmlImageElement::new().unwrap();
+        stone.set_width(90);
+        stone.set_height(54);
+        let mut obstacles = low_barrier(stone, 0);
+        // 次のフレームで、張り出しの当たる部分が立っている少年の右端にちょうどかかる位置に置く
+        let boy_box = walk.boy.bounding_box();
+        let speed = walk.boy.walking_speed();
+        let hit_left = obstacles[0].left() + (90 - LOW_BARRIER_HIT_WIDTH) / 2;
+        obstacles[0].move_horizontally(boy_box.right() - 1 + speed - hit_left);
+        walk.obstacles = obstacles;
+        walk
+    }
+
+    fn boy_running_off_a_platform(frames_in_air: usize) -> RedHatBoy {
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.boy.land_on(400);
+        for _ in 0..frames_in_air {
+            walk.boy.update();
+        }
+        walk.boy
+    }
+
+    #[wasm_bindgen_test]
+    fn test_boy_can_jump_just_after_leaving_a_platform() {
+        let mut boy = boy_running_off_a_platform(3);
+        boy.jump();
+
+        assert_eq!(boy.state_name(), "Jumping");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_boy_cannot_jump_once_coyote_time_expires() {
+        let mut boy = boy_running_off_a_platform(10);
+        boy.jump();
+
+        assert_eq!(boy.state_name(), "Running");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_can_transition_follows_the_transition_table() {
+        let idle = test_walk().boy.state_machine;
+        assert!(idle.can_transition(&Event::Run(3)));
+        assert!(idle.can_transition(&Event::Update));
+        assert!(!idle.can_transition(&Event::Jump));
+        assert!(!idle.can_transition(&Event::Slide));
+        assert!(!idle.can_transition(&Event::KnockOut));
+
+        let running = idle.transition(Event::Run(3));
+        assert!(running.can_transition(&Event::Jump));
+        assert!(running.can_transition(&Event::Slide));
+        assert!(running.can_transition(&Event::KnockOut));
+        assert!(!running.can_transition(&Event::Run(3)));
+        assert!(!running.can_transition(&Event::FastFall));
+
+        let jumping = running.clone().transition(Event::Jump);
+        assert_eq!(jumping.state_name(), "Jumping");
+        assert!(jumping.can_transition(&Event::FastFall));
+        assert!(jumping.can_transition(&Event::Land(400)));
+        assert!(!jumping.can_transition(&Event::Slide));
+        assert!(!jumping.can_transition(&Event::Dash));
+        assert!(!jumping.can_transition(&Event::Jump));
+        let released = jumping.clone().transition(Event::JumpReleased);
+        assert!(released.can_transition(&Event::Jump));
+
+        // ダッシュ中は無敵なので、ぶつかっても何も起きない
+        let dashing = running.clone().transition(Event::Dash);
+        assert!(!dashing.can_transition(&Event::KnockOut));
+        // シールドも練習モードも、倒れる代わりにシールドが割れるだけ
+        let shielded = running.clone().map_context(|context| context.give_shield());
+        assert!(!shielded.can_transition(&Event::KnockOut));
+        assert_eq!(shielded.transition(Event::KnockOut).state_name(), "Running");
+        let invincible = running.map_context(|context| context.set_invincible(true));
+        assert!(!invincible.can_transition(&Event::KnockOut));
+        assert_eq!(
+            invincible.transition(Event::KnockOut).state_name(),
+            "Running"
+        );
+
+        let knocked_out = jumping.transition(Event::KnockOut);
+        assert!(knocked_out.can_transition(&Event::Update));
+        assert!(!knocked_out.can_transition(&Event::Jump));
+        assert!(!knocked_out.can_transition(&Event::Run(3)));
+    }
+
+    fn boy_falling_towards_a_platform() -> RedHatBoy {
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.boy.jump();
+        while walk.boy.velocity_y() <= 0 {
+            walk.boy.update();
+        }
+        walk.boy
+    }
+
+    #[wasm_bindgen_test]
+    fn test_jump_pressed_just_before_landing_fires_on_contact() {
+        let mut boy = boy_falling_towards_a_platform();
+        boy.jump();
+        boy.update();
+        boy.land_on(400);
+
+        assert_eq!(boy.state_name(), "Jumping");
+        assert!(boy.velocity_y() < 0);
+    }
+
+    // 跳んでから着地するまで、毎フレームジャンプを押しっぱなしにする
+    fn boy_holding_jump_through_a_landing(auto_hop: bool) -> RedHatBoy {
+        let mut boy = test_walk().boy;
+        boy.set_auto_hop(auto_hop);
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        let mut falling = false;
+        for _ in 0..200 {
+            boy.jump();
+            boy.update();
+            if boy.velocity_y() > 0 {
+                falling = true;
+            } else if falling {
+                break;
+            }
+        }
+        boy
+    }
+
+    #[wasm_bindgen_test]
+    fn test_holding_jump_through_a_landing_hops_again_immediately() {
+        let boy = boy_holding_jump_through_a_landing(true);
+
+        assert_eq!(boy.state_name(), "Jumping");
+        assert!(boy.velocity_y() < 0);
+        assert_eq!(boy.position().y, LevelConfig::default().floor);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_without_auto_hop_jump_must_be_pressed_again_after_landing() {
+        let mut boy = boy_holding_jump_through_a_landing(false);
+        assert_eq!(boy.state_name(), "Running");
+
+        boy.jump();
+        assert_eq!(boy.state_name(), "Running");
+
+        boy.release_jump();
+        boy.jump();
+        assert_eq!(boy.state_name(), "Jumping");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_boy_is_drawn_halfway_between_updates_at_half_alpha() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        boy.jump();
+        boy.update();
+        let prev = boy.position();
+        boy.update();
+        let current = boy.position();
+        assert_ne!(prev.y, current.y);
+
+        let halfway = boy.interpolated_position(0.5);
+
+        assert_eq!(halfway.x, (prev.x + current.x) / 2);
+        assert_eq!(
+            halfway.y,
+            ((prev.y + current.y) as f32 / 2.0).round() as i16
+        );
+        assert_eq!(boy.interpolated_position(0.0).y, prev.y);
+        assert_eq!(boy.interpolated_position(1.0).y, current.y);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_trail_keeps_the_latest_positions_sliding_back_with_the_world() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        boy.dash();
+        assert!(boy.shows_trail());
+
+        for _ in 0..TRAIL_LENGTH * 2 {
+            boy.update();
+        }
+
+        assert_eq!(boy.trail.len(), TRAIL_LENGTH);
+        let xs: Vec<i16> = boy.trail.iter().map(|(position, _)| position.x).collect();
+        assert!(xs.windows(2).all(|pair| pair[0] < pair[1]));
+
+        boy.reset();
+        assert!(boy.trail.is_empty());
+        assert!(!boy.shows_trail());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_fast_fall_speeds_up_the_fall_without_sliding() {
+        let mut boy = boy_falling_towards_a_platform();
+        let velocity = boy.velocity_y();
+
+        boy.slide();
+        boy.fast_fall();
+
+        assert_eq!(boy.state_name(), "Jumping");
+        assert!(boy.velocity_y() > velocity);
+        for _ in 0..20 {
+            boy.fast_fall();
+        }
+        assert_eq!(boy.velocity_y(), LevelConfig::default().terminal_velocity);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_landing_without_a_buffered_jump_keeps_running() {
+        let mut boy = boy_falling_towards_a_platform();
+        boy.land_on(400);
+
+        assert_eq!(boy.state_name(), "Running");
+    }
+
+    fn jump_apex(frames_held: usize) -> i16 {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+
+        let mut machine = walking_for(walk, &["Space"], frames_held);
+        let mut apex = machine.walk().boy.pos_y();
+        for _ in 0..60 {
+            machine = machine.update(&keystate_with(&[]));
+            apex = apex.min(machine.walk().boy.pos_y());
+        }
+        apex
+    }
+
+    #[wasm_bindgen_test]
+    fn test_releasing_space_early_gives_a_lower_jump() {
+        let held = jump_apex(30);
+        let tapped = jump_apex(1);
+
+        // y座標は上ほど小さい
+        assert!(tapped > held);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_sliding_bounding_box_is_shorter_than_running() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        let running_box = boy.bounding_box();
+
+        boy.slide();
+        let sliding_box = boy.bounding_box();
+
+        assert!(sliding_box.height < running_box.height);
+        assert!(sliding_box.y() > running_box.y());
+
+        // 起き上がったら元の高さに戻る
+        for _ in 0..20 {
+            boy.update();
+        }
+        assert_eq!(boy.state_name(), "Running");
+        assert_eq!(boy.bounding_box().height, running_box.height);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_standing_boy_hits_low_barrier() {
+        let machine = walking_for(walk_with_low_barrier_ahead(), &[], 60);
+
+        assert!(machine.walk().knocked_down());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_sliding_boy_passes_under_low_barrier() {
+        // 一回押すだけの、普通のスライディングでくぐれる
+        let mut machine = walking_for(walk_with_low_barrier_ahead(), &["ArrowDown"], 1);
+        for _ in 0..59 {
+            machine = machine.update(&keystate_with(&[]));
+        }
+
+        assert!(!machine.walk().knocked_down());
+        assert_eq!(machine.walk().combo, 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_background_theme_changes_with_each_new_segment() {
+        let mut walk = test_walk();
+        let city = HtmlImageElement::new().unwrap();
+        city.set_width(700);
+        walk.themes.push(city);
+        walk.backgrounds[0].set_x(-10);
+        walk.backgrounds[1].set_x(0);
+
+        walk.generate_next_segment();
+        assert_eq!(walk.theme, 1);
+        assert_eq!(walk.backgrounds[0].bounding_box().width, 700);
+        assert_eq!(walk.backgrounds[1].bounding_box().x(), 690);
+
+        walk.generate_next_segment();
+        assert_eq!(walk.theme, 0);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_previous_theme_fades_out_over_the_transition() {
+        let mut walk = test_walk();
+        let city = HtmlImageElement::new().unwrap();
+        city.set_width(700);
+        walk.themes.push(city);
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        assert_eq!(walk.theme_fade.as_ref().map(ThemeFade::alpha), None);
+
+        walk.generate_next_segment();
+        walk.timeline = i16::MAX / 2;
+        assert_eq!(walk.theme_fade.as_ref().map(ThemeFade::alpha), Some(1.0));
+
+        let mut alphas = vec![];
+        while let Some(alpha) = walk.theme_fade.as_ref().map(ThemeFade::alpha) {
+            alphas.push(alpha);
+            walk.update_world();
+        }
+        assert_eq!(alphas.len(), THEME_FADE_FRAMES as usize);
+        assert!(alphas.windows(2).all(|pair| pair[1] < pair[0]));
+        assert_eq!(walk.theme, 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_fading_theme_scrolls_with_the_current_one() {
+        let mut walk = test_walk();
+        walk.themes.push(HtmlImageElement::new().unwrap());
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.generate_next_segment();
+        walk.timeline = i16::MAX / 2;
+
+        walk.update_world();
+
+        let fade = walk.theme_fade.as_ref().unwrap();
+        assert_eq!(
+            fade.backgrounds[0].bounding_box().x(),
+            walk.backgrounds[0].bounding_box().x()
+        );
+    }
+
+    #[wasm_bindgen_test]
+    fn test_active_obstacles_stay_under_the_cap() {
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.obstacles = (0..MAX_ACTIVE_OBSTACLES as i16)
+            .map(|index| barrier_at(1000 + index * 10))
+            .collect();
+
+        for _ in 0..20 {
+            walk.timeline = 0;
+            walk.update_world();
+            assert!(walk.obstacles.len() <= MAX_ACTIVE_OBSTACLES);
+        }
+        assert_eq!(walk.obstacles.len(), MAX_ACTIVE_OBSTACLES);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_obstacle_ids_are_unique_and_stable() {
+        let mut obstacles = vec![barrier_at(0), barrier_at(100)];
+        obstacles.append(&mut low_barrier(HtmlImageElement::new().unwrap(), 200));
+        let ids: Vec<u32> = obstacles.iter().map(|obstacle| obstacle.id()).collect();
+
+        obstacles
+            .iter_mut()
+            .for_each(|obstacle| obstacle.move_horizontally(-50));
+        let moved_ids: Vec<u32> = obstacles.iter().map(|obstacle| obstacle.id()).collect();
+
+        assert_eq!(ids, moved_ids);
+        assert!(ids[0] != ids[1] && ids[1] != ids[2] && ids[0] != ids[2]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_ghost_follows_the_recorded_track_by_distance() {
+        let mut track = GhostTrack::default();
+        for frame in 0..10 {
+            track.record(
+                frame * 4,
+                479 - frame as i16,
+                format!("Run ({}).png", frame + 1),
+            );
+        }
+        let track: GhostTrack = JsValue::from_serde(&track).unwrap().into_serde().unwrap();
+
+        let frame = track.at(20).unwrap();
+        assert_eq!(frame.y, 474);
+        assert_eq!(frame.sprite, "Run (6).png");
+        assert_eq!(track.at(22).unwrap().y, 474);
+        assert!(track.at(40).is_none());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_reset_puts_the_run_back_to_a_fresh_start() {
+        let fresh = test_walk();
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.obstacles = vec![barrier_at(walk.boy.bounding_box().x() + 2)];
+        for _ in 0..10 {
+            walk.update_world();
+        }
+        walk.score = 120;
+        walk.boy.knock_out();
+
+        walk.reset();
+
+        assert_eq!(walk.boy.state_name(), "Idle");
+        assert_eq!(walk.boy.pos_y(), fresh.boy.pos_y());
+        assert_eq!(walk.boy.walking_speed(), fresh.boy.walking_speed());
+        assert_eq!(walk.backgrounds[0].bounding_box().x(), 0);
+        assert_eq!(
+            walk.backgrounds[1].bounding_box().x(),
+            walk.backgrounds[0].right()
+        );
+        assert_eq!(walk.timeline, rightmost(&walk.obstacles));
+        assert!(walk.passed_obstacles.is_empty());
+        assert_eq!(walk.score, fresh.score);
+        assert_eq!(walk.combo, fresh.combo);
+        assert_eq!(walk.distance, fresh.distance);
+        assert_eq!(walk.theme, fresh.theme);
+        assert_eq!(walk.theme_fade.as_ref().map(ThemeFade::alpha), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_longest_run_becomes_the_ghost() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        for _ in 0..10 {
+            walk.update_world();
+        }
+        walk.reset();
+
+        assert_eq!(walk.best_run.as_ref().unwrap().distance(), 40);
+        assert!(walk.ghost.frames.is_empty());
+    }
+
+    #[test]
+    fn queued_actions_are_drained_in_priority_order() {
+        let mut keystate = KeyState::new();
+        keystate.record_press("ArrowRight");
+        keystate.record_press("ArrowDown");
+        keystate.record_press("KeyQ");
+        keystate.record_press("Space");
+        keystate.record_press("ArrowDown");
+
+        assert_eq!(
+            Action::queued(&keystate),
+            vec![Action::Jump, Action::Slide, Action::Run]
+        );
+    }
+
     #[wasm_bindgen_test]
-    fn test_transition_from_game_over_to_new_game() {
-        let (_, receiver) = unbounded();
+    async fn test_loading_progress_advances_as_assets_resolve() {
+        let canvas = browser::create_canvas(WIDTH as u32, HEIGHT as u32).unwrap();
+        let renderer = Renderer::new(browser::context_of(&canvas).unwrap());
+        let loading = Loading::new(4);
+        assert_eq!(loading.progress(), 0.0);
+
+        let json = loading
+            .track(&renderer, "rhb.json", async { Ok("rhb.json") })
+            .await
+            .unwrap();
+        assert_eq!(json, "rhb.json");
+        assert_eq!(loading.progress(), 0.25);
+
+        loading
+            .track(&renderer, "BG.png", async { Ok(()) })
+            .await
+            .unwrap();
+        assert_eq!(loading.progress(), 0.5);
+
+        let failed: Result<()> = loading
+            .track(&renderer, "Stone.png", async { Err(anyhow!("404")) })
+            .await;
+        assert!(format!("{}", failed.err().unwrap()).contains("Stone.png"));
+        assert_eq!(loading.progress(), 0.5);
+    }
+
+    #[wasm_bindgen_test]
+    async fn test_assets_are_requested_before_any_of_them_finishes() {
+        let canvas = browser::create_canvas(WIDTH as u32, HEIGHT as u32).unwrap();
+        let renderer = Renderer::new(browser::context_of(&canvas).unwrap());
+        let loading = Loading::new(2);
+        let requested = Rc::new(RefCell::new(vec![]));
+
+        // 要求されたことを記録し、返事が来るまで終わらない読み込みの代役
+        let fake_load = |name: &'static str, response: oneshot::Receiver<()>| {
+            let requested = requested.clone();
+            async move {
+                requested.borrow_mut().push(name);
+                response.await.map_err(|_| anyhow!("Canceled"))
+            }
+        };
+        let (json_tx, json_rx) = oneshot::channel();
+        let (image_tx, image_rx) = oneshot::channel();
+
+        let all = futures::future::try_join(
+            loading.track(&renderer, "rhb.json", fake_load("rhb.json", json_rx)),
+            loading.track(&renderer, "rhb.png", fake_load("rhb.png", image_rx)),
+        );
+        futures::pin_mut!(all);
+        assert!(futures::poll!(&mut all).is_pending());
+        assert_eq!(*requested.borrow(), vec!["rhb.json", "rhb.png"]);
+        assert_eq!(loading.progress(), 0.0);
+
+        image_tx.send(()).unwrap();
+        json_tx.send(()).unwrap();
+        all.await.unwrap();
+        assert_eq!(loading.progress(), 1.0);
+    }
+
+    // 当たり判定の範囲だけを持つ、DOMに依存しない障害物
+    struct RectObstacle(Rect);
+
+    impl Obstacle for RectObstacle {
+        fn check_intersection(&mut self, _boy: &mut RedHatBoy) -> Option<Rect> {
+            None
+        }
+        fn draw(&self, _renderer: &Renderer) {}
+        fn outlines(&self) -> Vec<Rect> {
+            vec![self.0]
+        }
+
+        fn move_horizontally(&mut self, x: i16) {
+            self.0.set_x(self.0.x() + x);
+        }
+        fn left(&self) -> i16 {
+            self.0.x()
+        }
+        fn right(&self) -> i16 {
+            self.0.right()
+        }
+        fn id(&self) -> u32 {
+            0
+        }
+    }
+
+    #[test]
+    fn broadphase_finds_the_same_collisions_as_checking_every_obstacle() {
+        let rects: Vec<Rect> = (0..40)
+            .map(|index| {
+                Rect::new_from_x_y(index * 53 - 300, (index % 3) * 40, 20 + index % 90, 50)
+            })
+            .collect();
+        let obstacles: Vec<Box<dyn Obstacle>> = rects
+            .iter()
+            .map(|rect| Box::new(RectObstacle(*rect)) as Box<dyn Obstacle>)
+            .collect();
+
+        for boy_x in (-400..2000).step_by(37) {
+            let boy = Rect::new_from_x_y(boy_x, 30, 60, 90);
+            let naive: Vec<usize> = (0..rects.len())
+                .filter(|index| boy.intersects(&rects[*index]))
+                .collect();
+            let broad: Vec<usize> = broadphase(&obstacles, boy.x(), boy.right())
+                .into_iter()
+                .filter(|index| boy.intersects(&rects[*index]))
+                .collect();
+
+            assert_eq!(broad, naive, "boy at x {}", boy_x);
+        }
+    }
+
+    #[test]
+    fn parse_command_reads_known_commands() {
+        assert_eq!(parse_command("spawn_stone"), Some(DebugCommand::SpawnStone));
+        assert_eq!(
+            parse_command(" set_speed  8 "),
+            Some(DebugCommand::SetSpeed(8))
+        );
+        assert_eq!(parse_command("kill"), Some(DebugCommand::Kill));
+        assert_eq!(parse_command("god"), Some(DebugCommand::God));
+        assert_eq!(parse_command("peek"), Some(DebugCommand::Peek));
+    }
+
+    #[test]
+    fn parse_command_rejects_unknown_or_malformed_input() {
+        assert_eq!(parse_command(""), None);
+        assert_eq!(parse_command("fly"), None);
+        assert_eq!(parse_command("set_speed"), None);
+        assert_eq!(parse_command("set_speed fast"), None);
+        assert_eq!(parse_command("kill now"), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_god_mode_ignores_obstacles() {
+        let knocked_down_by_wall = |god_mode: bool| {
+            let mut walk = test_walk();
+            walk.timeline = i16::MAX / 2;
+            walk.boy
+                .run_right(Difficulty::Normal.settings().running_speed);
+            if god_mode {
+                walk.run_debug_command(DebugCommand::God);
+            }
+            let boy_box = walk.boy.bounding_box();
+            walk.obstacles = vec![platform_at(
+                boy_box.right() - 5,
+                Rect::new_from_x_y(0, boy_box.y(), 100, boy_box.height),
+            )];
+            walk.update_world();
+            walk.knocked_down()
+        };
+
+        assert!(knocked_down_by_wall(false));
+        assert!(!knocked_down_by_wall(true));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_practice_mode_flashes_instead_of_knocking_out_and_keeps_no_record() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy.set_invincible(true);
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        walk.obstacles = vec![platform_at(
+            boy_box.right() - 5,
+            Rect::new_from_x_y(0, boy_box.y(), 100, boy_box.height),
+        )];
+
+        walk.update_world();
+
+        assert!(!walk.knocked_down());
+        assert_eq!(walk.boy.state_name(), "Running");
+        assert!(walk.boy.state_machine.context().shield_grace_frames > 0);
+        assert_eq!(walk.ghost.distance(), 0);
+
+        walk.reset();
+        assert!(walk.boy.is_invincible());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_conveyor_platform_changes_scrolling_speed() {
+        let velocity_on = |conveyor_speed: i16| {
+            let mut walk = test_walk();
+            walk.timeline = i16::MAX / 2;
+            walk.boy
+                .run_right(Difficulty::Normal.settings().running_speed);
+            let boy_box = walk.boy.bounding_box();
+            walk.obstacles = vec![Box::new(
+                test_platform(
+                    boy_box.x(),
+                    Rect::new_from_x_y(0, boy_box.bottom() - 1, 300, 20),
+                )
+                .with_conveyor(conveyor_speed),
+            )];
+            walk.boy.update();
+            walk.update_world();
+            walk.velocity()
+        };
+
+        assert_eq!(velocity_on(3), velocity_on(0) - 3);
+        assert_eq!(velocity_on(-2), velocity_on(0) + 2);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_generated_conveyor_platform_carries_the_boy() {
+        let velocity_on = |conveyor_speed: i16| {
+            let mut walk = test_walk();
+            walk.boy
+                .run_right(Difficulty::Normal.settings().running_speed);
+            let boy_box = walk.boy.bounding_box();
+            generate_segment_with(
+                &mut walk,
+                vec![ObstacleData::Platform {
+                    x: boy_box.x(),
+                    y: boy_box.bottom() - 1,
+                    tiles: vec![],
+                    bounding_boxes: vec![Rect::new_from_x_y(0, 0, 300, 20)],
+                    conveyor_speed,
+                }],
+            );
+            walk.boy.update();
+            walk.update_world();
+            walk.velocity()
+        };
+
+        assert_eq!(velocity_on(3), velocity_on(0) - 3);
+    }
+
+    #[test]
+    fn test_palettes_give_each_obstacle_category_its_own_color() {
+        let categories = [
+            ObstacleCategory::Hazard,
+            ObstacleCategory::Platform,
+            ObstacleCategory::Pickup,
+            ObstacleCategory::Goal,
+        ];
+        for palette in [Palette::Standard, Palette::Colorblind] {
+            let mut colors: Vec<&str> = categories
+                .iter()
+                .map(|category| palette.color(ColorRole::Obstacle(*category)))
+                .chain(std::iter::once(palette.color(ColorRole::Boy)))
+                .collect();
+            colors.sort_unstable();
+            colors.dedup();
+            assert_eq!(colors.len(), categories.len() + 1);
+        }
+        for role in [
+            ColorRole::DamageFlash,
+            ColorRole::PowerShield,
+            ColorRole::ShieldBreak,
+            ColorRole::Magnet,
+            ColorRole::VelocityLine,
+        ] {
+            assert_ne!(
+                Palette::Standard.color(role),
+                Palette::Colorblind.color(role)
+            );
+        }
+        assert_eq!(Palette::from_name("Colorblind"), Some(Palette::Colorblind));
+    }
+
+    #[wasm_bindgen_test]
+    fn test_high_contrast_outlines_every_obstacle() {
+        let canvas = browser::create_canvas(WIDTH as u32, HEIGHT as u32).unwrap();
+        let context = browser::context_of(&canvas).unwrap();
+        let renderer = Renderer::new(context.clone());
+        let mut walk = test_walk();
+        walk.obstacles = vec![
+            Box::new(RectObstacle(Rect::new_from_x_y(300, 100, 50, 50))),
+            Box::new(RectObstacle(Rect::new_from_x_y(450, 200, 50, 50))),
+        ];
+        let outline_pixel = |x: f64, y: f64| {
+            context
+                .get_image_data(x, y, 1.0, 1.0)
+                .unwrap()
+                .data()
+                .to_vec()
+        };
+
+        walk.draw(&renderer, 1.0);
+        assert_eq!(outline_pixel(300.0, 125.0), vec![0, 0, 0, 0]);
+
+        walk.settings.high_contrast = true;
+        walk.draw(&renderer, 1.0);
+        assert_eq!(outline_pixel(300.0, 125.0), vec![0, 255, 255, 255]);
+        assert_eq!(outline_pixel(450.0, 225.0), vec![0, 255, 255, 255]);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_last_collision_is_set_only_on_the_frame_of_the_hit() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
         let image = HtmlImageElement::new().unwrap();
-        let audio = Audio::new().unwrap();
-        let options = AudioBufferOptions::new(1, 3000.0);
-        let sound = Sound {
-            buffer: AudioBuffer::new(&options).unwrap(),
+        image.set_width(10);
+        image.set_height(10);
+        walk.obstacles = vec![Box::new(Coin::new(Image::new(
+            image,
+            Point {
+                x: boy_box.x() + 5,
+                y: boy_box.y() + 5,
+            },
+        )))];
+
+        walk.update_world();
+        let hit = walk.last_collision.expect("The coin was not hit");
+        assert_eq!(hit.width, 10);
+
+        walk.update_world();
+        assert!(walk.last_collision.is_none());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_running_on_a_platform_is_not_a_collision() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        walk.obstacles = vec![Box::new(test_platform(
+            boy_box.x(),
+            Rect::new_from_x_y(0, boy_box.bottom() - 1, 300, 20),
+        ))];
+
+        for _ in 0..5 {
+            walk.update_world();
+            assert!(walk.last_collision.is_none());
+        }
+        assert!(!walk.knocked_down());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_crumbling_platform_stops_colliding_after_the_delay() {
+        let running_boy = || {
+            let mut boy = test_walk().boy;
+            boy.run_right(Difficulty::Normal.settings().running_speed);
+            boy.update();
+            boy
         };
-        let rhb = RedHatBoy::new(
-            Sheet {
-                frames: HashMap::new(),
+        let mut boy = running_boy();
+        let boy_box = boy.bounding_box();
+        let mut platform = test_platform(
+            boy_box.x(),
+            Rect::new_from_x_y(0, boy_box.bottom() - 1, 300, 20),
+        )
+        .with_conveyor(3);
+        platform.make_crumbling();
+
+        platform.check_intersection(&mut boy);
+        assert_eq!(boy.conveyor_speed(), 3);
+        assert_eq!(platform.crumble_frames, Some(CRUMBLE_FRAMES));
+
+        for _ in 0..CRUMBLE_FRAMES {
+            platform.update();
+        }
+        let mut boy = running_boy();
+        platform.check_intersection(&mut boy);
+
+        assert!(platform.crumbled());
+        assert_eq!(boy.conveyor_speed(), 0);
+    }
+
+    fn bounced_from(prepare: fn(&mut RedHatBoy)) -> RedHatBoy {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        prepare(&mut boy);
+        boy.update();
+        boy.bounce(-17);
+        boy
+    }
+
+    #[wasm_bindgen_test]
+    fn test_bounce_from_each_moving_state_jumps_at_the_given_speed() {
+        let bounced = [
+            bounced_from(|_boy| {}),
+            bounced_from(|boy| boy.jump()),
+            bounced_from(|boy| boy.slide()),
+        ];
+
+        for boy in bounced.iter() {
+            assert_eq!(boy.state_name(), "Jumping");
+            assert_eq!(boy.velocity_y(), -17);
+            assert_eq!(boy.state_machine.context().frame, 0);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_bounce_is_ignored_while_knocked_out() {
+        let boy = bounced_from(|boy| boy.knock_out());
+
+        assert_eq!(boy.state_name(), "Falling");
+        assert_ne!(boy.velocity_y(), -17);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_springboard_launches_higher_than_a_jump() {
+        let mut jumping = test_walk().boy;
+        jumping.run_right(Difficulty::Normal.settings().running_speed);
+        jumping.jump();
+
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = boy.bounding_box();
+        let image = HtmlImageElement::new().unwrap();
+        image.set_width(60);
+        image.set_height(20);
+        let mut springboard = Springboard::new(Image::new(
+            image,
+            Point {
+                x: boy_box.x(),
+                y: boy_box.bottom() - 10,
             },
-            image.clone(),
-            audio,
-            sound,
+        ));
+        springboard.check_intersection(&mut boy);
+
+        assert_eq!(boy.state_name(), "Jumping");
+        assert!(boy.velocity_y() < jumping.velocity_y());
+    }
+
+    // segments.json にobstaclesだけのセグメントがあるものとして、次のセグメントを生成する
+    // 生成する位置を0にずらすので、obstaclesのx, yはそのまま画面上の位置になる
+    fn generate_segment_with(walk: &mut Walk, obstacles: Vec<ObstacleData>) {
+        walk.segments = vec![SegmentData {
+            name: "test".to_string(),
+            obstacles,
+        }];
+        walk.segment_source = SegmentSource::Sequence(vec![SegmentKind::Loaded(0)]);
+        walk.timeline = -walk.current_difficulty().obstacle_buffer;
+        walk.generate_next_segment();
+        walk.timeline = i16::MAX / 2;
+    }
+
+    fn sized_image(width: u32, height: u32) -> HtmlImageElement {
+        let image = HtmlImageElement::new().unwrap();
+        image.set_width(width);
+        image.set_height(height);
+        image
+    }
+
+    #[wasm_bindgen_test]
+    fn test_generated_springboard_bounces_the_boy() {
+        let mut walk = test_walk();
+        walk.props.springboard = sized_image(90, 24);
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        generate_segment_with(
+            &mut walk,
+            vec![ObstacleData::Springboard {
+                x: boy_box.x(),
+                y: boy_box.bottom() - 10,
+            }],
         );
+        assert_eq!(walk.obstacles.len(), 1);
 
-        let sprite_sheet = SpriteSheet::new(
-            Sheet {
-                frames: HashMap::new(),
+        walk.update_world();
+
+        assert_eq!(walk.boy.state_name(), "Jumping");
+        assert_eq!(walk.boy.velocity_y(), SPRINGBOARD_VELOCITY);
+    }
+
+    fn dog_at(x: i16, y: i16, height: i16) -> Box<dyn Obstacle> {
+        let image = HtmlImageElement::new().unwrap();
+        image.set_width(60);
+        image.set_height(height as u32);
+        Box::new(Dog::new(Image::new(image, Point { x, y })))
+    }
+
+    #[wasm_bindgen_test]
+    fn test_stomping_a_dog_defeats_it_and_bounces_the_boy() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        walk.boy.jump();
+        while walk.boy.velocity_y() <= 0 {
+            walk.boy.update();
+        }
+        let boy_box = walk.boy.bounding_box();
+        walk.obstacles = vec![dog_at(boy_box.x() + 10, boy_box.bottom() - 5, 40)];
+
+        walk.update_world();
+
+        assert!(walk.obstacles.is_empty());
+        assert_eq!(walk.boy.state_name(), "Jumping");
+        assert_eq!(walk.boy.velocity_y(), STOMP_VELOCITY);
+        assert_eq!(walk.score, DOG_SCORE);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_generated_dog_runs_in_and_knocks_the_boy_out() {
+        let mut walk = test_walk();
+        walk.props.dog = sized_image(80, 56);
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        generate_segment_with(
+            &mut walk,
+            vec![ObstacleData::Dog {
+                x: boy_box.right() + 20,
+                y: boy_box.bottom() - 56,
+            }],
+        );
+
+        let mut frames = 0;
+        while !walk.knocked_down() {
+            walk.update_world();
+            frames += 1;
+            assert!(frames < 60, "The dog never reached the boy");
+        }
+        assert_eq!(walk.obstacles.len(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_running_into_a_dog_knocks_the_boy_out() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        walk.obstacles = vec![dog_at(boy_box.right() - 5, boy_box.y(), boy_box.height)];
+
+        walk.update_world();
+
+        assert!(walk.knocked_down());
+        assert_eq!(walk.obstacles.len(), 1);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_shield_absorbs_one_hit() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        let image = HtmlImageElement::new().unwrap();
+        image.set_width(30);
+        image.set_height(30);
+        walk.obstacles = vec![Box::new(PowerUp::new(Image::new(
+            image,
+            Point {
+                x: boy_box.x() + 10,
+                y: boy_box.y(),
             },
-            image.clone(),
+        )))];
+
+        walk.update_world();
+        assert!(walk.obstacles.is_empty());
+        assert!(walk.boy.is_shielded());
+
+        walk.boy.knock_out();
+        assert_eq!(walk.boy.state_name(), "Running");
+        assert!(!walk.boy.is_shielded());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_generated_shield_is_picked_up_and_absorbs_the_stone_behind_it() {
+        let mut walk = test_walk();
+        walk.props.shield = sized_image(36, 36);
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let boy_box = walk.boy.bounding_box();
+        generate_segment_with(
+            &mut walk,
+            vec![ObstacleData::Shield {
+                x: boy_box.x() + 10,
+                y: boy_box.y() + 10,
+            }],
         );
-        let walk = Walk {
-            boy: rhb,
-            backgrounds: [
-                Image::new(image.clone(), Point { x: 0, y: 0 }),
-                Image::new(image.clone(), Point { x: 0, y: 0 }),
-            ],
-            obstacles: vec![],
-            obstacle_sheet: Rc::new(sprite_sheet),
-            stone: image.clone(),
-            timeline: 0,
+
+        walk.update_world();
+        assert!(walk.obstacles.is_empty());
+        assert!(walk.boy.is_shielded());
+
+        walk.obstacles = vec![Box::new(Barrier::new(Image::new(
+            sized_image(30, 30),
+            Point {
+                x: boy_box.x() + 10,
+                y: boy_box.y() + 10,
+            },
+        )))];
+        walk.update_world();
+        assert!(!walk.knocked_down());
+        assert!(!walk.boy.is_shielded());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_boy_falls_to_the_next_hit_once_the_shield_is_gone() {
+        let mut boy = test_walk().boy;
+        boy.run_right(Difficulty::Normal.settings().running_speed);
+        boy.give_shield();
+        boy.knock_out();
+
+        for _ in 0..SHIELD_GRACE_FRAMES {
+            boy.update();
+        }
+        boy.knock_out();
+
+        assert_eq!(boy.state_name(), "Falling");
+    }
+
+    #[wasm_bindgen_test]
+    fn test_magnet_pulls_nearby_coin_toward_boy() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy.give_magnet();
+        let center = walk.boy.center();
+        let image = HtmlImageElement::new().unwrap();
+        image.set_width(10);
+        image.set_height(10);
+        walk.obstacles = vec![Box::new(Coin::new(Image::new(
+            image,
+            Point {
+                x: center.x + 100,
+                y: center.y - 100,
+            },
+        )))];
+        let distance = |walk: &Walk| {
+            let coin = walk.obstacles[0].left();
+            (coin - walk.boy.center().x).abs()
         };
 
-        let document = browser::document().unwrap();
-        document
-            .body()
+        let before = distance(&walk);
+        for _ in 0..5 {
+            walk.update_world();
+        }
+
+        assert_eq!(walk.obstacles.len(), 1);
+        assert!(distance(&walk) < before);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_generated_magnet_pulls_in_a_coin_out_of_reach() {
+        let run = |with_magnet: bool| {
+            let mut walk = test_walk();
+            walk.props.magnet = sized_image(36, 36);
+            walk.props.coin = sized_image(30, 30);
+            walk.boy
+                .run_right(Difficulty::Normal.settings().running_speed);
+            let boy_box = walk.boy.bounding_box();
+            // 走っているだけでは届かない、頭の上のコイン
+            let coin = ObstacleData::Coin {
+                x: boy_box.x() + 60,
+                y: boy_box.y() - 100,
+            };
+            let magnet = ObstacleData::Magnet {
+                x: boy_box.x() + 10,
+                y: boy_box.y() + 10,
+            };
+            let obstacles = if with_magnet {
+                vec![magnet, coin]
+            } else {
+                vec![coin]
+            };
+            generate_segment_with(&mut walk, obstacles);
+
+            // 磁石がなければ、コインはまだ少年を追い越していない
+            for _ in 0..20 {
+                walk.update_world();
+            }
+            (walk.score, walk.stats.coins)
+        };
+
+        assert_eq!(run(false), (0, 0));
+        assert_eq!(run(true), (COIN_SCORE, 1));
+    }
+
+    // 当たり判定のうち、いちばん上の辺
+    fn top(obstacle: &dyn Obstacle) -> i16 {
+        obstacle
+            .outlines()
+            .iter()
+            .map(|outline| outline.y())
+            .min()
             .unwrap()
-            .insert_adjacent_html("afterbegin", "<div id='ui'></div>")
-            .unwrap();
-        browser::draw_ui("<p>This is the UI</p>").unwrap();
-        let state = WalkTheDogState {
+    }
+
+    #[wasm_bindgen_test]
+    fn test_y_offset_shifts_only_the_platforms() {
+        let walk = test_walk();
+        for segment in [stone_and_platform, platform_and_stone] {
+            let tops = |y_offset| -> Vec<(ObstacleCategory, i16)> {
+                segment(walk.stone.clone(), walk.obstacle_sheet.clone(), 0, y_offset)
+                    .iter()
+                    .map(|obstacle| (obstacle.category(), top(obstacle.as_ref())))
+                    .collect()
+            };
+
+            let expected: Vec<(ObstacleCategory, i16)> = tops(0)
+                .into_iter()
+                .map(|(category, top)| match category {
+                    ObstacleCategory::Platform => (category, top - 40),
+                    _ => (category, top),
+                })
+                .collect();
+            assert_eq!(tops(-40), expected);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_nothing_can_be_hit_within_the_grace_distance() {
+        let mut walk = test_walk();
+        walk.reset();
+        let grace = walk.difficulty.grace_distance;
+        assert!(grace > 0);
+        assert_eq!(Difficulty::Hard.settings().grace_distance, 0);
+
+        // 障害物も少年と同じ速さで近づくので、間が猶予より広ければ猶予の間は当たらない
+        let boy_right = walk.boy.bounding_box().right();
+        assert!(walk
+            .obstacles
+            .iter()
+            .all(|obstacle| obstacle.left() - boy_right >= grace));
+
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+        let obstacle_count = walk.obstacles.len();
+        while walk.distance < i32::from(grace) {
+            walk.update_world();
+            assert!(walk.last_collision.is_none());
+            assert!(!walk.knocked_down());
+        }
+        assert_eq!(walk.obstacles.len(), obstacle_count);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_peek_next_segment_matches_the_generated_segment() {
+        let mut walk = test_walk();
+        let lefts = |obstacles: &[Box<dyn Obstacle>]| -> Vec<i16> {
+            obstacles.iter().map(|obstacle| obstacle.left()).collect()
+        };
+
+        for _ in 0..10 {
+            let kind = walk.peek_next_segment();
+            assert_eq!(walk.peek_next_segment(), kind);
+            let offset_x = walk.timeline + walk.current_difficulty().obstacle_buffer;
+            let expected = lefts(&walk.build_segment(kind, offset_x));
+
+            walk.obstacles.clear();
+            walk.generate_next_segment();
+
+            assert_eq!(lefts(&walk.obstacles), expected);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_every_run_starts_with_the_tutorial() {
+        let mut walk = test_walk();
+        let expected: Vec<i16> = tutorial(walk.stone.clone(), walk.difficulty.grace_distance)
+            .iter()
+            .map(|obstacle| obstacle.left())
+            .collect();
+
+        for _ in 0..3 {
+            walk.obstacles.clear();
+            walk.generate_next_segment();
+            walk.reset();
+
+            let lefts: Vec<i16> = walk
+                .obstacles
+                .iter()
+                .map(|obstacle| obstacle.left())
+                .collect();
+            assert_eq!(lefts, expected);
+        }
+    }
+
+    #[wasm_bindgen_test]
+    fn test_set_speed_overrides_scrolling_speed() {
+        let mut walk = test_walk();
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+
+        walk.run_debug_command(DebugCommand::SetSpeed(9));
+
+        assert_eq!(walk.velocity(), -9);
+    }
+
+    #[test]
+    fn notification_is_shown_until_it_expires() {
+        let mut notifications = Notifications::default();
+        notifications.push("100m!".to_string());
+        notifications.push("First dash!".to_string());
+
+        for _ in 0..NOTIFICATION_FRAMES - 1 {
+            notifications.update();
+        }
+        assert_eq!(notifications.current().unwrap().text, "100m!");
+
+        notifications.update();
+        assert_eq!(notifications.current().unwrap().text, "First dash!");
+
+        for _ in 0..NOTIFICATION_FRAMES {
+            notifications.update();
+        }
+        assert!(notifications.current().is_none());
+    }
+
+    #[wasm_bindgen_test]
+    fn test_first_dash_is_announced_once() {
+        let mut walk = test_walk();
+        walk.timeline = i16::MAX / 2;
+        walk.boy
+            .run_right(Difficulty::Normal.settings().running_speed);
+
+        let machine = walking_for(walk, &["ShiftLeft"], 2);
+        assert_eq!(
+            machine.walk().notifications.current().unwrap().text,
+            "First dash!"
+        );
+        assert_eq!(machine.walk().notifications.queue.len(), 1);
+    }
+
+    #[test]
+    fn night_gets_darker_towards_the_middle_of_the_day_cycle() {
+        assert_eq!(night_alpha(0), 0.0);
+        assert_eq!(night_alpha(DAY_LENGTH / 4), MAX_NIGHT_ALPHA / 2.0);
+        assert_eq!(night_alpha(DAY_LENGTH / 2), MAX_NIGHT_ALPHA);
+        assert_eq!(night_alpha(DAY_LENGTH * 3 / 4), MAX_NIGHT_ALPHA / 2.0);
+        assert_eq!(night_alpha(DAY_LENGTH), 0.0);
+    }
+
+    #[test]
+    fn difficulty_is_parsed_from_its_name() {
+        assert_eq!(Difficulty::from_name("Hard"), Some(Difficulty::Hard));
+        assert_eq!(Difficulty::from_name("easy"), Some(Difficulty::Easy));
+        assert_eq!(Difficulty::from_name("impossible"), None);
+    }
+
+    #[wasm_bindgen_test]
+    fn test_transition_from_game_over_to_main_menu_on_enter() {
+        let (_, receiver) = unbounded();
+        let machine = WalkTheDogStateMachine::GameOver(WalkTheDogState {
             _state: GameOver {
                 new_game_event: receiver,
             },
-            walk,
-        };
+            walk: test_walk(),
+        })
+        .update(&keystate_with(&["Enter"]));
 
-        state.new_game();
-        let ui = browser::find_html_element_by_id("ui").unwrap();
-        assert_eq!(ui.child_element_count(), 0);
+        assert!(matches!(machine, WalkTheDogStateMachine::MainMenu(_)));
     }
 }
@@ -1,7 +1,11 @@
 use anyhow::{anyhow, Result};
-use wasm_bindgen::JsValue;
+use wasm_bindgen::JsCast;
 use web_sys::HtmlElement;
 
+pub use crate::browser::{
+    context, create_text_input, load_item, post_json, print, query_param, save_item, spawn_local,
+};
+
 pub fn draw_ui(_html: &str) -> Result<()> {
     Ok(())
 }
@@ -11,9 +15,9 @@ pub fn hide_ui() -> Result<()> {
 }
 
 pub fn find_html_element_by_id(_id: &str) -> Result<HtmlElement> {
-    Err(anyhow!("Not implemented yet!"))
-}
-
-pub async fn fetch_json(_json_path: &str) -> Result<JsValue> {
-    Err(anyhow!("Not implemented yet!"))
+    crate::browser::document()?
+        .create_element("button")
+        .map_err(|err| anyhow!("Could not create element {:#?}", err))?
+        .dyn_into::<HtmlElement>()
+        .map_err(|err| anyhow!("Could not cast into HtmlElement {:#?}", err))
 }
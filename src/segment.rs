@@ -1,10 +1,14 @@
-use std::rc::Rc;
+use std::{
+    rc::Rc,
+    sync::atomic::{AtomicU32, Ordering},
+};
 
+use serde::Deserialize;
 use web_sys::HtmlImageElement;
 
 use crate::{
     engine::{Image, Point, Rect, SpriteSheet},
-    game::{Barrier, Obstacle, Platform},
+    game::{Barrier, Coin, Dog, LowBarrier, Obstacle, Platform, PowerUp, PowerUpKind, Springboard},
 };
 
 const LOW_PLATFORM: i16 = 420;
@@ -12,6 +16,15 @@ const HIGH_PLATFORM: i16 = 375;
 const FIRST_PLATFORM: i16 = 370;
 
 const STONE_ON_GROUND: i16 = 546;
+// 立っている少年の頭より低く、スライディング中の頭より高い位置に下端が来る
+const STONE_OVERHEAD: i16 = 471;
+
+// 障害物を作るたびに増えていく通し番号
+static NEXT_OBSTACLE_ID: AtomicU32 = AtomicU32::new(0);
+
+pub fn next_obstacle_id() -> u32 {
+    NEXT_OBSTACLE_ID.fetch_add(1, Ordering::Relaxed)
+}
 
 const FLOATING_PLATFORM_SPRITES: [&str; 3] = ["13.png", "14.png", "15.png"];
 const PLATFORM_WIDTH: i16 = 384;
@@ -34,10 +47,47 @@ const FLOATING_PLATFORM_BOUNDING_BOXES: [Rect; 3] = [
     ),
 ];
 
+// 地面に石を一つだけ置く。デバッグコンソールから呼ぶ
+pub fn lone_stone(stone: HtmlImageElement, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
+    vec![Box::new(Barrier::new(Image::new(
+        stone,
+        Point {
+            x: offset_x,
+            y: STONE_ON_GROUND,
+        },
+    )))]
+}
+
+// 最初に必ず出す、ジャンプとスライディングを一つずつ練習するセグメント
+// ランダムな生成に切り替わる前に、間を広くとって順番に見せる
+pub fn tutorial(stone: HtmlImageElement, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
+    const JUMP_STONE_OFFSET: i16 = 400;
+    const SLIDE_BARRIER_OFFSET: i16 = 1100;
+
+    vec![
+        Box::new(Barrier::new(Image::new(
+            stone.clone(),
+            Point {
+                x: offset_x + JUMP_STONE_OFFSET,
+                y: STONE_ON_GROUND,
+            },
+        ))),
+        Box::new(LowBarrier::new(Image::new(
+            stone,
+            Point {
+                x: offset_x + SLIDE_BARRIER_OFFSET,
+                y: STONE_OVERHEAD,
+            },
+        ))),
+    ]
+}
+
+// y_offsetは足場を上下にずらす量。負の値で高くなる。石は地面に置いたまま
 pub fn stone_and_platform(
     stone: HtmlImageElement,
     sprite_sheet: Rc<SpriteSheet>,
     offset_x: i16,
+    y_offset: i16,
 ) -> Vec<Box<dyn Obstacle>> {
     const INITIAL_STONE_OFFSET: i16 = 150;
     vec![
@@ -52,7 +102,7 @@ pub fn stone_and_platform(
             sprite_sheet,
             Point {
                 x: offset_x + FIRST_PLATFORM,
-                y: LOW_PLATFORM,
+                y: LOW_PLATFORM + y_offset,
             },
         )),
     ]
@@ -62,6 +112,7 @@ pub fn platform_and_stone(
     stone: HtmlImageElement,
     sprite_sheet: Rc<SpriteSheet>,
     offset_x: i16,
+    y_offset: i16,
 ) -> Vec<Box<dyn Obstacle>> {
     const INITIAL_STONE_OFFSET: i16 = 150;
 
@@ -70,7 +121,7 @@ pub fn platform_and_stone(
             sprite_sheet,
             Point {
                 x: offset_x + INITIAL_STONE_OFFSET,
-                y: HIGH_PLATFORM,
+                y: HIGH_PLATFORM + y_offset,
             },
         )),
         Box::new(Barrier::new(Image::new(
@@ -83,6 +134,174 @@ pub fn platform_and_stone(
     ]
 }
 
+pub fn low_barrier(stone: HtmlImageElement, offset_x: i16) -> Vec<Box<dyn Obstacle>> {
+    const INITIAL_STONE_OFFSET: i16 = 150;
+
+    vec![Box::new(LowBarrier::new(Image::new(
+        stone,
+        Point {
+            x: offset_x + INITIAL_STONE_OFFSET,
+            y: STONE_OVERHEAD,
+        },
+    )))]
+}
+
+// segments.json で石と足場以外に置けるものの画像
+#[derive(Clone)]
+pub struct PropImages {
+    pub springboard: HtmlImageElement,
+    pub dog: HtmlImageElement,
+    pub shield: HtmlImageElement,
+    pub magnet: HtmlImageElement,
+    pub coin: HtmlImageElement,
+}
+
+// segments.json に書く障害物。x, y はセグメントの左端からの位置
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ObstacleData {
+    Stone {
+        x: i16,
+        y: i16,
+    },
+    LowBarrier {
+        x: i16,
+        y: i16,
+    },
+    Platform {
+        x: i16,
+        y: i16,
+        tiles: Vec<String>,
+        // 足場の左上からの相対位置
+        bounding_boxes: Vec<Rect>,
+        // 上に乗った少年を運ぶ速さ。書かなければ普通の足場
+        #[serde(default)]
+        conveyor_speed: i16,
+    },
+    Springboard {
+        x: i16,
+        y: i16,
+    },
+    // 置いた位置から左へ走ってくる
+    Dog {
+        x: i16,
+        y: i16,
+    },
+    // 取ると次の一撃を一度だけ防ぐ
+    Shield {
+        x: i16,
+        y: i16,
+    },
+    // 取るとしばらくの間、近くのコインを引き寄せる
+    Magnet {
+        x: i16,
+        y: i16,
+    },
+    Coin {
+        x: i16,
+        y: i16,
+    },
+}
+
+#[derive(Deserialize)]
+pub struct SegmentData {
+    // JSONを読む人が見分けるための名前。ゲームでは使わない
+    #[allow(dead_code)]
+    pub name: String,
+    pub obstacles: Vec<ObstacleData>,
+}
+
+// JSONで定義したセグメントを、offset_xの位置に並べる
+pub fn load_segment(
+    segment: &SegmentData,
+    stone: HtmlImageElement,
+    sprite_sheet: Rc<SpriteSheet>,
+    props: &PropImages,
+    offset_x: i16,
+) -> Vec<Box<dyn Obstacle>> {
+    segment
+        .obstacles
+        .iter()
+        .map(|obstacle| -> Box<dyn Obstacle> {
+            match obstacle {
+                ObstacleData::Stone { x, y } => Box::new(Barrier::new(Image::new(
+                    stone.clone(),
+                    Point {
+                        x: offset_x + x,
+                        y: *y,
+                    },
+                ))),
+                ObstacleData::LowBarrier { x, y } => Box::new(LowBarrier::new(Image::new(
+                    stone.clone(),
+                    Point {
+                        x: offset_x + x,
+                        y: *y,
+                    },
+                ))),
+                ObstacleData::Platform {
+                    x,
+                    y,
+                    tiles,
+                    bounding_boxes,
+                    conveyor_speed,
+                } => {
+                    let tiles: Vec<&str> = tiles.iter().map(String::as_str).collect();
+                    Box::new(
+                        Platform::new(
+                            sprite_sheet.clone(),
+                            Point {
+                                x: offset_x + x,
+                                y: *y,
+                            },
+                            &tiles,
+                            bounding_boxes,
+                        )
+                        .with_conveyor(*conveyor_speed),
+                    )
+                }
+                ObstacleData::Springboard { x, y } => Box::new(Springboard::new(Image::new(
+                    props.springboard.clone(),
+                    Point {
+                        x: offset_x + x,
+                        y: *y,
+                    },
+                ))),
+                ObstacleData::Dog { x, y } => Box::new(Dog::new(Image::new(
+                    props.dog.clone(),
+                    Point {
+                        x: offset_x + x,
+                        y: *y,
+                    },
+                ))),
+                ObstacleData::Shield { x, y } => Box::new(PowerUp::new(Image::new(
+                    props.shield.clone(),
+                    Point {
+                        x: offset_x + x,
+                        y: *y,
+                    },
+                ))),
+                ObstacleData::Magnet { x, y } => Box::new(PowerUp::with_kind(
+                    Image::new(
+                        props.magnet.clone(),
+                        Point {
+                            x: offset_x + x,
+                            y: *y,
+                        },
+                    ),
+                    PowerUpKind::Magnet,
+                )),
+                ObstacleData::Coin { x, y } => Box::new(Coin::new(Image::new(
+                    props.coin.clone(),
+                    Point {
+                        x: offset_x + x,
+                        y: *y,
+                    },
+                ))),
+            }
+        })
+        .collect()
+}
+
 fn create_floating_platform(sprite_sheet: Rc<SpriteSheet>, position: Point) -> Platform {
     Platform::new(
         sprite_sheet,
@@ -91,3 +310,62 @@ fn create_floating_platform(sprite_sheet: Rc<SpriteSheet>, position: Point) -> P
         &FLOATING_PLATFORM_BOUNDING_BOXES,
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use gloo_utils::format::JsValueSerdeExt;
+    use wasm_bindgen_test::wasm_bindgen_test;
+
+    const SAMPLE_SEGMENT: &str = r#"{
+        "name": "sample",
+        "obstacles": [
+            { "type": "stone", "x": 150, "y": 546 },
+            {
+                "type": "platform",
+                "x": 400,
+                "y": 420,
+                "tiles": ["13.png"],
+                "bounding_boxes": [
+                    { "position": { "x": 0, "y": 0 }, "width": 60, "height": 54 }
+                ]
+            },
+            { "type": "low_barrier", "x": 700, "y": 471 },
+            { "type": "springboard", "x": 900, "y": 576 },
+            { "type": "dog", "x": 1100, "y": 544 },
+            { "type": "shield", "x": 1300, "y": 500 },
+            { "type": "magnet", "x": 1400, "y": 500 },
+            { "type": "coin", "x": 1500, "y": 400 }
+        ]
+    }"#;
+
+    #[wasm_bindgen_test]
+    fn test_load_segment_places_obstacles_from_json() {
+        let segment: SegmentData = js_sys::JSON::parse(SAMPLE_SEGMENT)
+            .unwrap()
+            .into_serde()
+            .unwrap();
+        let stone = HtmlImageElement::new().unwrap();
+        stone.set_width(90);
+        let tiles = js_sys::JSON::parse(include_str!("../static/tiles.json")).unwrap();
+        let sprite_sheet = Rc::new(SpriteSheet::new(
+            tiles.into_serde().unwrap(),
+            HtmlImageElement::new().unwrap(),
+        ));
+
+        let props = PropImages {
+            springboard: HtmlImageElement::new().unwrap(),
+            dog: HtmlImageElement::new().unwrap(),
+            shield: HtmlImageElement::new().unwrap(),
+            magnet: HtmlImageElement::new().unwrap(),
+            coin: HtmlImageElement::new().unwrap(),
+        };
+
+        let obstacles = load_segment(&segment, stone, sprite_sheet, &props, 1000);
+
+        let lefts: Vec<i16> = obstacles.iter().map(|obstacle| obstacle.left()).collect();
+        assert_eq!(lefts, vec![1150, 1400, 1700, 1900, 2100, 2300, 2400, 2500]);
+        assert_eq!(obstacles[0].right(), 1240);
+        assert_eq!(obstacles[1].right(), 1460);
+    }
+}
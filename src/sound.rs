@@ -34,15 +34,33 @@ pub enum LOOPING {
     NO,
 }
 
-pub fn play_sound(ctx: &AudioContext, buffer: &AudioBuffer, looping: LOOPING) -> Result<()> {
+// offset: 音のどこ（秒）から鳴らし始めるか
+pub fn play_sound(
+    ctx: &AudioContext,
+    buffer: &AudioBuffer,
+    looping: LOOPING,
+    offset: f64,
+) -> Result<AudioBufferSourceNode> {
     let track_source = create_track_source(ctx, buffer)?;
     if matches!(looping, LOOPING::YES) {
         track_source.set_loop(true);
     }
 
     track_source
-        .start()
-        .map_err(|err| anyhow!("Could not start sound!{:#?}", err))
+        .start_with_when_and_grain_offset(0.0, offset)
+        .map_err(|err| anyhow!("Could not start sound!{:#?}", err))?;
+    Ok(track_source)
+}
+
+// 鳴っている音を止められるもの。テストではモックに差し替える
+pub trait Playback {
+    fn stop(&self) -> Result<()>;
+}
+
+impl Playback for AudioBufferSourceNode {
+    fn stop(&self) -> Result<()> {
+        AudioBufferSourceNode::stop(self).map_err(|err| anyhow!("Could not stop sound!{:#?}", err))
+    }
 }
 
 pub async fn decode_audio_data(